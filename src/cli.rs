@@ -1,11 +1,43 @@
 use clap::*;
+use pn::utils::parse_duration;
+use std::{num::NonZeroUsize, path::PathBuf, time::Duration};
 
 #[derive(Debug, Parser)]
 #[clap(author, version, about, rename_all = "kebab-case")]
 pub struct Cli {
+    /// Run as if `pn` was started in this directory instead of the current one.
+    #[clap(short = 'C', long, global = true)]
+    pub dir: Option<PathBuf>,
     /// Run the command on the root workspace project.
     #[clap(short, long)]
     pub workspace_root: bool,
+    /// Run the command in every project of the workspace.
+    #[clap(short, long, global = true)]
+    pub recursive: bool,
+    /// Select the workspace projects to run the command in, e.g. `foo...`, `./packages/*` or `[origin/main]`.
+    #[clap(short = 'F', long, global = true)]
+    pub filter: Vec<String>,
+    /// Maximum number of projects to run at the same time, negative means CPUs minus this number.
+    #[clap(long, global = true, allow_negative_numbers = true)]
+    pub workspace_concurrency: Option<isize>,
+    /// Run all projects at once, ignoring concurrency limits and topological order.
+    #[clap(long, global = true)]
+    pub parallel: bool,
+    /// Do not sort projects topologically in recursive runs.
+    #[clap(long, global = true)]
+    pub no_sort: bool,
+    /// Run dependents before their dependencies in recursive runs.
+    #[clap(long, global = true)]
+    pub reverse: bool,
+    /// Do not prefix the output lines of concurrently running scripts with their project and script.
+    #[clap(long, global = true)]
+    pub reporter_hide_prefix: bool,
+    /// Write the output of each concurrently running script in one block once it finishes.
+    #[clap(long, global = true)]
+    pub aggregate_output: bool,
+    /// Time scripts get to exit after `pn` forwards them a signal, before they are killed, e.g. `500ms` or `10s`.
+    #[clap(long, global = true, default_value = "10s", value_parser = parse_duration)]
+    pub kill_grace_period: Duration,
     /// Command to execute.
     #[clap(subcommand)]
     pub command: Command,
@@ -17,18 +49,129 @@ pub enum Command {
     /// Runs a defined package script.
     #[clap(alias = "run-script")]
     Run(RunArgs),
+    /// Executes a command in scope of a project, without a shell.
+    Exec(ExecArgs),
+    /// Runs package scripts one after another, like `npm-run-all --serial`.
+    #[clap(name = "run-s")]
+    RunS(RunAllArgs),
+    /// Runs package scripts at the same time, like `npm-run-all --parallel`.
+    #[clap(name = "run-p")]
+    RunP(RunParallelArgs),
+    /// Run a script with the built-in shell emulator, used when `shell-emulator` is enabled.
+    #[clap(name = "__shell-emulator", hide = true)]
+    ShellEmulator { script: String },
     /// Execute a shell command in scope of a project.
     #[clap(external_subcommand)]
     Other(Vec<String>),
 }
 
+/// Runs several package scripts.
+#[derive(Debug, Args)]
+#[clap(rename_all = "kebab-case")]
+pub struct RunAllArgs {
+    /// Names or globs of the package scripts to run, e.g. `lint` or `watch:*`.
+    #[clap(required = true)]
+    pub patterns: Vec<String>,
+
+    /// Keep running the other scripts after one of them fails.
+    #[clap(long)]
+    pub continue_on_error: bool,
+}
+
+/// Runs several package scripts at the same time.
+#[derive(Debug, Args)]
+#[clap(rename_all = "kebab-case")]
+pub struct RunParallelArgs {
+    #[clap(flatten)]
+    pub scripts: RunAllArgs,
+
+    /// Stop the other scripts as soon as one of them succeeds.
+    #[clap(long)]
+    pub race: bool,
+
+    /// Maximum number of scripts to run at the same time.
+    #[clap(long)]
+    pub max_parallel: Option<NonZeroUsize>,
+}
+
 /// Runs a defined package script.
 #[derive(Debug, Args)]
 #[clap(rename_all = "kebab-case")]
 pub struct RunArgs {
-    /// Name of the package script to run.
-    pub script: Option<String>, // Not OsString because it would be compared against package.json#scripts
+    /// Name of the package script to run, or `<package>#<script>` for a script of another package of the workspace, followed by the arguments to pass to it.
+    ///
+    /// Options of `pn run` go before the name of the script, the ones after it are passed to the script.
+    #[clap(
+        value_name = "SCRIPT",
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    pub command: Vec<String>, // Not OsString because the name would be compared against package.json#scripts
+
+    /// Succeed without doing anything when the script is missing.
+    #[clap(long)]
+    pub if_present: bool,
+
+    /// Keep running the script in the other packages after it fails in one of them.
+    #[clap(long)]
+    pub no_bail: bool,
+
+    /// Restart the script whenever the files of the package change.
+    ///
+    /// `node_modules`, `.git` and the paths ignored by `.gitignore` files are not watched.
+    #[clap(long)]
+    pub watch: bool,
+
+    /// Glob of the paths to watch instead of the whole package, relative to the package.
+    #[clap(long, requires = "watch")]
+    pub watch_path: Vec<String>,
+
+    /// Glob of the paths not to watch, relative to the package.
+    #[clap(long, requires = "watch")]
+    pub watch_ignore: Vec<String>,
+
+    #[clap(flatten)]
+    pub limits: ScriptLimits,
+}
+
+impl RunArgs {
+    /// Name of the package script to run, if any.
+    pub fn script(&self) -> Option<&str> {
+        self.command.first().map(String::as_str)
+    }
 
     /// Arguments to pass to the package script.
-    pub args: Vec<String>,
+    pub fn args(&self) -> &[String] {
+        self.command.get(1..).unwrap_or_default()
+    }
+}
+
+/// Limits on each script run by `pn run`, including its `pre` and `post` hooks.
+#[derive(Debug, Default, Clone, Args)]
+#[clap(rename_all = "kebab-case")]
+pub struct ScriptLimits {
+    /// Kill the script and fail when it runs longer than this, e.g. `90s` or `5m`.
+    #[clap(long, value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// Run a failed script again up to this many times.
+    #[clap(long, default_value_t = 0)]
+    pub retry: u32,
+
+    /// Wait this long before the first retry, twice as long before each next one, e.g. `1s`.
+    #[clap(long, value_parser = parse_duration, requires = "retry")]
+    pub retry_backoff: Option<Duration>,
+}
+
+/// Executes a command in scope of a project, without a shell.
+#[derive(Debug, Args)]
+#[clap(rename_all = "kebab-case")]
+pub struct ExecArgs {
+    /// Run the command in a shell, which interprets the command as shell syntax.
+    #[clap(short = 'c', long)]
+    pub shell_mode: bool,
+
+    /// Command to execute and its arguments.
+    #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
 }
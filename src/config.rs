@@ -0,0 +1,427 @@
+use crate::{
+    error::{MainError, PnError},
+    workspace::WORKSPACE_MANIFEST_FILENAME,
+};
+use pipe_trait::Pipe;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+const NPMRC_FILENAME: &str = ".npmrc";
+const ENV_PREFIX: &str = "npm_config_";
+
+/// Settings that alter how `pn` runs scripts.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Run `pre<name>` and `post<name>` scripts around `<name>`.
+    ///
+    /// Off unless `enable-pre-post-scripts=true` is set, as in pnpm 7, whereas pnpm 8 and later
+    /// run these scripts by default.
+    pub enable_pre_post_scripts: bool,
+
+    /// Include the workspace root project in recursive runs.
+    pub include_workspace_root: bool,
+
+    /// Maximum number of projects to run at the same time in recursive runs.
+    pub workspace_concurrency: Option<isize>,
+
+    /// Run scripts with the shell emulator built into `pn` instead of `sh`.
+    pub shell_emulator: bool,
+
+    /// Shell to run scripts with instead of `sh`.
+    pub script_shell: Option<PathBuf>,
+
+    /// Value of `NODE_OPTIONS` for the scripts.
+    pub node_options: Option<String>,
+}
+
+impl Config {
+    /// Load settings the way pnpm does, in increasing order of precedence:
+    /// * the global `npmrc` and the global pnpm `rc` file,
+    /// * the user `~/.npmrc`,
+    /// * the `.npmrc` of the workspace root,
+    /// * the `.npmrc` of the project,
+    /// * the `pnpm-workspace.yaml`,
+    /// * the `npm_config_*` environment variables.
+    pub fn load(project_dir: &Path, workspace_dir: Option<&Path>) -> Result<Self, MainError> {
+        // variables that are not valid UTF-8 cannot hold settings
+        let env_vars: HashMap<String, String> = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Config::load_with_env(project_dir, workspace_dir, &env_vars)
+    }
+
+    fn load_with_env(
+        project_dir: &Path,
+        workspace_dir: Option<&Path>,
+        env_vars: &HashMap<String, String>,
+    ) -> Result<Self, MainError> {
+        let env_settings = parse_env_settings(env_vars);
+        let home_dir = env_vars
+            .get("HOME")
+            .or_else(|| env_vars.get("USERPROFILE"))
+            .map(PathBuf::from);
+        let read_npmrc = |path: &Path| read_npmrc(path, env_vars);
+
+        let mut settings = RawSettings::new();
+        if let Some(path) = global_npmrc_path(&env_settings) {
+            settings.extend(read_npmrc(&path)?);
+        }
+        if let Some(path) = pnpm_rc_path(env_vars, home_dir.as_deref()) {
+            settings.extend(read_npmrc(&path)?);
+        }
+        let user_npmrc = env_settings
+            .get("userconfig")
+            .map(PathBuf::from)
+            .or_else(|| home_dir.as_ref().map(|home| home.join(NPMRC_FILENAME)));
+        if let Some(path) = user_npmrc {
+            settings.extend(read_npmrc(&path)?);
+        }
+        if let Some(workspace_dir) = workspace_dir {
+            settings.extend(read_npmrc(&workspace_dir.join(NPMRC_FILENAME))?);
+        }
+        if workspace_dir != Some(project_dir) {
+            settings.extend(read_npmrc(&project_dir.join(NPMRC_FILENAME))?);
+        }
+        if let Some(workspace_dir) = workspace_dir {
+            settings.extend(read_workspace_settings(
+                &workspace_dir.join(WORKSPACE_MANIFEST_FILENAME),
+            )?);
+        }
+        settings.extend(env_settings);
+        Ok(Config::from_settings(&settings))
+    }
+
+    fn from_settings(settings: &RawSettings) -> Self {
+        let mut config = Config::default();
+        if let Some(value) = settings.get("enable-pre-post-scripts") {
+            config.enable_pre_post_scripts = parse_bool(value);
+        }
+        if let Some(value) = settings.get("include-workspace-root") {
+            config.include_workspace_root = parse_bool(value);
+        }
+        if let Some(value) = settings.get("workspace-concurrency") {
+            config.workspace_concurrency = value.trim().parse().ok();
+        }
+        if let Some(value) = settings.get("shell-emulator") {
+            config.shell_emulator = parse_bool(value);
+        }
+        if let Some(value) = settings.get("script-shell") {
+            config.script_shell = non_empty(value).map(PathBuf::from);
+        }
+        if let Some(value) = settings.get("node-options") {
+            config.node_options = non_empty(value).map(str::to_string);
+        }
+        config
+    }
+}
+
+/// Settings keyed by their kebab-case names.
+type RawSettings = HashMap<String, String>;
+
+fn read_optional_file(path: &Path) -> Result<Option<String>, MainError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => PnError::FsError {
+            path: path.to_path_buf(),
+            error,
+        }
+        .pipe(MainError::Pn)
+        .pipe(Err),
+    }
+}
+
+fn read_npmrc(path: &Path, env_vars: &HashMap<String, String>) -> Result<RawSettings, MainError> {
+    read_optional_file(path)?
+        .as_deref()
+        .map(parse_npmrc)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key, value)| (key, expand_env(&value, env_vars)))
+        .collect::<RawSettings>()
+        .pipe(Ok)
+}
+
+/// The global `npmrc`, either set by `globalconfig` or inside the `prefix` directory.
+fn global_npmrc_path(env_settings: &RawSettings) -> Option<PathBuf> {
+    if let Some(path) = env_settings.get("globalconfig") {
+        return Some(PathBuf::from(path));
+    }
+    env_settings
+        .get("prefix")
+        .map(|prefix| Path::new(prefix).join("etc").join("npmrc"))
+}
+
+/// The `rc` file written by `pnpm config set --global`.
+fn pnpm_rc_path(env_vars: &HashMap<String, String>, home_dir: Option<&Path>) -> Option<PathBuf> {
+    let config_dir = match env_vars.get("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => home_dir?.join(".config"),
+    };
+    config_dir.join("pnpm").join("rc").pipe(Some)
+}
+
+/// Settings from the `npm_config_*` environment variables, whose names are case-insensitive and use
+/// `_` in place of `-`.
+fn parse_env_settings(env_vars: &HashMap<String, String>) -> RawSettings {
+    env_vars
+        .iter()
+        .filter_map(|(name, value)| {
+            let prefix = name.get(..ENV_PREFIX.len())?;
+            if !prefix.eq_ignore_ascii_case(ENV_PREFIX) {
+                return None;
+            }
+            let key = name[ENV_PREFIX.len()..]
+                .to_ascii_lowercase()
+                .replace('_', "-");
+            Some((key, value.clone()))
+        })
+        .collect()
+}
+
+/// Replace the `${NAME}` references of an `.npmrc` value with environment variables.
+///
+/// References to undefined variables are left as is.
+fn expand_env(value: &str, env_vars: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(length) = rest[start..].find('}') else {
+            break;
+        };
+        let reference = &rest[start..start + length + 1];
+        result.push_str(&rest[..start]);
+        match env_vars.get(&reference[2..reference.len() - 1]) {
+            Some(value) => result.push_str(value),
+            None => result.push_str(reference),
+        }
+        rest = &rest[start + length + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn read_workspace_settings(path: &Path) -> Result<RawSettings, MainError> {
+    let Some(content) = read_optional_file(path)? else {
+        return Ok(RawSettings::new());
+    };
+    content
+        .pipe_as_ref(serde_yaml::from_str::<serde_yaml::Value>)
+        .map_err(|error| PnError::ParseYamlError {
+            file: path.to_path_buf(),
+            message: error.to_string(),
+        })?
+        .pipe_ref(parse_workspace_settings)
+        .pipe(Ok)
+}
+
+/// Parse the `key=value` lines of an `.npmrc` file.
+fn parse_npmrc(content: &str) -> RawSettings {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), unquote(value.trim()).to_string()))
+        .collect()
+}
+
+/// Extract the scalar top-level settings of a `pnpm-workspace.yaml`, whose keys are camelCase.
+fn parse_workspace_settings(value: &serde_yaml::Value) -> RawSettings {
+    let Some(mapping) = value.as_mapping() else {
+        return RawSettings::new();
+    };
+    mapping
+        .iter()
+        .filter_map(|(key, value)| {
+            let key = key.as_str()?.pipe(camel_to_kebab);
+            let value = match value {
+                serde_yaml::Value::Bool(value) => value.to_string(),
+                serde_yaml::Value::Number(value) => value.to_string(),
+                serde_yaml::Value::String(value) => value.clone(),
+                _ => return None,
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn camel_to_kebab(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for char in name.chars() {
+        if char.is_ascii_uppercase() {
+            result.push('-');
+            result.push(char.to_ascii_lowercase());
+        } else {
+            result.push(char);
+        }
+    }
+    result
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+fn parse_bool(value: &str) -> bool {
+    value.trim() == "true"
+}
+
+fn non_empty(value: &str) -> Option<&str> {
+    let value = value.trim();
+    (!value.is_empty()).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_npmrc() {
+        let received = parse_npmrc(
+            "# comment\n; another comment\nenable-pre-post-scripts = true\nregistry=\"https://example.com/\"\n\ninvalid line\n",
+        );
+        dbg!(&received);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received["enable-pre-post-scripts"], "true");
+        assert_eq!(received["registry"], "https://example.com/");
+    }
+
+    #[test]
+    fn test_parse_workspace_settings() {
+        let value: serde_yaml::Value = serde_yaml::from_str(
+            "packages:\n  - 'packages/*'\nenablePrePostScripts: true\nchildConcurrency: 4\n",
+        )
+        .unwrap();
+        let received = parse_workspace_settings(&value);
+        dbg!(&received);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received["enable-pre-post-scripts"], "true");
+        assert_eq!(received["child-concurrency"], "4");
+    }
+
+    #[test]
+    fn test_load_precedence() {
+        use build_fs_tree::{dir, file, Build, MergeableFileSystemTree};
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            ".npmrc" => file!("enable-pre-post-scripts=true\n"),
+            "pnpm-workspace.yaml" => file!("packages: ['packages/*']\n"),
+            "packages" => dir! {
+                "foo" => dir! {
+                    ".npmrc" => file!("enable-pre-post-scripts=false\n"),
+                },
+                "bar" => dir! {},
+            },
+        });
+        tree.build(&temp_dir).unwrap();
+        let workspace_dir = temp_dir.path();
+
+        let foo = Config::load(&workspace_dir.join("packages/foo"), Some(workspace_dir)).unwrap();
+        assert!(!foo.enable_pre_post_scripts);
+
+        let bar = Config::load(&workspace_dir.join("packages/bar"), Some(workspace_dir)).unwrap();
+        assert!(bar.enable_pre_post_scripts);
+
+        let root = Config::load(workspace_dir, Some(workspace_dir)).unwrap();
+        assert!(root.enable_pre_post_scripts);
+    }
+
+    #[test]
+    fn test_parse_env_settings() {
+        let env_vars = HashMap::from([
+            (
+                "npm_config_script_shell".to_string(),
+                "/bin/bash".to_string(),
+            ),
+            (
+                "NPM_CONFIG_NODE_OPTIONS".to_string(),
+                "--inspect".to_string(),
+            ),
+            ("npm_package_name".to_string(), "foo".to_string()),
+        ]);
+        let received = parse_env_settings(&env_vars);
+        dbg!(&received);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received["script-shell"], "/bin/bash");
+        assert_eq!(received["node-options"], "--inspect");
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let env_vars = HashMap::from([("TOKEN".to_string(), "secret".to_string())]);
+        assert_eq!(
+            expand_env("a${TOKEN}b${TOKEN}", &env_vars),
+            "asecretbsecret"
+        );
+        assert_eq!(expand_env("${MISSING}", &env_vars), "${MISSING}");
+        assert_eq!(expand_env("${unterminated", &env_vars), "${unterminated");
+        assert_eq!(expand_env("plain", &env_vars), "plain");
+    }
+
+    #[test]
+    fn test_load_layers() {
+        use build_fs_tree::{dir, file, Build, MergeableFileSystemTree};
+        use tempfile::tempdir;
+
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            "global" => dir! {
+                "etc" => dir! {
+                    "npmrc" => file!("script-shell=/global/sh\nnode-options=--global\nshell-emulator=true\n"),
+                },
+            },
+            "home" => dir! {
+                ".npmrc" => file!("script-shell=/user/sh\nnode-options=${OPTIONS}\n"),
+            },
+            "workspace" => dir! {
+                ".npmrc" => file!("script-shell=/workspace/sh\n"),
+                "pnpm-workspace.yaml" => file!("packages: ['packages/*']\n"),
+                "packages" => dir! {
+                    "foo" => dir! {
+                        ".npmrc" => file!("script-shell=/project/sh\n"),
+                    },
+                },
+            },
+        });
+        tree.build(&temp_dir).unwrap();
+        let root = temp_dir.path();
+        let workspace_dir = root.join("workspace");
+        let project_dir = workspace_dir.join("packages/foo");
+        let mut env_vars = HashMap::from([
+            ("HOME".to_string(), root.join("home").display().to_string()),
+            (
+                "XDG_CONFIG_HOME".to_string(),
+                root.join("xdg").display().to_string(),
+            ),
+            (
+                "npm_config_prefix".to_string(),
+                root.join("global").display().to_string(),
+            ),
+            ("OPTIONS".to_string(), "--user".to_string()),
+        ]);
+
+        let received =
+            Config::load_with_env(&project_dir, Some(&workspace_dir), &env_vars).unwrap();
+        dbg!(&received);
+        assert_eq!(received.script_shell, Some(PathBuf::from("/project/sh")));
+        assert_eq!(received.node_options.as_deref(), Some("--user"));
+        assert!(received.shell_emulator);
+
+        env_vars.insert("npm_config_script_shell".to_string(), "/env/sh".to_string());
+        let received =
+            Config::load_with_env(&project_dir, Some(&workspace_dir), &env_vars).unwrap();
+        dbg!(&received);
+        assert_eq!(received.script_shell, Some(PathBuf::from("/env/sh")));
+    }
+}
@@ -1,6 +1,10 @@
-use crate::shell_quoted::ShellQuoted;
+use crate::{
+    shell_quoted::ShellQuoted,
+    signal::{describe_signal, signal_exit_code},
+    utils::format_duration,
+};
 use derive_more::{Display, From};
-use std::{env::JoinPathsError, io, num::NonZeroI32, path::PathBuf};
+use std::{env::JoinPathsError, io, num::NonZeroI32, path::PathBuf, time::Duration};
 
 /// Error types emitted by `pn` itself.
 #[derive(Debug, Display)]
@@ -9,14 +13,26 @@ pub enum PnError {
     #[display("Missing script: {name}")]
     MissingScript { name: String },
 
-    /// Script ran by `pn run` exits with non-zero status code.
-    #[display("Command failed with exit code {status}")]
+    /// Script (or one of its `pre`/`post` hooks) ran by `pn run` exits with non-zero status code.
+    #[display("Command {name:?} failed with exit code {status}")]
     ScriptError { name: String, status: NonZeroI32 },
 
+    /// Subprocess is terminated by a signal, such as `SIGKILL` from the OOM killer.
+    #[display("Command {name:?} was terminated by {}", describe_signal(*signal))]
+    TerminatedBySignal { name: String, signal: i32 },
+
+    /// `pn` received a signal, which it forwarded to the running scripts, and starts no more of them.
+    #[display("Interrupted by {}", describe_signal(*signal))]
+    Interrupted { signal: i32 },
+
     /// Subprocess finishes but without a status code.
     #[display("Command ended unexpectedly: {command}")]
     UnexpectedTermination { command: ShellQuoted },
 
+    /// The executable given to `pn exec` is not found in `PATH`.
+    #[display("Command not found: {name}")]
+    ExecutableNotFound { name: String },
+
     /// Fail to spawn a subprocess.
     #[display("Failed to spawn process: {_0}")]
     SpawnProcessError(io::Error),
@@ -25,9 +41,77 @@ pub enum PnError {
     #[display("Failed to wait for the process: {_0}")]
     WaitProcessError(io::Error),
 
-    /// The program receives --workspace-root outside a workspace.
-    #[display("--workspace-root may only be used in a workspace")]
-    NotInWorkspace,
+    /// The program receives a workspace-only flag such as --workspace-root outside a workspace.
+    #[display("{flag} may only be used in a workspace")]
+    NotInWorkspace { flag: &'static str },
+
+    /// A `/regex/` script name given to `pn run` is not a valid regular expression.
+    #[display("Invalid regular expression {pattern}: {message}")]
+    InvalidScriptRegex { pattern: String, message: String },
+
+    /// No script matches the `/regex/` script name given to `pn run`.
+    #[display("No script matches {pattern}")]
+    NoScriptMatch { pattern: String },
+
+    /// None of the projects selected by a recursive run has the script.
+    #[display("None of the selected packages has a {name:?} script")]
+    RecursiveRunNoScript { name: String },
+
+    /// The script failed in some of the projects of a recursive run with `--no-bail`.
+    #[display("The script failed in {count} of the selected packages:\n{summary}")]
+    RecursiveRunFailures { count: usize, summary: String },
+
+    /// Some of the scripts ran by `pn run-s` or `pn run-p` with `--continue-on-error` failed.
+    #[display("{count} of the scripts failed:\n{summary}")]
+    ScriptFailures { count: usize, summary: String },
+
+    /// No package of the workspace has the name given to `pn run <package>#<script>`.
+    #[display(
+        "No package named {name:?} in the workspace{}",
+        did_you_mean(close_matches)
+    )]
+    UnknownPackage {
+        name: String,
+        close_matches: Vec<String>,
+    },
+
+    /// Several packages of the workspace have the unscoped name given to `pn run <package>#<script>`.
+    #[display("Package name {name:?} is ambiguous, it could be {}", candidates.join(", "))]
+    AmbiguousPackage {
+        name: String,
+        candidates: Vec<String>,
+    },
+
+    /// The tasks declared in the `pn` section depend on each other.
+    #[display("Tasks depend on each other in a cycle: {cycle}")]
+    TaskCycle { cycle: String },
+
+    /// An option that changes the order of the projects of a recursive run is given for a task.
+    #[display("{option} cannot be used with {script:?}, which runs as a task after the tasks it depends on")]
+    TaskGraphOption {
+        option: &'static str,
+        script: String,
+    },
+
+    /// Failed to read or write the cache of the tasks.
+    #[display("Task cache error at {path:?}: {error}")]
+    TaskCacheError { path: PathBuf, error: io::Error },
+
+    /// A script ran longer than the `--timeout` of `pn run`, and was killed.
+    #[display("Script {name:?} timed out after {}", format_duration(*timeout))]
+    ScriptTimeout { name: String, timeout: Duration },
+
+    /// `pn run --watch` is given several packages to run the script in.
+    #[display("--watch cannot be used with --recursive or --filter")]
+    WatchMultiProject,
+
+    /// The operating system cannot report the changes of the files watched by `pn run --watch`.
+    #[display("Failed to watch {path:?}: {message}")]
+    WatchError { path: PathBuf, message: String },
+
+    /// A glob pattern, such as the ones of `pnpm-workspace.yaml` or of the `pn` section, is invalid.
+    #[display("Invalid glob pattern {pattern:?}: {message}")]
+    InvalidGlob { pattern: String, message: String },
 
     /// No package manifest.
     #[display("File not found: {file:?}")]
@@ -37,7 +121,7 @@ pub enum PnError {
     #[display("{path:?}: {error}")]
     FsError { path: PathBuf, error: io::Error },
 
-    /// Error emitted by [`lets_find_up`]'s functions.
+    /// Error encountered while searching for a file in ancestor directories.
     #[display("Failed to find {file_name:?} from {start_dir:?} upward: {error}")]
     FindUpError {
         start_dir: PathBuf,
@@ -53,11 +137,45 @@ pub enum PnError {
     #[display("Failed to parse {file:?}: {message}")]
     ParseJsonError { file: PathBuf, message: String },
 
+    /// A `--filter` selector cannot be parsed.
+    #[display("Invalid filter: {selector:?}")]
+    InvalidFilter { selector: String },
+
+    /// Failed to list the files changed since a git ref for a `--filter` selector.
+    #[display("Failed to find the files changed since {git_ref:?}: {message}")]
+    GitDiffError { git_ref: String, message: String },
+
+    /// Parse YAML error.
+    #[display("Failed to parse {file:?}: {message}")]
+    ParseYamlError { file: PathBuf, message: String },
+
     /// Failed to prepend `node_modules/.bin` to `PATH`.
     #[display("Cannot add `node_modules/.bin` to PATH: {_0}")]
     NodeBinPathError(JoinPathsError),
 }
 
+impl PnError {
+    /// Exit code of `pn` when it fails with this error: the status of the failed script, or 128
+    /// plus the number of the signal that terminated it, like shells report it.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PnError::ScriptError { status, .. } => status.get(),
+            PnError::TerminatedBySignal { signal, .. } | PnError::Interrupted { signal } => {
+                signal_exit_code(*signal)
+            }
+            _ => 1,
+        }
+    }
+}
+
+/// Suggest the names in `close_matches`, if any.
+fn did_you_mean(close_matches: &[String]) -> String {
+    match close_matches {
+        [] => String::new(),
+        names => format!(", did you mean {}?", names.join(" or ")),
+    }
+}
+
 /// The main error type.
 #[derive(Debug, Display, From)]
 pub enum MainError {
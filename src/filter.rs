@@ -0,0 +1,526 @@
+//! Selection of workspace projects with pnpm's `--filter` syntax.
+
+use crate::{
+    error::{MainError, PnError},
+    glob::{self, Glob},
+    utils::normalize_path,
+    workspace_graph::WorkspaceGraph,
+};
+use pipe_trait::Pipe;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A parsed `--filter` selector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageSelector {
+    /// `!selector`: remove the matched projects from the selection.
+    pub exclude: bool,
+    /// `^`: select the dependencies or dependents but not the matched projects themselves.
+    pub exclude_self: bool,
+    /// `selector...`: also select the dependencies of the matched projects.
+    pub include_dependencies: bool,
+    /// `...selector`: also select the dependents of the matched projects.
+    pub include_dependents: bool,
+    /// Glob over project names.
+    pub name_pattern: Option<String>,
+    /// `./dir` or `{dir}`: select the projects in this directory, relative to the current directory.
+    pub parent_dir: Option<String>,
+    /// `[ref]`: select the projects that changed since this git ref.
+    pub diff: Option<String>,
+}
+
+impl PackageSelector {
+    /// Parse a selector, such as `@scope/*...`, `...^{packages/app}` or `![origin/main]`.
+    pub fn parse(raw: &str) -> Result<Self, PnError> {
+        let invalid = || PnError::InvalidFilter {
+            selector: raw.to_string(),
+        };
+        let mut selector = raw.trim();
+        let exclude = match selector.strip_prefix('!') {
+            Some(rest) => {
+                selector = rest;
+                true
+            }
+            None => false,
+        };
+        let mut exclude_self = false;
+        let include_dependencies = match selector.strip_suffix("...") {
+            Some(rest) => {
+                selector = rest;
+                if let Some(rest) = selector.strip_suffix('^') {
+                    selector = rest;
+                    exclude_self = true;
+                }
+                true
+            }
+            None => false,
+        };
+        let include_dependents = match selector.strip_prefix("...") {
+            Some(rest) => {
+                selector = rest;
+                if let Some(rest) = selector.strip_prefix('^') {
+                    selector = rest;
+                    exclude_self = true;
+                }
+                true
+            }
+            None => false,
+        };
+        if selector.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut parsed = PackageSelector {
+            exclude,
+            exclude_self,
+            include_dependencies,
+            include_dependents,
+            name_pattern: None,
+            parent_dir: None,
+            diff: None,
+        };
+        if is_location(selector) {
+            parsed.parent_dir = Some(selector.to_string());
+            return Ok(parsed);
+        }
+
+        let (rest, diff) = match selector.strip_suffix(']') {
+            Some(rest) => {
+                let (rest, diff) = rest.rsplit_once('[').ok_or_else(invalid)?;
+                (rest, Some(diff))
+            }
+            None => (selector, None),
+        };
+        let (name, parent_dir) = match rest.strip_suffix('}') {
+            Some(rest) => {
+                let (name, dir) = rest.rsplit_once('{').ok_or_else(invalid)?;
+                (name, Some(dir))
+            }
+            None => (rest, None),
+        };
+        let is_valid = |part: &str| !part.is_empty() && !part.contains(['{', '}', '[', ']']);
+        if !name.is_empty() && (!is_valid(name) || name.starts_with('.')) {
+            return Err(invalid());
+        }
+        if parent_dir.is_some_and(|dir| !is_valid(dir)) || diff.is_some_and(|diff| !is_valid(diff))
+        {
+            return Err(invalid());
+        }
+        parsed.name_pattern = (!name.is_empty()).then(|| name.to_string());
+        parsed.parent_dir = parent_dir.map(str::to_string);
+        parsed.diff = diff.map(str::to_string);
+        Ok(parsed)
+    }
+}
+
+/// Whether a selector is a relative path such as `.`, `./foo` or `../foo`.
+fn is_location(selector: &str) -> bool {
+    selector == "."
+        || selector == ".."
+        || selector.starts_with("./")
+        || selector.starts_with("../")
+        || selector.starts_with(".\\")
+        || selector.starts_with("..\\")
+}
+
+/// Files that changed since a git ref, relative to the workspace directory.
+pub type ChangedFiles<'a> = dyn FnMut(&str) -> Result<Vec<PathBuf>, MainError> + 'a;
+
+/// Select the indices of the projects of `graph` matched by `selectors`.
+///
+/// Without any inclusive selector, every project is selected before exclusions are applied.
+/// `changed_files` is only called for selectors with a git ref.
+pub fn filter_projects(
+    graph: &WorkspaceGraph,
+    workspace_dir: &Path,
+    cwd: &Path,
+    selectors: &[PackageSelector],
+    changed_files: &mut ChangedFiles,
+) -> Result<Vec<usize>, MainError> {
+    let (excludes, includes): (Vec<_>, Vec<_>) =
+        selectors.iter().partition(|selector| selector.exclude);
+    let mut selected = BTreeSet::new();
+    if includes.is_empty() {
+        selected.extend(0..graph.projects().len());
+    }
+    for selector in includes {
+        selected.extend(select(graph, workspace_dir, cwd, selector, changed_files)?);
+    }
+    for selector in excludes {
+        for index in select(graph, workspace_dir, cwd, selector, changed_files)? {
+            selected.remove(&index);
+        }
+    }
+    Ok(selected.into_iter().collect())
+}
+
+fn select(
+    graph: &WorkspaceGraph,
+    workspace_dir: &Path,
+    cwd: &Path,
+    selector: &PackageSelector,
+    changed_files: &mut ChangedFiles,
+) -> Result<BTreeSet<usize>, MainError> {
+    let mut seeds: Vec<usize> = (0..graph.projects().len()).collect();
+    if let Some(pattern) = &selector.name_pattern {
+        seeds = match_names(graph, &seeds, pattern);
+    }
+    if let Some(dir) = &selector.parent_dir {
+        let mut retained = Vec::with_capacity(seeds.len());
+        for index in seeds {
+            if is_in_dir(&graph.projects()[index].dir, cwd, dir)? {
+                retained.push(index);
+            }
+        }
+        seeds = retained;
+    }
+    if let Some(git_ref) = &selector.diff {
+        let changed = changed_files(git_ref)?;
+        let changed_projects: BTreeSet<_> = changed
+            .iter()
+            .filter_map(|file| owner_project(graph, &workspace_dir.join(file)))
+            .collect();
+        seeds.retain(|index| changed_projects.contains(index));
+    }
+
+    let mut selected = BTreeSet::new();
+    if !selector.exclude_self || !(selector.include_dependencies || selector.include_dependents) {
+        selected.extend(seeds.iter().copied());
+    }
+    if selector.include_dependencies {
+        walk(&seeds, &mut selected, |index| {
+            graph.dependencies_of(index).to_vec()
+        });
+    }
+    if selector.include_dependents {
+        walk(&seeds, &mut selected, |index| {
+            graph.dependents_of(index).collect()
+        });
+    }
+    Ok(selected)
+}
+
+/// Match project names against `pattern`.
+///
+/// Like pnpm, an unscoped pattern that matches nothing falls back to a single scoped project
+/// with that name, so `foo` selects `@scope/foo`.
+fn match_names(graph: &WorkspaceGraph, candidates: &[usize], pattern: &str) -> Vec<usize> {
+    let name_of = |index: &usize| graph.projects()[*index].manifest.name.as_str();
+    let matched: Vec<_> = candidates
+        .iter()
+        .copied()
+        .filter(|index| glob::is_name_match(pattern, name_of(index)))
+        .collect();
+    if !matched.is_empty() || pattern.contains('/') {
+        return matched;
+    }
+    let scoped_pattern = format!("@*/{pattern}");
+    let scoped: Vec<_> = candidates
+        .iter()
+        .copied()
+        .filter(|index| glob::is_name_match(&scoped_pattern, name_of(index)))
+        .collect();
+    if scoped.len() == 1 {
+        scoped
+    } else {
+        Vec::new()
+    }
+}
+
+/// Whether `project_dir` is `dir` (relative to `cwd`) or inside it. `dir` may be a glob.
+fn is_in_dir(project_dir: &Path, cwd: &Path, dir: &str) -> Result<bool, PnError> {
+    if !dir.contains(['*', '?', '[', '{']) {
+        return Ok(normalize_path(project_dir).starts_with(normalize_path(&cwd.join(dir))));
+    }
+    let mut base = cwd.to_path_buf();
+    let mut pattern = dir.strip_prefix("./").unwrap_or(dir);
+    while let Some(rest) = pattern.strip_prefix("../") {
+        base.pop();
+        pattern = rest;
+    }
+    let glob = Glob::new(pattern)?;
+    Ok(project_dir
+        .strip_prefix(&base)
+        .ok()
+        .and_then(Path::to_str)
+        .is_some_and(|relative| glob.is_match(&relative.replace('\\', "/"))))
+}
+
+/// Find the project with the deepest directory that contains `file`.
+fn owner_project(graph: &WorkspaceGraph, file: &Path) -> Option<usize> {
+    graph
+        .projects()
+        .iter()
+        .enumerate()
+        .filter(|(_, project)| file.starts_with(&project.dir))
+        .max_by_key(|(_, project)| project.dir.components().count())
+        .map(|(index, _)| index)
+}
+
+/// Add every node transitively reachable from `seeds` through `next`, excluding the seeds themselves.
+fn walk(seeds: &[usize], selected: &mut BTreeSet<usize>, next: impl Fn(usize) -> Vec<usize>) {
+    let mut visited: BTreeSet<usize> = seeds.iter().copied().collect();
+    let mut stack = seeds.to_vec();
+    while let Some(index) = stack.pop() {
+        for neighbor in next(index) {
+            if visited.insert(neighbor) {
+                selected.insert(neighbor);
+                stack.push(neighbor);
+            }
+        }
+    }
+}
+
+/// List the files that changed since `git_ref` in `workspace_dir`, including untracked files.
+pub fn git_changed_files(workspace_dir: &Path, git_ref: &str) -> Result<Vec<PathBuf>, MainError> {
+    let git = |args: &[&str]| -> Result<String, MainError> {
+        let error = |message: String| PnError::GitDiffError {
+            git_ref: git_ref.to_string(),
+            message,
+        };
+        let output = Command::new("git")
+            .current_dir(workspace_dir)
+            .args(args)
+            .output()
+            .map_err(|error_| error(error_.to_string()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(error(stderr.trim().to_string()).into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    let diff = git(&["diff", "--name-only", "--relative", git_ref, "--", "."])?;
+    let untracked = git(&["ls-files", "--others", "--exclude-standard"])?;
+    diff.lines()
+        .chain(untracked.lines())
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect::<Vec<_>>()
+        .pipe(Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{workspace::WorkspaceProject, NodeManifest};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse() {
+        let default = PackageSelector {
+            exclude: false,
+            exclude_self: false,
+            include_dependencies: false,
+            include_dependents: false,
+            name_pattern: None,
+            parent_dir: None,
+            diff: None,
+        };
+        let cases = [
+            (
+                "foo",
+                PackageSelector {
+                    name_pattern: Some("foo".into()),
+                    ..default.clone()
+                },
+            ),
+            (
+                "@scope/*...",
+                PackageSelector {
+                    name_pattern: Some("@scope/*".into()),
+                    include_dependencies: true,
+                    ..default.clone()
+                },
+            ),
+            (
+                "foo^...",
+                PackageSelector {
+                    name_pattern: Some("foo".into()),
+                    include_dependencies: true,
+                    exclude_self: true,
+                    ..default.clone()
+                },
+            ),
+            (
+                "...^foo",
+                PackageSelector {
+                    name_pattern: Some("foo".into()),
+                    include_dependents: true,
+                    exclude_self: true,
+                    ..default.clone()
+                },
+            ),
+            (
+                "...foo...",
+                PackageSelector {
+                    name_pattern: Some("foo".into()),
+                    include_dependencies: true,
+                    include_dependents: true,
+                    ..default.clone()
+                },
+            ),
+            (
+                "!foo",
+                PackageSelector {
+                    name_pattern: Some("foo".into()),
+                    exclude: true,
+                    ..default.clone()
+                },
+            ),
+            (
+                "./packages/foo",
+                PackageSelector {
+                    parent_dir: Some("./packages/foo".into()),
+                    ..default.clone()
+                },
+            ),
+            (
+                ".",
+                PackageSelector {
+                    parent_dir: Some(".".into()),
+                    ..default.clone()
+                },
+            ),
+            (
+                "{packages/*}...",
+                PackageSelector {
+                    parent_dir: Some("packages/*".into()),
+                    include_dependencies: true,
+                    ..default.clone()
+                },
+            ),
+            (
+                "...[origin/main]",
+                PackageSelector {
+                    diff: Some("origin/main".into()),
+                    include_dependents: true,
+                    ..default.clone()
+                },
+            ),
+            (
+                "foo{packages}[HEAD~1]",
+                PackageSelector {
+                    name_pattern: Some("foo".into()),
+                    parent_dir: Some("packages".into()),
+                    diff: Some("HEAD~1".into()),
+                    ..default.clone()
+                },
+            ),
+        ];
+        for (raw, expected) in cases {
+            eprintln!("SELECTOR: {raw:?}");
+            assert_eq!(PackageSelector::parse(raw).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for raw in [
+            "",
+            "!",
+            "...",
+            "^...",
+            "foo{bar",
+            "foo[main",
+            "{}",
+            "[]",
+            "foo{a}bar",
+        ] {
+            eprintln!("SELECTOR: {raw:?}");
+            let error = PackageSelector::parse(raw).unwrap_err();
+            assert!(matches!(error, PnError::InvalidFilter { .. }));
+        }
+    }
+
+    /// `app` -> `lib` -> `utils`, `app` -> `@scope/ui` -> `utils`, `docs` standalone.
+    fn create_graph() -> WorkspaceGraph {
+        let project = |dir: &str, name: &str, dependencies: &[&str]| WorkspaceProject {
+            dir: Path::new("/repo").join(dir),
+            manifest: NodeManifest {
+                name: name.to_string(),
+                version: "1.0.0".to_string(),
+                dependencies: dependencies
+                    .iter()
+                    .map(|name| (name.to_string(), "workspace:*".to_string()))
+                    .collect(),
+                ..Default::default()
+            },
+        };
+        WorkspaceGraph::new(vec![
+            project("", "root", &[]),
+            project("apps/app", "app", &["lib", "@scope/ui"]),
+            project("apps/docs", "docs", &[]),
+            project("packages/lib", "lib", &["utils"]),
+            project("packages/ui", "@scope/ui", &["utils"]),
+            project("packages/utils", "utils", &[]),
+        ])
+    }
+
+    fn names(graph: &WorkspaceGraph, filters: &[&str], cwd: &str) -> Vec<String> {
+        let selectors: Vec<_> = filters
+            .iter()
+            .map(|raw| PackageSelector::parse(raw).unwrap())
+            .collect();
+        let mut changed_files = |git_ref: &str| {
+            assert_eq!(git_ref, "main");
+            Ok(vec![
+                PathBuf::from("packages/lib/src/index.ts"),
+                PathBuf::from("README.md"),
+            ])
+        };
+        filter_projects(
+            graph,
+            Path::new("/repo"),
+            &Path::new("/repo").join(cwd),
+            &selectors,
+            &mut changed_files,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|index| graph.projects()[index].manifest.name.clone())
+        .collect()
+    }
+
+    #[test]
+    fn test_filter_projects() {
+        let graph = create_graph();
+        let cases: &[(&[&str], &str, &[&str])] = &[
+            (&["app"], "", &["app"]),
+            (&["ui"], "", &["@scope/ui"]),
+            (&["@scope/*"], "", &["@scope/ui"]),
+            (
+                &["*"],
+                "",
+                &["root", "app", "docs", "lib", "@scope/ui", "utils"],
+            ),
+            (&["app..."], "", &["app", "lib", "@scope/ui", "utils"]),
+            (&["app^..."], "", &["lib", "@scope/ui", "utils"]),
+            (&["...utils"], "", &["app", "lib", "@scope/ui", "utils"]),
+            (&["...^lib"], "", &["app"]),
+            (&["...lib..."], "", &["app", "lib", "utils"]),
+            (&["./packages"], "", &["lib", "@scope/ui", "utils"]),
+            (&["."], "apps", &["app", "docs"]),
+            (&["../packages/ui"], "apps", &["@scope/ui"]),
+            (&["{apps/*}"], "", &["app", "docs"]),
+            (&["{packages}..."], "", &["lib", "@scope/ui", "utils"]),
+            (&["app", "docs"], "", &["app", "docs"]),
+            (
+                &["!root"],
+                "",
+                &["app", "docs", "lib", "@scope/ui", "utils"],
+            ),
+            (&["app...", "!utils"], "", &["app", "lib", "@scope/ui"]),
+            (&["[main]"], "", &["root", "lib"]),
+            (&["...[main]"], "", &["root", "app", "lib"]),
+            (&["{packages}[main]"], "", &["lib"]),
+            (&["missing"], "", &[]),
+        ];
+        for (filters, cwd, expected) in cases {
+            eprintln!("FILTERS: {filters:?} CWD: {cwd:?}");
+            assert_eq!(names(&graph, filters, cwd), *expected);
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Glob patterns over `/`-separated relative paths, matched with [`globset`].
+//!
+//! Supports `*`, `?`, `[...]` character classes, `{a,b}` alternatives and `**` segments.
+//! Like fast-glob, which pnpm uses, wildcards do not match a leading `.` of a path segment, and a
+//! trailing `/**` also matches the directory itself.
+
+use crate::error::PnError;
+use globset::{GlobBuilder, GlobMatcher};
+
+/// A compiled glob pattern.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    matcher: GlobMatcher,
+    /// Matcher of the pattern without its trailing `/**`, if it has one.
+    dir_matcher: Option<GlobMatcher>,
+    /// Matchers of the segments of the pattern, `None` for `**`, or `None` altogether when a
+    /// `{a,b}` alternative contains a `/`.
+    segments: Option<Vec<Option<GlobMatcher>>>,
+    /// Matchers of the segments of the pattern that start with `.`.
+    dot_segments: Vec<GlobMatcher>,
+}
+
+impl Glob {
+    /// Compile `pattern`. A leading `./` is ignored.
+    pub fn new(pattern: &str) -> Result<Self, PnError> {
+        let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+        let dir_matcher = pattern
+            .strip_suffix("/**")
+            .map(|dir| compile(dir, true))
+            .transpose()?;
+        let segments = (!has_separator_in_braces(pattern))
+            .then(|| {
+                pattern
+                    .split('/')
+                    .map(|segment| match segment {
+                        "**" => Ok(None),
+                        segment => compile(segment, true).map(Some),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+        let dot_segments = pattern
+            .split('/')
+            .filter(|segment| segment.starts_with('.'))
+            .filter_map(|segment| compile(segment, true).ok())
+            .collect();
+        Ok(Glob {
+            matcher: compile(pattern, true)?,
+            dir_matcher,
+            segments,
+            dot_segments,
+        })
+    }
+
+    /// Whether `path` matches the whole pattern.
+    pub fn is_match(&self, path: &str) -> bool {
+        let path = path.strip_prefix("./").unwrap_or(path);
+        let is_match = self.matcher.is_match(path)
+            || self
+                .dir_matcher
+                .as_ref()
+                .is_some_and(|matcher| matcher.is_match(path));
+        is_match && self.allows_dot_segments(path)
+    }
+
+    /// Whether `path` itself or one of its descendants may match the pattern.
+    ///
+    /// This allows directory walkers to skip directories that cannot contain any match.
+    pub fn may_match_descendant(&self, path: &str) -> bool {
+        let path = path.strip_prefix("./").unwrap_or(path);
+        let Some(segments) = &self.segments else {
+            return true; // the segments of the pattern are unknown
+        };
+        if !self.allows_dot_segments(path) {
+            return false;
+        }
+        let mut names = path.split('/');
+        for segment in segments {
+            let Some(name) = names.next() else {
+                return true;
+            };
+            match segment {
+                None => return true,
+                Some(segment) if !segment.is_match(name) => return false,
+                Some(_) => {}
+            }
+        }
+        names.next().is_none() // the path is not deeper than the pattern
+    }
+
+    /// Whether every segment of `path` that starts with `.` is matched by a segment of the pattern
+    /// that starts with `.` too.
+    fn allows_dot_segments(&self, path: &str) -> bool {
+        path.split('/')
+            .filter(|name| name.starts_with('.') && *name != "." && *name != "..")
+            .all(|name| {
+                self.dot_segments
+                    .iter()
+                    .any(|segment| segment.is_match(name))
+            })
+    }
+}
+
+/// Compile `pattern` with `\` escapes, where wildcards cross `/` unless `literal_separator`.
+fn compile(pattern: &str, literal_separator: bool) -> Result<GlobMatcher, PnError> {
+    GlobBuilder::new(pattern)
+        .literal_separator(literal_separator)
+        .backslash_escape(true)
+        .empty_alternates(true)
+        .build()
+        .map(|glob| glob.compile_matcher())
+        .map_err(|error| PnError::InvalidGlob {
+            pattern: pattern.to_string(),
+            message: error.kind().to_string(),
+        })
+}
+
+/// Whether a `{a,b}` alternative of `pattern` contains a `/`.
+fn has_separator_in_braces(pattern: &str) -> bool {
+    let mut depth = 0_usize;
+    let mut chars = pattern.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => {
+                chars.next();
+            }
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            '/' if depth > 0 => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Match a string without treating `/` specially, e.g. a package name such as `@scope/*`.
+///
+/// An invalid pattern only matches itself.
+pub fn is_name_match(pattern: &str, name: &str) -> bool {
+    match compile(pattern, false) {
+        Ok(matcher) => matcher.is_match(name),
+        Err(_) => pattern == name,
+    }
+}
+
+/// Match a single path segment like a POSIX shell: without `{a,b}` alternatives, and wildcards do
+/// not match a leading `.`.
+///
+/// An invalid pattern only matches itself, without its escapes.
+pub fn is_shell_segment_match(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    // braces are literal in the shell, unlike in globset
+    let mut escaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => {
+                escaped.push(char);
+                escaped.extend(chars.next());
+            }
+            '{' | '}' | ',' => {
+                escaped.push('\\');
+                escaped.push(char);
+            }
+            char => escaped.push(char),
+        }
+    }
+    match compile(&escaped, true) {
+        Ok(matcher) => matcher.is_match(name),
+        Err(_) => unescape(pattern) == name,
+    }
+}
+
+/// Remove the `\` escapes of `pattern`.
+fn unescape(pattern: &str) -> String {
+    let mut text = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => text.extend(chars.next()),
+            char => text.push(char),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn glob(pattern: &str) -> Glob {
+        Glob::new(pattern).unwrap()
+    }
+
+    #[test]
+    fn test_is_match() {
+        let cases = [
+            ("packages/*", "packages/foo", true),
+            ("packages/*", "./packages/foo", true),
+            ("./packages/*", "packages/foo", true),
+            ("packages/*", "packages/foo/bar", false),
+            ("packages/*", "packages", false),
+            ("packages/**", "packages/foo/bar", true),
+            ("packages/**", "packages", true),
+            ("**", "foo/bar/baz", true),
+            ("**/test/**", "packages/foo/test/fixture", true),
+            ("**/test/**", "packages/foo/src", false),
+            ("apps/*-web", "apps/admin-web", true),
+            ("apps/*-web", "apps/admin-api", false),
+            ("pkg-?", "pkg-a", true),
+            ("pkg-?", "pkg-ab", false),
+            ("pkg-[a-c]", "pkg-b", true),
+            ("pkg-[!a-c]", "pkg-b", false),
+            ("pkg-[!a-c]", "pkg-d", true),
+            ("{apps,libs}/*", "libs/foo", true),
+            ("{apps,libs}/*", "tools/foo", false),
+            ("src/*.{ts,tsx}", "src/index.tsx", true),
+            ("*", ".hidden", false),
+            (".hidden/*", ".hidden/foo", true),
+            ("**/foo", ".git/foo", false),
+            ("**/.eslintrc", "src/.eslintrc", true),
+            ("**/*", "src/.cache/index.js", false),
+            ("foo\\*", "foo*", true),
+            ("foo\\*", "foobar", false),
+        ];
+        for (pattern, path, expected) in cases {
+            eprintln!("pattern={pattern:?} path={path:?}");
+            assert_eq!(glob(pattern).is_match(path), expected);
+        }
+    }
+
+    #[test]
+    fn test_may_match_descendant() {
+        let packages = glob("packages/*/lib");
+        assert!(packages.may_match_descendant("packages"));
+        assert!(packages.may_match_descendant("packages/foo"));
+        assert!(packages.may_match_descendant("packages/foo/lib"));
+        assert!(!packages.may_match_descendant("packages/foo/lib/src"));
+        assert!(!packages.may_match_descendant("apps"));
+        assert!(!packages.may_match_descendant("packages/foo/src"));
+        assert!(!packages.may_match_descendant("packages/.cache"));
+        assert!(glob("**/lib").may_match_descendant("anything/at/all"));
+        assert!(glob("{apps/web,libs}/*").may_match_descendant("apps"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error = Glob::new("packages/[a").unwrap_err();
+        assert!(matches!(error, PnError::InvalidGlob { .. }));
+    }
+
+    #[test]
+    fn test_is_name_match() {
+        assert!(is_name_match("@scope/*", "@scope/foo"));
+        assert!(is_name_match("*", "@scope/foo"));
+        assert!(is_name_match("*-utils", "@scope/string-utils"));
+        assert!(!is_name_match("@scope/*", "@other/foo"));
+        assert!(is_name_match("{foo,bar}", "bar"));
+    }
+
+    #[test]
+    fn test_is_shell_segment_match() {
+        assert!(is_shell_segment_match("*.js", "index.js"));
+        assert!(!is_shell_segment_match("*.js", ".eslintrc.js"));
+        assert!(is_shell_segment_match(".*", ".eslintrc.js"));
+        assert!(is_shell_segment_match("\\*.js", "*.js"));
+        assert!(!is_shell_segment_match("\\*.js", "index.js"));
+        assert!(!is_shell_segment_match("{a,b}", "a"));
+        assert!(is_shell_segment_match("{a,b}", "{a,b}"));
+        assert!(is_shell_segment_match("[a", "[a"));
+    }
+}
@@ -3,14 +3,29 @@
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
+pub mod config;
 pub mod error;
+pub mod filter;
+pub mod glob;
+pub mod nested_run;
+pub mod output;
 pub mod passed_through;
+pub mod process_group;
+pub mod scheduler;
+pub mod script_env;
+pub mod script_selector;
+pub mod shell_emulator;
 pub mod shell_quoted;
+pub mod signal;
+pub mod task_cache;
+pub mod task_graph;
 pub mod utils;
+pub mod watch;
 pub mod workspace;
+pub mod workspace_graph;
 
 /// Structure of `package.json`.
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct NodeManifest {
     #[serde(default)]
@@ -21,4 +36,35 @@ pub struct NodeManifest {
 
     #[serde(default)]
     pub scripts: IndexMap<String, String>,
+
+    #[serde(default)]
+    pub dependencies: IndexMap<String, String>,
+
+    #[serde(default, rename = "devDependencies")]
+    pub dev_dependencies: IndexMap<String, String>,
+
+    #[serde(default, rename = "optionalDependencies")]
+    pub optional_dependencies: IndexMap<String, String>,
+
+    #[serde(default, rename = "peerDependencies")]
+    pub peer_dependencies: IndexMap<String, String>,
+
+    /// Settings of `pn` itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pn: Option<task_graph::PnSection>,
+}
+
+impl NodeManifest {
+    /// Iterate over the names and version specs of every kind of dependency.
+    pub fn all_dependencies(&self) -> impl Iterator<Item = (&str, &str)> {
+        [
+            &self.dependencies,
+            &self.dev_dependencies,
+            &self.optional_dependencies,
+            &self.peer_dependencies,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|(name, spec)| (name.as_str(), spec.as_str()))
+    }
 }
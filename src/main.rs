@@ -1,23 +1,58 @@
 use clap::Parser;
-use cli::Cli;
+use cli::{Cli, ExecArgs, RunAllArgs, RunArgs, RunParallelArgs, ScriptLimits};
+use config::Config;
 use error::{MainError, PnError};
+use indexmap::IndexMap;
+use nested_run::{nested_chain, ChainStep};
+use output::ScriptOutput;
 use pipe_trait::Pipe;
+use recursive::RecursiveRunner;
+use scheduler::{concurrency_limit, run_tasks};
+use script_env::ScriptEnv;
+use script_selector::{select_globs, ScriptSelector};
 use shell_quoted::ShellQuoted;
 use std::{
     env,
+    fmt::Display,
+    fs,
     io::{self, Write},
-    path::Path,
+    num::{NonZeroI32, NonZeroUsize},
+    path::{Path, PathBuf},
     process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
 };
-use yansi::Color::{Black, Red};
+use task_cache::{clear_logs, task_hash, TaskCache};
+use task_graph::{task_config, TaskConfig, TaskGraph};
+use watch::Watcher;
+use workspace::WorkspaceProject;
+use workspace_graph::WorkspaceGraph;
+use yansi::Color::{Black, Red, Yellow};
 
 mod cli;
+mod recursive;
 
+use pn::config;
 use pn::error;
+use pn::nested_run;
+use pn::output;
 use pn::passed_through;
+use pn::process_group;
+use pn::scheduler;
+use pn::script_env;
+use pn::script_selector;
+use pn::shell_emulator;
 use pn::shell_quoted;
+use pn::task_cache;
+use pn::task_graph;
 use pn::utils::*;
+use pn::watch;
 use pn::workspace;
+use pn::workspace_graph;
 use pn::NodeManifest;
 
 fn main() {
@@ -28,72 +63,892 @@ fn main() {
             eprintln!(
                 "{prefix} {error}",
                 prefix = Black.paint("\u{2009}ERROR\u{2009}").bg(Red),
-                error = Red.paint(error),
+                error = Red.paint(&error),
             );
-            exit(1);
+            exit(error.exit_code());
         }
     }
 }
 
+fn warn(message: impl Display) {
+    eprintln!(
+        "{prefix} {message}",
+        prefix = Black.paint("\u{2009}WARN\u{2009}").bg(Yellow),
+        message = Yellow.paint(message),
+    );
+}
+
+/// Label of a project in the prefix of its output lines: its directory relative to the workspace.
+fn project_label(workspace_dir: &Path, project: &WorkspaceProject) -> String {
+    if project.dir == workspace_dir {
+        project_display_name(project)
+    } else {
+        relative_path(&project.dir, workspace_dir)
+            .display()
+            .to_string()
+    }
+}
+
+/// Name of a project in messages, falling back to its directory when it has no name.
+fn project_display_name(project: &WorkspaceProject) -> String {
+    if project.manifest.name.is_empty() {
+        project.dir.display().to_string()
+    } else {
+        project.manifest.name.clone()
+    }
+}
+
+/// Line of a failure summary, which reports that `error` happened in `name`.
+fn failure_line(name: &str, error: &MainError) -> String {
+    let error = match error {
+        MainError::Pn(error) => error.to_string(),
+        MainError::Sub(status) => format!("Exited with code {status}"),
+    };
+    format!("  {name}: {error}")
+}
+
+/// Describe the files reported by [`Watcher::wait_for_changes`], e.g. `src/a.ts and 2 more files`.
+fn describe_changes(changes: &[String]) -> String {
+    match changes {
+        [] => "nothing".to_string(),
+        [path] => path.clone(),
+        [path, rest @ ..] => format!(
+            "{path} and {} more file{}",
+            rest.len(),
+            if rest.len() == 1 { "" } else { "s" },
+        ),
+    }
+}
+
+/// Error reporting the scripts that failed in a run with `--continue-on-error`, if any.
+fn script_failures(mut failures: Vec<(usize, &str, MainError)>) -> Result<(), MainError> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    failures.sort_by_key(|(index, _, _)| *index);
+    let summary = failures
+        .iter()
+        .map(|(_, name, error)| failure_line(name, error))
+        .collect::<Vec<_>>()
+        .join("\n");
+    PnError::ScriptFailures {
+        count: failures.len(),
+        summary,
+    }
+    .pipe(MainError::Pn)
+    .pipe(Err)
+}
+
+/// Tasks declared in the `pn` section of `pnpm-workspace.yaml`, by script name.
+fn workspace_tasks(
+    workspace_dir: Option<&Path>,
+) -> Result<IndexMap<String, TaskConfig>, MainError> {
+    let Some(workspace_dir) = workspace_dir else {
+        return Ok(IndexMap::new());
+    };
+    workspace::read_workspace_manifest(workspace_dir)?
+        .pn
+        .map(|section| section.tasks)
+        .unwrap_or_default()
+        .pipe(Ok)
+}
+
+/// Graph of the projects of the workspace of `project`, or of `project` alone outside a workspace,
+/// with the index of `project` in it.
+fn project_graph(project: &ProjectScripts) -> Result<(WorkspaceGraph, usize), MainError> {
+    let mut projects = match project.workspace_dir {
+        Some(workspace_dir) => workspace::list_workspace_projects(workspace_dir)?,
+        None => Vec::new(),
+    };
+    let index = match projects.iter().position(|other| other.dir == project.dir) {
+        Some(index) => index,
+        None => {
+            projects.push(WorkspaceProject {
+                dir: project.dir.to_path_buf(),
+                manifest: project.manifest.clone(),
+            });
+            projects.len() - 1
+        }
+    };
+    Ok((WorkspaceGraph::new(projects), index))
+}
+
+/// Directory that `--dir` points to, relative to `cwd`.
+fn base_dir(cwd: &Path, dir: &Path) -> Result<PathBuf, MainError> {
+    let dir = normalize_path(&cwd.join(dir));
+    let metadata = fs::metadata(&dir).map_err(|error| PnError::FsError {
+        path: dir.clone(),
+        error,
+    })?;
+    if !metadata.is_dir() {
+        return PnError::FsError {
+            path: dir,
+            error: io::Error::other("Not a directory"),
+        }
+        .pipe(MainError::Pn)
+        .pipe(Err);
+    }
+    Ok(dir)
+}
+
 fn run() -> Result<(), MainError> {
     let cli = Cli::parse();
-    let cwd_and_manifest = || -> Result<_, MainError> {
-        let mut cwd = env::current_dir().expect("Couldn't find the current working directory");
-        if cli.workspace_root {
-            cwd = workspace::find_workspace_root(&cwd)?;
-        }
-        let manifest_path = cwd.join("package.json");
-        let manifest = read_package_manifest(&manifest_path)?;
-        Ok((cwd, manifest))
+    // the shell emulator runs inside the process group of its script, which receives the signals
+    if !matches!(cli.command, cli::Command::ShellEmulator { .. }) {
+        process_group::forward_signals(cli.kill_grace_period);
+    }
+    let init_cwd = env::current_dir().expect("Couldn't find the current working directory");
+    let init_cwd = match &cli.dir {
+        Some(dir) => base_dir(&init_cwd, dir)?,
+        None => init_cwd,
     };
-    let print_and_run_script =
-        |manifest: &NodeManifest, name: &str, command: ShellQuoted, cwd: &Path| {
-            eprintln!(
-                "\n> {name}@{version} {cwd}",
-                name = &manifest.name,
-                version = &manifest.version,
-                cwd = dunce::canonicalize(cwd)
-                    .unwrap_or_else(|_| cwd.to_path_buf())
+    let runner = ScriptRunner {
+        init_cwd: &init_cwd,
+        hide_prefix: cli.reporter_hide_prefix,
+        aggregate_output: cli.aggregate_output,
+        workspace_concurrency: cli.workspace_concurrency,
+        limits: match &cli.command {
+            cli::Command::Run(args) => args.limits.clone(),
+            _ => ScriptLimits::default(),
+        },
+        kill_grace_period: cli.kill_grace_period,
+    };
+    let recursive = RecursiveRunner {
+        runner: &runner,
+        filter: &cli.filter,
+        parallel: cli.parallel,
+        no_sort: cli.no_sort,
+        reverse: cli.reverse,
+    };
+    let current_project = || CurrentProject::load(&init_cwd, cli.workspace_root);
+    let is_multi_project = cli.recursive || !cli.filter.is_empty();
+    match &cli.command {
+        cli::Command::ShellEmulator { script } => exit(shell_emulator::run(script)),
+        cli::Command::Run(args) if is_multi_project => match args.script() {
+            Some(name) => recursive.run(name, args),
+            None => runner.run(current_project()?, args),
+        },
+        cli::Command::Exec(args) if is_multi_project => recursive.exec(args),
+        cli::Command::Other(args) if is_multi_project => recursive.run_other(args),
+        cli::Command::Run(args) => runner.run(current_project()?, args),
+        cli::Command::Exec(args) => runner.exec(&current_project()?, args),
+        cli::Command::RunS(args) => runner.run_s(&current_project()?, args),
+        cli::Command::RunP(args) => runner.run_p(&current_project()?, args),
+        cli::Command::Other(args) => runner.run_other(&current_project()?, args),
+    }
+}
+
+/// Run the command of `pn exec`, through the shell with `--shell-mode`.
+fn exec(
+    args: &ExecArgs,
+    cwd: &Path,
+    env: &ScriptEnv,
+    output: &ScriptOutput,
+    config: &Config,
+) -> Result<(), MainError> {
+    if args.shell_mode {
+        let (command, args) = args.command.split_first().expect("clap requires a command");
+        let command = ShellQuoted::from_command_and_args(command.into(), args);
+        pass_to_sub(command, cwd, env, output, config)
+    } else {
+        exec_command(&args.command, cwd, env, output, config)
+    }
+}
+
+/// The project that a command runs in when it is not recursive.
+struct CurrentProject {
+    dir: PathBuf,
+    workspace_dir: Option<PathBuf>,
+    manifest: NodeManifest,
+    config: Config,
+}
+
+impl CurrentProject {
+    /// Find the project that contains `init_cwd`, or the root project of its workspace if
+    /// `workspace_root`.
+    fn load(init_cwd: &Path, workspace_root: bool) -> Result<Self, MainError> {
+        let workspace_dir = workspace::find_workspace_dir(init_cwd)?;
+        let dir = if workspace_root {
+            workspace_dir.clone().ok_or(PnError::NotInWorkspace {
+                flag: "--workspace-root",
+            })?
+        } else {
+            workspace::find_project_dir(init_cwd, workspace_dir.as_deref())?
+                .unwrap_or_else(|| init_cwd.to_path_buf())
+        };
+        let config = Config::load(&dir, workspace_dir.as_deref())?;
+        let manifest = read_package_manifest(&dir.join("package.json"))?;
+        Ok(CurrentProject {
+            dir,
+            workspace_dir,
+            manifest,
+            config,
+        })
+    }
+
+    fn scripts(&self) -> ProjectScripts<'_> {
+        ProjectScripts {
+            manifest: &self.manifest,
+            config: &self.config,
+            dir: &self.dir,
+            workspace_dir: self.workspace_dir.as_deref(),
+            log_dir: None,
+        }
+    }
+
+    fn env(&self, init_cwd: &Path) -> Result<ScriptEnv, MainError> {
+        ScriptEnv::new(
+            &self.manifest,
+            &self.dir,
+            self.workspace_dir.as_deref(),
+            init_cwd,
+        )
+    }
+}
+
+/// A project whose scripts `pn` runs.
+struct ProjectScripts<'a> {
+    manifest: &'a NodeManifest,
+    config: &'a Config,
+    dir: &'a Path,
+    workspace_dir: Option<&'a Path>,
+    /// Where the output of the scripts is logged for the task cache, if anywhere.
+    log_dir: Option<&'a Path>,
+}
+
+/// Runs package scripts with the output options of the command line.
+struct ScriptRunner<'a> {
+    init_cwd: &'a Path,
+    hide_prefix: bool,
+    aggregate_output: bool,
+    workspace_concurrency: Option<isize>,
+    /// Timeout and retries of the scripts run by `pn run`.
+    limits: ScriptLimits,
+    kill_grace_period: Duration,
+}
+
+impl ScriptRunner<'_> {
+    /// `label` is only given to scripts that run concurrently with others.
+    fn output(&self, label: Option<&str>, script: &str) -> ScriptOutput {
+        let Some(label) = label else {
+            return ScriptOutput::Inherit;
+        };
+        let prefix = if self.hide_prefix {
+            String::new()
+        } else {
+            output::prefix(label, script)
+        };
+        if self.aggregate_output {
+            ScriptOutput::Aggregated { prefix }
+        } else {
+            ScriptOutput::Lines { prefix }
+        }
+    }
+
+    fn print_and_run(
+        &self,
+        project: &ProjectScripts,
+        name: &str,
+        command: ShellQuoted,
+        label: Option<&str>,
+    ) -> Result<(), MainError> {
+        let output = match (project.log_dir, self.output(label, name)) {
+            (None, output) => output,
+            (
+                Some(log_dir),
+                ScriptOutput::Lines { prefix } | ScriptOutput::Aggregated { prefix },
+            ) => ScriptOutput::Logged {
+                prefix,
+                log_dir: log_dir.to_path_buf(),
+            },
+            (Some(log_dir), _) => ScriptOutput::Logged {
+                prefix: String::new(),
+                log_dir: log_dir.to_path_buf(),
+            },
+        };
+        let header = match &output {
+            ScriptOutput::Lines { prefix }
+            | ScriptOutput::Aggregated { prefix }
+            | ScriptOutput::Logged { prefix, .. }
+                if !prefix.is_empty() =>
+            {
+                format!("{prefix}{command}{limits}\n", limits = self.limits_note())
+            }
+            _ => format!(
+                "\n> {name}@{version} {cwd}{limits}\n> {command}\n\n",
+                limits = self.limits_note(),
+                name = &project.manifest.name,
+                version = &project.manifest.version,
+                cwd = dunce::canonicalize(project.dir)
+                    .unwrap_or_else(|_| project.dir.to_path_buf())
                     .display(),
-            );
-            eprintln!("> {command}\n");
-            run_script(name, command, cwd)
+            ),
         };
-    match cli.command {
-        cli::Command::Run(args) => {
-            let (cwd, manifest) = cwd_and_manifest()?;
-            if let Some(name) = args.script {
-                if let Some(command) = manifest.scripts.get(&name) {
-                    let command = ShellQuoted::from_command_and_args(command.into(), &args.args);
-                    print_and_run_script(&manifest, &name, command, &cwd)
-                } else {
-                    PnError::MissingScript { name }
+        let env = ScriptEnv::new(
+            project.manifest,
+            project.dir,
+            project.workspace_dir,
+            self.init_cwd,
+        )?;
+        if let Some(steps) = nested_chain(&command, &project.manifest.scripts) {
+            output.write_header(&header);
+            let env = env.with_lifecycle(name, &command.to_string());
+            return self.run_chain(project, name, steps, &env, &output, label);
+        }
+        run_script(
+            name,
+            command,
+            project.dir,
+            env,
+            &output,
+            &header,
+            project.config,
+        )
+    }
+
+    /// Run the steps of the `&&` list of the script `name` one after the other, the scripts of the
+    /// same manifest in-process, and fail like the script would when one of them fails.
+    ///
+    /// `env` is the environment of the script `name`, in which its commands run.
+    fn run_chain(
+        &self,
+        project: &ProjectScripts,
+        name: &str,
+        steps: Vec<ChainStep>,
+        env: &ScriptEnv,
+        output: &ScriptOutput,
+        label: Option<&str>,
+    ) -> Result<(), MainError> {
+        for step in steps {
+            let result = match step {
+                ChainStep::Run(run) => {
+                    let nested = &project.manifest.scripts[&run.script];
+                    let hooks = run.forces_hooks || project.config.enable_pre_post_scripts;
+                    self.run_with_hooks_if(project, &run.script, nested, &run.args, label, hooks)
+                }
+                ChainStep::Command(step) => {
+                    pass_to_sub(step, project.dir, env, output, project.config)
+                }
+            };
+            // the package manager of a nested run would exit with the code of its failure
+            let status = match &result {
+                Err(MainError::Sub(status)) => Some(*status),
+                Err(MainError::Pn(
+                    error @ (PnError::ScriptError { .. } | PnError::TerminatedBySignal { .. }),
+                )) => NonZeroI32::new(error.exit_code()),
+                _ => None,
+            };
+            if let Some(status) = status {
+                return PnError::ScriptError {
+                    name: name.to_string(),
+                    status,
+                }
+                .pipe(MainError::Pn)
+                .pipe(Err);
+            }
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Settings of [`ScriptLimits`] shown in the headers of the scripts, e.g. ` (timeout 30s, retry 2)`.
+    fn limits_note(&self) -> String {
+        let ScriptLimits {
+            timeout,
+            retry,
+            retry_backoff,
+        } = &self.limits;
+        let mut notes = Vec::new();
+        if let Some(timeout) = timeout {
+            notes.push(format!("timeout {}", format_duration(*timeout)));
+        }
+        match (retry, retry_backoff) {
+            (0, _) => {}
+            (retry, None) => notes.push(format!("retry {retry}")),
+            (retry, Some(backoff)) => notes.push(format!(
+                "retry {retry}, backoff {}",
+                format_duration(*backoff),
+            )),
+        }
+        if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", notes.join(", "))
+        }
+    }
+
+    /// Run the script `name` with its hooks, within the [`ScriptLimits`].
+    ///
+    /// A failed script runs again after the backoff, unless `pn` was interrupted.
+    fn run_with_hooks(
+        &self,
+        project: &ProjectScripts,
+        name: &str,
+        command: &str,
+        args: &[String],
+        label: Option<&str>,
+    ) -> Result<(), MainError> {
+        let hooks = project.config.enable_pre_post_scripts;
+        let attempts = self.limits.retry.saturating_add(1);
+        let mut backoff = self.limits.retry_backoff.unwrap_or_default();
+        let mut attempt = 1;
+        loop {
+            if let (Some(log_dir), true) = (project.log_dir, attempt > 1) {
+                clear_logs(log_dir)?; // only the logs of the last attempt are cached
+            }
+            let run = || self.run_with_hooks_if(project, name, command, args, label, hooks);
+            let result = match self.limits.timeout {
+                None => run(),
+                Some(timeout) => {
+                    match process_group::with_timeout(timeout, self.kill_grace_period, run) {
+                        (_, true) => PnError::ScriptTimeout {
+                            name: name.to_string(),
+                            timeout,
+                        }
                         .pipe(MainError::Pn)
-                        .pipe(Err)
+                        .pipe(Err),
+                        (result, false) => result,
+                    }
                 }
-            } else if manifest.scripts.is_empty() {
-                println!("There are no scripts in package.json");
-                Ok(())
+            };
+            let error = match result {
+                Err(error) if attempt < attempts && process_group::received_signal().is_none() => {
+                    error
+                }
+                result => return result,
+            };
+            let prefix = match label {
+                Some(label) if !self.hide_prefix => output::prefix(label, name),
+                _ => String::new(),
+            };
+            let delay = if backoff.is_zero() {
+                String::new()
+            } else {
+                format!(" in {}", format_duration(backoff))
+            };
+            warn(format_args!(
+                "{prefix}{failure}, retrying{delay} (attempt {next} of {attempts})",
+                failure = failure_line(name, &error).trim_start(),
+                next = attempt + 1,
+            ));
+            thread::sleep(backoff);
+            backoff = backoff.saturating_mul(2);
+            attempt += 1;
+        }
+    }
+
+    /// Run the script `name` with its `pre` and `post` hooks if `hooks` is set.
+    fn run_with_hooks_if(
+        &self,
+        project: &ProjectScripts,
+        name: &str,
+        command: &str,
+        args: &[String],
+        label: Option<&str>,
+        hooks: bool,
+    ) -> Result<(), MainError> {
+        let run_hook = |hook_name: String| match project.manifest.scripts.get(&hook_name) {
+            Some(hook) if hooks => {
+                let hook = ShellQuoted::from_command(hook.clone());
+                self.print_and_run(project, &hook_name, hook, label)
+            }
+            _ => Ok(()),
+        };
+        run_hook(format!("pre{name}"))?;
+        let command = ShellQuoted::from_command_and_args(command.into(), args);
+        self.print_and_run(project, name, command, label)?;
+        run_hook(format!("post{name}"))
+    }
+
+    /// Run the `tasks` of the projects of `graph`, each after the tasks it depends on, passing
+    /// `args` to the tasks that were asked for.
+    ///
+    /// A task with inputs whose hash is in the cache is not run: its outputs are restored and its
+    /// logs are replayed instead.
+    ///
+    /// Unless `bail`, a failed task doesn't stop the others, except the ones that depend on it,
+    /// which are skipped, and the failures are reported at the end.
+    fn run_task_graph(
+        &self,
+        graph: &WorkspaceGraph,
+        tasks: &TaskGraph,
+        workspace_dir: Option<&Path>,
+        args: &[String],
+        concurrency: usize,
+        bail: bool,
+    ) -> Result<(), MainError> {
+        let cache_root = workspace_dir.unwrap_or(&graph.projects()[0].dir);
+        let cache = TaskCache::new(cache_root);
+        // hashes of the finished tasks, `None` for the ones without inputs
+        let hashes = Mutex::new(vec![None::<String>; tasks.tasks.len()]);
+        let is_concurrent = concurrency > 1 && tasks.tasks.len() > 1;
+        let run_task = |index: usize| -> Result<(), MainError> {
+            let task = &tasks.tasks[index];
+            let project = &graph.projects()[task.project];
+            let config = Config::load(&project.dir, workspace_dir)?;
+            let command = &project.manifest.scripts[&task.script];
+            let args = if task.is_root { args } else { &[] };
+            let label = is_concurrent.then(|| match workspace_dir {
+                Some(workspace_dir) => project_label(workspace_dir, project),
+                None => ".".to_string(),
+            });
+            let label = label.as_deref();
+            let mut project_scripts = ProjectScripts {
+                manifest: &project.manifest,
+                config: &config,
+                dir: &project.dir,
+                workspace_dir,
+                log_dir: None,
+            };
+            let dependency_hashes: Option<Vec<_>> = {
+                let hashes = hashes.lock().unwrap();
+                tasks.waits_for[index]
+                    .iter()
+                    .map(|&dependency| hashes[dependency].clone())
+                    .collect()
+            };
+            // a task is only cached if the tasks it depends on are
+            let Some(dependency_hashes) =
+                dependency_hashes.filter(|_| !task.config.inputs.is_empty())
+            else {
+                return self.run_with_hooks(&project_scripts, &task.script, command, args, label);
+            };
+            let hook = |hook_name: String| match project.manifest.scripts.get(&hook_name) {
+                Some(hook) if config.enable_pre_post_scripts => hook.as_str(),
+                _ => "",
+            };
+            let args_text = ShellQuoted::from_args(args).to_string();
+            let fingerprint = [
+                task.script.as_str(),
+                command.as_str(),
+                &args_text,
+                hook(format!("pre{}", task.script)),
+                hook(format!("post{}", task.script)),
+            ];
+            let hash = task_hash(
+                &project.dir,
+                &task.config.inputs,
+                &fingerprint,
+                &dependency_hashes,
+            )?;
+            if let Some(entry) = cache.get(&hash) {
+                let prefix = match self.output(label, &task.script) {
+                    ScriptOutput::Lines { prefix } | ScriptOutput::Aggregated { prefix } => prefix,
+                    _ => String::new(),
+                };
+                if prefix.is_empty() {
+                    eprint!(
+                        "\n> {name}@{version} {cwd}\n> {script}: cache hit, replaying logs\n\n",
+                        name = &project.manifest.name,
+                        version = &project.manifest.version,
+                        cwd = dunce::canonicalize(&project.dir)
+                            .unwrap_or_else(|_| project.dir.clone())
+                            .display(),
+                        script = &task.script,
+                    );
+                } else {
+                    eprintln!("{prefix}cache hit, replaying logs");
+                }
+                entry.restore_outputs(&project.dir, &task.config.outputs)?;
+                entry.replay_logs(&prefix);
             } else {
-                list_scripts(io::stdout(), manifest.scripts)
-                    .map_err(PnError::WriteStdoutError)
-                    .map_err(MainError::from)
+                let pending = cache.start(&hash)?;
+                project_scripts.log_dir = Some(pending.log_dir());
+                self.run_with_hooks(&project_scripts, &task.script, command, args, label)?;
+                pending.commit(&project.dir, &task.config.outputs)?;
+            }
+            hashes.lock().unwrap()[index] = Some(hash);
+            Ok(())
+        };
+        let names: Vec<_> = tasks
+            .tasks
+            .iter()
+            .map(|task| {
+                let project = &graph.projects()[task.project];
+                format!("{}#{}", project_display_name(project), task.script)
+            })
+            .collect();
+        // tasks that failed or were skipped, and the errors of the ones that failed
+        let failed = Mutex::new(vec![false; tasks.tasks.len()]);
+        let failures = Mutex::new(Vec::new());
+        run_tasks(&tasks.waits_for, concurrency, |index| {
+            let skipped = {
+                let failed = failed.lock().unwrap();
+                tasks.waits_for[index]
+                    .iter()
+                    .any(|&dependency| failed[dependency])
+            };
+            match if skipped { Ok(()) } else { run_task(index) } {
+                // an interruption stops the run even with `--no-bail`
+                Err(error) if !bail && process_group::received_signal().is_none() => {
+                    failed.lock().unwrap()[index] = true;
+                    failures
+                        .lock()
+                        .unwrap()
+                        .push((index, names[index].as_str(), error));
+                    Ok(())
+                }
+                result => {
+                    failed.lock().unwrap()[index] = skipped;
+                    result
+                }
             }
+        })?;
+        script_failures(failures.into_inner().unwrap())
+    }
+
+    /// Scripts selected by a `/regex/` run concurrently, labelled with `project_label`.
+    fn run_selected(
+        &self,
+        project: &ProjectScripts,
+        scripts: &[(&str, &str)],
+        args: &[String],
+        label: Option<&str>,
+        project_label: &str,
+    ) -> Result<(), MainError> {
+        if let [(name, command)] = scripts {
+            return self.run_with_hooks(project, name, command, args, label);
         }
-        cli::Command::Other(args) => {
-            let (cwd, manifest) = cwd_and_manifest()?;
-            if let Some(name) = args.first() {
-                let name = name.as_str();
-                if passed_through::PASSED_THROUGH_COMMANDS.contains(name) {
-                    return pass_to_pnpm(&args); // args already contain name, no need to prepend
+        let concurrency = self
+            .workspace_concurrency
+            .or(project.config.workspace_concurrency)
+            .pipe(concurrency_limit);
+        let label = label.or((concurrency > 1).then_some(project_label));
+        run_tasks(&vec![Vec::new(); scripts.len()], concurrency, |index| {
+            let (name, command) = scripts[index];
+            self.run_with_hooks(project, name, command, args, label)
+        })
+    }
+
+    /// Run the script `args.script` of `project`, or of another project of the workspace when it
+    /// is written `<package>#<script>`, or list the scripts of `project` if there is none.
+    fn run(&self, project: CurrentProject, args: &RunArgs) -> Result<(), MainError> {
+        let Some(name) = args.script() else {
+            if project.manifest.scripts.is_empty() {
+                println!("There are no scripts in package.json");
+                return Ok(());
+            }
+            return list_scripts(io::stdout(), project.manifest.scripts)
+                .map_err(PnError::WriteStdoutError)
+                .map_err(MainError::from);
+        };
+        let reference = name.split_once('#').filter(|(package, _)| {
+            !package.is_empty() && !project.manifest.scripts.contains_key(name)
+        });
+        let Some((package, script)) = reference else {
+            return self.run_or_watch(&project.scripts(), name, args);
+        };
+        let workspace_dir = project
+            .workspace_dir
+            .as_deref()
+            .ok_or(PnError::NotInWorkspace {
+                flag: "<package>#<script>",
+            })?;
+        let projects = workspace::list_workspace_projects(workspace_dir)?;
+        let other = workspace::find_project_by_name(&projects, package)?;
+        let config = Config::load(&other.dir, Some(workspace_dir))?;
+        let project_scripts = ProjectScripts {
+            manifest: &other.manifest,
+            config: &config,
+            dir: &other.dir,
+            workspace_dir: Some(workspace_dir),
+            log_dir: None,
+        };
+        self.run_or_watch(&project_scripts, script, args)
+    }
+
+    /// Run the script `name`, again whenever the files of `project` change with `--watch`.
+    fn run_or_watch(
+        &self,
+        project: &ProjectScripts,
+        name: &str,
+        args: &RunArgs,
+    ) -> Result<(), MainError> {
+        if args.watch {
+            self.watch_script(project, name, args)
+        } else {
+            self.run_named_script(project, name, args)
+        }
+    }
+
+    /// Run the scripts of `project` selected by `name`, as a task graph if it is a task.
+    fn run_named_script(
+        &self,
+        project: &ProjectScripts,
+        name: &str,
+        args: &RunArgs,
+    ) -> Result<(), MainError> {
+        let selector = ScriptSelector::parse(name)?;
+        if let ScriptSelector::Name(script) = &selector {
+            let workspace_tasks = workspace_tasks(project.workspace_dir)?;
+            if project.manifest.scripts.contains_key(script)
+                && task_config(project.manifest, &workspace_tasks, script).is_some()
+            {
+                let (graph, root) = project_graph(project)?;
+                let tasks = TaskGraph::build(&graph, &workspace_tasks, &[root], script)?;
+                let concurrency = self
+                    .workspace_concurrency
+                    .or(project.config.workspace_concurrency)
+                    .pipe(concurrency_limit);
+                return self.run_task_graph(
+                    &graph,
+                    &tasks,
+                    project.workspace_dir,
+                    args.args(),
+                    concurrency,
+                    !args.no_bail,
+                );
+            }
+        }
+        let scripts = selector.select(&project.manifest.scripts);
+        if scripts.is_empty() {
+            if args.if_present {
+                return Ok(());
+            }
+            return selector.missing_error().pipe(MainError::Pn).pipe(Err);
+        }
+        let project_label = match project.manifest.name.as_str() {
+            "" => ".",
+            name => name,
+        };
+        self.run_selected(project, &scripts, args.args(), None, project_label)
+    }
+
+    /// Run the script `name`, and restart it whenever the watched files of `project` change, until
+    /// `pn` is interrupted.
+    fn watch_script(
+        &self,
+        project: &ProjectScripts,
+        name: &str,
+        args: &RunArgs,
+    ) -> Result<(), MainError> {
+        let mut watcher = Watcher::new(project.dir, &args.watch_path, &args.watch_ignore)?;
+        loop {
+            process_group::resume();
+            let changes = thread::scope(|scope| {
+                scope.spawn(|| {
+                    let result = self.run_named_script(project, name, args);
+                    if process_group::received_signal().is_some() {
+                        return; // stopped to restart, or interrupted
+                    }
+                    match result {
+                        Ok(()) => eprintln!("{name} finished, waiting for changes"),
+                        Err(error) => warn(format_args!(
+                            "{}, waiting for changes",
+                            failure_line(name, &error).trim_start(),
+                        )),
+                    }
+                });
+                let changes = watcher.wait_for_changes();
+                process_group::terminate_all(self.kill_grace_period);
+                changes
+            })?;
+            eprintln!(
+                "{}",
+                Yellow.paint(format!(
+                    "Restarting {name}: {} changed",
+                    describe_changes(&changes),
+                )),
+            );
+        }
+    }
+
+    /// Run a command in `project`, like `pn exec <command>`.
+    fn exec(&self, project: &CurrentProject, args: &ExecArgs) -> Result<(), MainError> {
+        let env = project
+            .env(self.init_cwd)?
+            .with_exec(&project.manifest.name);
+        exec(
+            args,
+            &project.dir,
+            &env,
+            &ScriptOutput::Inherit,
+            &project.config,
+        )
+    }
+
+    /// Run the scripts of `project` matched by `args` one after the other, like `run-s`.
+    fn run_s(&self, project: &CurrentProject, args: &RunAllArgs) -> Result<(), MainError> {
+        let scripts = select_globs(&args.patterns, &project.manifest.scripts)?;
+        let project_scripts = project.scripts();
+        let mut failures = Vec::new();
+        for (index, (name, command)) in scripts.into_iter().enumerate() {
+            match self.run_with_hooks(&project_scripts, name, command, &[], None) {
+                // an interruption stops the run even with `--continue-on-error`
+                Err(error)
+                    if args.continue_on_error && process_group::received_signal().is_none() =>
+                {
+                    failures.push((index, name, error));
                 }
-                if let Some(command) = manifest.scripts.get(name) {
-                    let command = ShellQuoted::from_command_and_args(command.into(), &args[1..]);
-                    return print_and_run_script(&manifest, name, command, &cwd);
+                result => result?,
+            }
+        }
+        script_failures(failures)
+    }
+
+    /// Run the scripts of `project` matched by `args` at the same time, like `run-p`.
+    fn run_p(&self, project: &CurrentProject, args: &RunParallelArgs) -> Result<(), MainError> {
+        let scripts = select_globs(&args.scripts.patterns, &project.manifest.scripts)?;
+        let project_scripts = project.scripts();
+        let concurrency = args.max_parallel.map_or(usize::MAX, NonZeroUsize::get);
+        let label = match project.manifest.name.as_str() {
+            "" => ".",
+            name => name,
+        };
+        let label = (concurrency > 1 && scripts.len() > 1).then_some(label);
+        // set once a script wins the race, after which the others are stopped
+        let won = AtomicBool::new(false);
+        let failures = Mutex::new(Vec::new());
+        run_tasks(&vec![Vec::new(); scripts.len()], concurrency, |index| {
+            if won.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let (name, command) = scripts[index];
+            match self.run_with_hooks(&project_scripts, name, command, &[], label) {
+                _ if won.load(Ordering::SeqCst) => Ok(()),
+                Ok(()) => {
+                    if args.race && !won.swap(true, Ordering::SeqCst) {
+                        process_group::terminate_all(self.kill_grace_period);
+                    }
+                    Ok(())
+                }
+                Err(error)
+                    if args.scripts.continue_on_error
+                        && process_group::received_signal().is_none() =>
+                {
+                    failures.lock().unwrap().push((index, name, error));
+                    Ok(())
+                }
+                Err(error) => {
+                    if process_group::received_signal().is_none() {
+                        process_group::terminate_all(self.kill_grace_period);
+                    }
+                    Err(error)
                 }
             }
-            pass_to_sub(ShellQuoted::from_args(args))
+        })?;
+        script_failures(failures.into_inner().unwrap())
+    }
+
+    /// Pass `args` to pnpm if it is a pnpm command, run it as a script of `project` if it has one,
+    /// or as a shell command otherwise.
+    fn run_other(&self, project: &CurrentProject, args: &[String]) -> Result<(), MainError> {
+        if let Some(name) = args.first() {
+            let name = name.as_str();
+            if passed_through::PASSED_THROUGH_COMMANDS.contains(name) {
+                return pass_to_pnpm(args, &project.dir); // args already contain name, no need to prepend
+            }
+            if let Some(command) = project.manifest.scripts.get(name) {
+                return self.run_with_hooks(&project.scripts(), name, command, &args[1..], None);
+            }
         }
+        pass_to_sub(
+            ShellQuoted::from_args(args),
+            &project.dir,
+            &project.env(self.init_cwd)?,
+            &ScriptOutput::Inherit,
+            &project.config,
+        )
     }
 }
 
@@ -201,12 +1056,48 @@ mod tests {
         assert_eq!(received_message, expected_message);
     }
 
+    #[test]
+    fn test_relative_path() {
+        let cases = [
+            ("/repo/packages/foo", "/repo", "packages/foo"),
+            ("/repo/packages/foo", "/repo/apps/web", "../../packages/foo"),
+            ("/repo", "/repo/packages/foo", "../.."),
+            ("/repo", "/repo", "."),
+        ];
+        for (path, base, expected) in cases {
+            let received = relative_path(Path::new(path), Path::new(base));
+            assert_eq!(received, Path::new(expected));
+        }
+    }
+
+    #[test]
+    fn test_format_duration() {
+        let cases = ["500ms", "10s", "1.5s", "0ms", "90s"];
+        for text in cases {
+            let duration = parse_duration(text).unwrap();
+            assert_eq!(format_duration(duration), text);
+        }
+    }
+
     #[test]
     fn test_create_path_env() {
-        let bin_path = Path::new("node_modules").join(".bin");
-        let path_env = create_path_env().expect("prepend 'node_modules/.bin' to PATH");
+        let root = env::temp_dir();
+        let package_dir = root.join("repo/packages/foo");
+        let workspace_dir = root.join("repo");
+        let path_env = create_path_env(&package_dir, Some(&workspace_dir))
+            .expect("prepend the 'node_modules/.bin' directories to PATH");
 
-        let first_path = env::split_paths(&path_env).next();
-        assert_eq!(first_path, Some(bin_path));
+        let bin_paths: Vec<_> = env::split_paths(&path_env)
+            .filter(|path| path.ends_with("node_modules/.bin"))
+            .collect();
+        dbg!(&bin_paths);
+        assert_eq!(
+            bin_paths,
+            [
+                package_dir.join("node_modules/.bin"),
+                root.join("repo/packages/node_modules/.bin"),
+                workspace_dir.join("node_modules/.bin"),
+            ],
+        );
     }
 }
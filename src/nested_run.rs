@@ -0,0 +1,188 @@
+//! Scripts that call `pnpm run`, `npm run` or `pn` for another script of the same manifest, which
+//! `pn` runs in-process instead of spawning a package manager for them.
+
+use crate::{passed_through::PASSED_THROUGH_COMMANDS, shell_quoted::ShellQuoted};
+use indexmap::IndexMap;
+use pipe_trait::Pipe;
+
+/// Call to a script of the same manifest through a package manager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NestedRun {
+    /// Name of the called script.
+    pub script: String,
+    /// Arguments passed to the script.
+    pub args: Vec<String>,
+    /// Whether the `pre` and `post` hooks run regardless of `enable-pre-post-scripts`, as with `npm`.
+    pub forces_hooks: bool,
+}
+
+/// A command of an `&&` list.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChainStep {
+    /// A script of the same manifest, run in-process.
+    Run(NestedRun),
+    /// Any other command, which runs on its own.
+    Command(ShellQuoted),
+}
+
+/// Split a script into the steps of its `&&` list if one of them calls another script of `scripts`.
+///
+/// Anything else gives `None`, so that the script runs as usual.
+pub fn nested_chain(
+    command: &ShellQuoted,
+    scripts: &IndexMap<String, String>,
+) -> Option<Vec<ChainStep>> {
+    let steps: Vec<_> = command
+        .split_and_list()?
+        .into_iter()
+        .map(|words| match parse_nested_run(&words, scripts) {
+            Some(run) => ChainStep::Run(run),
+            None => ShellQuoted::from_args(words).pipe(ChainStep::Command),
+        })
+        .collect();
+    steps
+        .iter()
+        .any(|step| matches!(step, ChainStep::Run(_)))
+        .then_some(steps)
+}
+
+/// Recognize `words` as a call to a script of `scripts` whose arguments reach the script the same
+/// way when it runs in-process.
+pub fn parse_nested_run(words: &[String], scripts: &IndexMap<String, String>) -> Option<NestedRun> {
+    let (program, rest) = words.split_first()?;
+    let is_run = |word: &String| word == "run" || word == "run-script";
+    let (script, args, forces_hooks) = match (program.as_str(), rest) {
+        ("pnpm", [run, script, args @ ..]) if is_run(run) => {
+            // pnpm hands the arguments after the script name over as is, but its handling of `--`
+            // changed across versions
+            if args.iter().any(|arg| arg == "--") {
+                return None;
+            }
+            (script, args.to_vec(), false)
+        }
+        ("npm", [run, script, args @ ..]) if is_run(run) => {
+            // npm takes the options before `--` for itself
+            let args = match args {
+                [separator, args @ ..] if separator == "--" => args,
+                args if args.iter().any(|arg| arg.starts_with('-')) => return None,
+                args => args,
+            };
+            (script, args.to_vec(), true)
+        }
+        ("pn", [run, script, args @ ..]) if is_run(run) => {
+            if args.iter().any(|arg| arg.starts_with('-')) {
+                return None;
+            }
+            (script, args.to_vec(), false)
+        }
+        ("pn", [script, args @ ..])
+            if !PASSED_THROUGH_COMMANDS.contains(script.as_str())
+                && !["run", "run-script", "exec"].contains(&script.as_str()) =>
+        {
+            (script, args.to_vec(), false)
+        }
+        _ => return None,
+    };
+    if script.starts_with('-') || !scripts.contains_key(script) {
+        return None;
+    }
+    Some(NestedRun {
+        script: script.clone(),
+        args,
+        forces_hooks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn scripts() -> IndexMap<String, String> {
+        [("lint", "eslint ."), ("test", "vitest run")]
+            .into_iter()
+            .map(|(name, command)| (name.to_string(), command.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_nested_run() {
+        let parse = |command: &str| {
+            let words: Vec<_> = command.split(' ').map(String::from).collect();
+            parse_nested_run(&words, &scripts())
+        };
+        let run = |script: &str, args: &[&str], forces_hooks: bool| NestedRun {
+            script: script.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            forces_hooks,
+        };
+        assert_eq!(parse("pnpm run lint"), Some(run("lint", &[], false)));
+        assert_eq!(
+            parse("pnpm run-script test --watch"),
+            Some(run("test", &["--watch"], false)),
+        );
+        assert_eq!(parse("npm run test"), Some(run("test", &[], true)));
+        assert_eq!(
+            parse("npm run test -- --watch"),
+            Some(run("test", &["--watch"], true)),
+        );
+        assert_eq!(parse("pn run test a"), Some(run("test", &["a"], false)));
+        assert_eq!(
+            parse("pn test --watch"),
+            Some(run("test", &["--watch"], false))
+        );
+        let spawned = [
+            "pnpm run build",
+            "pnpm run test -- --watch",
+            "pnpm run --silent test",
+            "pnpm test",
+            "npm run test --watch",
+            "npm test",
+            "pn run test --watch",
+            "pn install",
+            "yarn run test",
+            "./pnpm run test",
+        ];
+        for command in spawned {
+            eprintln!("command={command:?}");
+            assert_eq!(parse(command), None);
+        }
+    }
+
+    #[test]
+    fn test_nested_chain() {
+        let chain = |command: &str| {
+            nested_chain(&ShellQuoted::from_command(command.to_string()), &scripts())
+        };
+        let received = chain("tsc -p . && pnpm run lint && pn test").unwrap();
+        let expected = vec![
+            ChainStep::Command(ShellQuoted::from_command("'tsc' '-p' '.'".to_string())),
+            ChainStep::Run(NestedRun {
+                script: "lint".to_string(),
+                args: Vec::new(),
+                forces_hooks: false,
+            }),
+            ChainStep::Run(NestedRun {
+                script: "test".to_string(),
+                args: Vec::new(),
+                forces_hooks: false,
+            }),
+        ];
+        assert_eq!(received, expected);
+        assert_eq!(chain("tsc && vitest"), None);
+        assert_eq!(chain("pnpm run lint || pnpm run test"), None);
+        assert_eq!(
+            chain("pnpm run build && pnpm run test"),
+            Some(vec![
+                ChainStep::Command(ShellQuoted::from_command(
+                    "'pnpm' 'run' 'build'".to_string()
+                )),
+                ChainStep::Run(NestedRun {
+                    script: "test".to_string(),
+                    args: Vec::new(),
+                    forces_hooks: false,
+                }),
+            ])
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Stream the output of concurrently running scripts line by line, so that lines of different
+//! processes never mix.
+
+use crate::{
+    error::PnError,
+    process_group,
+    task_cache::{LogStream, TaskLog},
+};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    thread,
+};
+use yansi::Color;
+
+/// Colors given to the prefixes of the different projects.
+const PREFIX_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Magenta,
+    Color::Blue,
+    Color::Yellow,
+    Color::Green,
+    Color::Red,
+];
+
+/// How the output of a child process reaches the terminal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptOutput {
+    /// The child writes to the terminal directly.
+    Inherit,
+    /// The output of the child is forwarded line by line, each line starting with `prefix`.
+    Lines { prefix: String },
+    /// The output of the child is collected, then written in one block once it exits, each line
+    /// starting with `prefix`.
+    Aggregated { prefix: String },
+    /// The output of the child is forwarded line by line like with `Lines`, and appended without
+    /// the prefix to the logs of a cached task in `log_dir`.
+    Logged { prefix: String, log_dir: PathBuf },
+}
+
+impl ScriptOutput {
+    /// Spawn `command` with this output mode and wait for it to exit.
+    ///
+    /// `header` is written to stderr before the output of the child.
+    pub fn run(&self, command: &mut Command, header: &str) -> Result<ExitStatus, PnError> {
+        let prefix = match self {
+            ScriptOutput::Inherit => {
+                self.write_header(header);
+                command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+                return process_group::spawn(command, true)?.wait();
+            }
+            ScriptOutput::Lines { prefix } | ScriptOutput::Logged { prefix, .. } => {
+                self.write_header(header);
+                prefix
+            }
+            ScriptOutput::Aggregated { prefix } => prefix,
+        };
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = process_group::spawn(command, false)?;
+        if let ScriptOutput::Lines { .. } = self {
+            let stdout = child.take_stdout().expect("stdout is piped");
+            let stderr = child.take_stderr().expect("stderr is piped");
+            thread::scope(|scope| {
+                scope.spawn(|| copy_lines(stdout, io::stdout(), prefix));
+                copy_lines(stderr, io::stderr(), prefix);
+            });
+            return child.wait();
+        }
+        if let ScriptOutput::Logged { log_dir, .. } = self {
+            let stdout = child.take_stdout().expect("stdout is piped");
+            let stderr = child.take_stderr().expect("stderr is piped");
+            let log = TaskLog::open(log_dir)?;
+            thread::scope(|scope| {
+                scope.spawn(|| {
+                    copy_logged_lines(stdout, io::stdout(), prefix, |line| {
+                        log.write_line(LogStream::Stdout, line)
+                    })
+                });
+                copy_logged_lines(stderr, io::stderr(), prefix, |line| {
+                    log.write_line(LogStream::Stderr, line)
+                });
+            });
+            return child.wait();
+        }
+        let (status, stdout, stderr) = child.wait_with_output()?;
+        let mut stdout_block = Vec::new();
+        copy_lines(&*stdout, &mut stdout_block, prefix);
+        let mut stderr_block = header.as_bytes().to_vec();
+        copy_lines(&*stderr, &mut stderr_block, prefix);
+        // hold both locks so that no other line gets between the two halves of the block
+        let mut stdout = io::stdout().lock();
+        let mut stderr = io::stderr().lock();
+        stderr.write_all(&stderr_block).ok();
+        stderr.flush().ok();
+        stdout.write_all(&stdout_block).ok();
+        stdout.flush().ok();
+        Ok(status)
+    }
+
+    /// Write the `header` of a script to stderr like [`run`](Self::run) does, also for a script that
+    /// runs in-process, such as an `&&` list of nested runs.
+    pub fn write_header(&self, header: &str) {
+        // one write under the lock, so that no line of a concurrent script gets into the header
+        let mut stderr = io::stderr().lock();
+        stderr.write_all(header.as_bytes()).ok();
+        stderr.flush().ok();
+    }
+}
+
+/// Prefix of the output lines of `script` of the project `label`, painted in the color of `label`.
+pub fn prefix(label: &str, script: &str) -> String {
+    let prefix = format!("{label} {script}$");
+    format!("{} ", prefix_color(label).paint(prefix))
+}
+
+/// Pick the color of `label`, which stays the same from one run to the next.
+pub fn prefix_color(label: &str) -> Color {
+    // FNV-1a, because the standard hasher is not guaranteed to be stable
+    let hash = label.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    PREFIX_COLORS[(hash % PREFIX_COLORS.len() as u64) as usize]
+}
+
+/// Copy every line of `reader` to `writer` with `prefix` in front of it.
+///
+/// Every line is written at once, so that lines written to the same destination by other threads
+/// don't cut through it. A last line without a line break gets one.
+///
+/// Write errors are ignored so that the child never blocks on a full pipe.
+pub fn copy_lines(reader: impl Read, writer: impl Write, prefix: &str) {
+    copy_logged_lines(reader, writer, prefix, |_| {});
+}
+
+/// Copy every line of `reader` to `writer` with `prefix` in front of it like [`copy_lines`], and
+/// pass it as is to `log`.
+pub fn copy_logged_lines(
+    reader: impl Read,
+    mut writer: impl Write,
+    prefix: &str,
+    mut log: impl FnMut(&[u8]),
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        line.extend_from_slice(prefix.as_bytes());
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if line.last() != Some(&b'\n') {
+            line.push(b'\n');
+        }
+        writer.write_all(&line).ok();
+        log(&line[prefix.len()..]);
+    }
+    writer.flush().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_copy_lines() {
+        let mut received = Vec::new();
+        copy_lines(
+            &b"first\nsecond\r\n\nlast"[..],
+            &mut received,
+            "foo build$ ",
+        );
+        let received = String::from_utf8(received).unwrap();
+        let expected = "foo build$ first\nfoo build$ second\r\nfoo build$ \nfoo build$ last\n";
+        assert_eq!(received, expected);
+
+        let mut received = Vec::new();
+        copy_lines(&b""[..], &mut received, "foo build$ ");
+        assert_eq!(received, b"");
+    }
+
+    #[test]
+    fn test_prefix_color() {
+        assert_eq!(prefix_color("packages/api"), prefix_color("packages/api"));
+        let colors: Vec<_> = ["a", "b", "c", "d", "e", "f", "g", "h"]
+            .into_iter()
+            .map(prefix_color)
+            .collect();
+        dbg!(&colors);
+        assert!(colors.iter().any(|color| *color != colors[0]));
+    }
+}
@@ -0,0 +1,444 @@
+//! Run every child in its own process group, so that signals reach the whole process tree of a
+//! script, including the grandchildren started by `sh -c`.
+//!
+//! `SIGINT`, `SIGTERM` and `SIGHUP` received by `pn` are forwarded to the process groups of the
+//! running children, which are killed with `SIGKILL` if they are still running after a grace period.
+//! `pn` keeps waiting for its children in the meantime, and refuses to spawn new ones.
+//!
+//! Children spawned by [`with_timeout`] are terminated the same way when its timeout expires.
+//!
+//! A child that writes to the terminal on its own gets the terminal as its foreground process
+//! group, so that it receives `Ctrl-C` and `Ctrl-Z` directly and can read from the terminal.
+
+use crate::error::PnError;
+use std::{
+    cell::RefCell,
+    io::Read,
+    process::{Child, ChildStderr, ChildStdout, Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// A process group of a running child.
+#[derive(Debug)]
+struct Group {
+    /// Process ID of the leader of the group.
+    id: u32,
+    /// Timeout of the [`with_timeout`] call that spawned the child, if any.
+    timeout: Option<Arc<TimeoutScope>>,
+}
+
+/// Process groups of the running children.
+static GROUPS: Mutex<Vec<Group>> = Mutex::new(Vec::new());
+
+/// State of a [`with_timeout`] call.
+#[derive(Debug, Default)]
+struct TimeoutScope {
+    /// Set once the timeout expires, after which the call spawns no more children.
+    expired: AtomicBool,
+}
+
+thread_local! {
+    /// Timeout of the [`with_timeout`] call that the current thread is in, if any.
+    static TIMEOUT: RefCell<Option<Arc<TimeoutScope>>> = const { RefCell::new(None) };
+}
+
+/// Last signal forwarded to the children, or sent by [`terminate_all`], zero if none.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// Signal received by `pn` that was forwarded to the children, or sent by [`terminate_all`], if any.
+pub fn received_signal() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// A child process that leads its own process group.
+#[derive(Debug)]
+pub struct GroupChild {
+    child: Child,
+    foreground: bool,
+}
+
+/// Spawn `command` in a new process group, which becomes the foreground process group of the
+/// terminal when `foreground` is set and `pn` owns the terminal.
+///
+/// A child that does not own the terminal reads nothing from it, because it would be stopped if it
+/// tried.
+pub fn spawn(command: &mut Command, foreground: bool) -> Result<GroupChild, PnError> {
+    // hold the lock so that a signal cannot be forwarded between the spawn and the registration
+    let mut groups = GROUPS.lock().unwrap();
+    if let Some(signal) = received_signal() {
+        return Err(PnError::Interrupted { signal });
+    }
+    let timeout = TIMEOUT.with(|timeout| timeout.borrow().clone());
+    if let Some(timeout) = &timeout {
+        if timeout.expired.load(Ordering::SeqCst) {
+            let signal = sys::SIGTERM;
+            return Err(PnError::Interrupted { signal });
+        }
+    }
+    let foreground = foreground && sys::owns_terminal();
+    sys::prepare(command, foreground);
+    let child = command.spawn().map_err(PnError::SpawnProcessError)?;
+    groups.push(Group {
+        id: child.id(),
+        timeout,
+    });
+    Ok(GroupChild { child, foreground })
+}
+
+impl GroupChild {
+    /// Take the piped stdout of the child.
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    /// Take the piped stderr of the child.
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.child.stderr.take()
+    }
+
+    /// Wait for the child to exit.
+    pub fn wait(&mut self) -> Result<ExitStatus, PnError> {
+        let status = if self.foreground {
+            sys::wait_foreground(&self.child)
+        } else {
+            self.child.wait()
+        };
+        unregister(self.child.id());
+        status.map_err(PnError::WaitProcessError)
+    }
+
+    /// Wait for the child to exit, collecting its piped stdout and stderr.
+    pub fn wait_with_output(&mut self) -> Result<(ExitStatus, Vec<u8>, Vec<u8>), PnError> {
+        let read = |reader: Option<&mut dyn Read>| {
+            let mut buffer = Vec::new();
+            if let Some(reader) = reader {
+                reader.read_to_end(&mut buffer).ok();
+            }
+            buffer
+        };
+        let mut stdout = self.take_stdout();
+        let mut stderr = self.take_stderr();
+        let (stdout, stderr) = thread::scope(|scope| {
+            let stdout = scope.spawn(|| read(stdout.as_mut().map(|x| x as &mut dyn Read)));
+            let stderr = read(stderr.as_mut().map(|x| x as &mut dyn Read));
+            (stdout.join().expect("reading stdout never panics"), stderr)
+        });
+        Ok((self.wait()?, stdout, stderr))
+    }
+}
+
+impl Drop for GroupChild {
+    fn drop(&mut self) {
+        unregister(self.child.id());
+    }
+}
+
+fn unregister(id: u32) {
+    GROUPS.lock().unwrap().retain(|group| group.id != id);
+}
+
+/// Forward `SIGINT`, `SIGTERM` and `SIGHUP` to the process groups of the children from now on,
+/// killing them if they are still running after `grace_period`.
+///
+/// When no child is running, the signal terminates `pn` as if it had no handler.
+pub fn forward_signals(grace_period: Duration) {
+    sys::forward_signals(grace_period);
+}
+
+/// Terminate the running children as if `pn` received `SIGTERM`, killing them if they are still
+/// running after `grace_period`, and refuse to spawn new ones.
+///
+/// This stops the other scripts of a run once its outcome is known, such as with `pn run-p --race`.
+pub fn terminate_all(grace_period: Duration) {
+    let groups = {
+        let groups = GROUPS.lock().unwrap();
+        RECEIVED.store(sys::SIGTERM, Ordering::SeqCst);
+        group_ids(&groups, |_| true)
+    };
+    signal_groups(groups, sys::SIGTERM, grace_period);
+}
+
+/// Run `f`, terminating the children it spawns as if `pn` received `SIGTERM` once `timeout`
+/// expires, and killing them if they are still running after `grace_period`.
+///
+/// Returns the result of `f` and whether the timeout expired, after which `f` could spawn no more
+/// children. The children must be spawned by the thread that calls `f`.
+pub fn with_timeout<T>(
+    timeout: Duration,
+    grace_period: Duration,
+    f: impl FnOnce() -> T,
+) -> (T, bool) {
+    let scope = Arc::new(TimeoutScope::default());
+    let previous = TIMEOUT.with(|current| current.replace(Some(scope.clone())));
+    let (finished, watchdog) = mpsc::channel::<()>();
+    let result = thread::scope(|threads| {
+        let scope = &scope;
+        threads.spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = watchdog.recv_timeout(timeout) {
+                let groups = {
+                    let groups = GROUPS.lock().unwrap();
+                    // while the lock is held, so that no child is spawned after this
+                    scope.expired.store(true, Ordering::SeqCst);
+                    group_ids(&groups, |group| {
+                        group
+                            .timeout
+                            .as_ref()
+                            .is_some_and(|timeout| Arc::ptr_eq(timeout, scope))
+                    })
+                };
+                signal_groups(groups, sys::SIGTERM, grace_period);
+            }
+        });
+        let result = f();
+        drop(finished);
+        result
+    });
+    TIMEOUT.with(|current| current.replace(previous));
+    (result, scope.expired.load(Ordering::SeqCst))
+}
+
+/// Let new children spawn again after [`terminate_all`], such as to restart a script with
+/// `pn run --watch`.
+///
+/// A signal received by `pn` in the meantime is kept.
+pub fn resume() {
+    RECEIVED
+        .compare_exchange(sys::SIGTERM, 0, Ordering::SeqCst, Ordering::SeqCst)
+        .ok();
+}
+
+/// Send `signal` to the running children, then `SIGKILL` to those still running after
+/// `grace_period`.
+fn forward(signal: i32, grace_period: Duration) {
+    let groups = {
+        let groups = GROUPS.lock().unwrap();
+        if groups.is_empty() {
+            return sys::die_of(signal);
+        }
+        // while the lock is held, so that no child is spawned without receiving the signal
+        RECEIVED.store(signal, Ordering::SeqCst);
+        group_ids(&groups, |_| true)
+    };
+    signal_groups(groups, signal, grace_period);
+}
+
+/// Process IDs of the leaders of the `groups` that match `predicate`.
+fn group_ids(groups: &[Group], predicate: impl Fn(&Group) -> bool) -> Vec<u32> {
+    groups
+        .iter()
+        .filter(|group| predicate(group))
+        .map(|group| group.id)
+        .collect()
+}
+
+/// Send `signal` to `groups`, then `SIGKILL` to those still running after `grace_period`.
+fn signal_groups(groups: Vec<u32>, signal: i32, grace_period: Duration) {
+    for group in &groups {
+        sys::kill_group(*group, signal);
+    }
+    thread::spawn(move || {
+        thread::sleep(grace_period);
+        let remaining = GROUPS.lock().unwrap();
+        let remaining = group_ids(&remaining, |_| true);
+        for group in groups.iter().filter(|group| remaining.contains(group)) {
+            sys::kill_group(*group, sys::SIGKILL);
+        }
+    });
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{
+        fs::File,
+        io::{self, ErrorKind, IsTerminal, Read},
+        mem,
+        os::{
+            fd::FromRawFd,
+            unix::process::{CommandExt, ExitStatusExt},
+        },
+        process::{Child, Command, ExitStatus, Stdio},
+        ptr,
+        sync::atomic::{AtomicI32, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    pub const SIGKILL: i32 = libc::SIGKILL;
+    pub const SIGTERM: i32 = libc::SIGTERM;
+
+    const FORWARDED_SIGNALS: [i32; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+    /// Write end of the pipe through which the signal handler wakes up the forwarding thread.
+    static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handle_signal(signal: libc::c_int) {
+        let byte = signal as u8;
+        // SAFETY: `write` is async-signal-safe and `byte` outlives the call
+        unsafe {
+            libc::write(
+                SIGNAL_PIPE.load(Ordering::SeqCst),
+                ptr::addr_of!(byte).cast(),
+                1,
+            )
+        };
+    }
+
+    pub fn forward_signals(grace_period: Duration) {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two ends of the pipe
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return;
+        }
+        for fd in fds {
+            // SAFETY: `fd` was just opened, the children must not inherit it
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+        // SAFETY: the read end of the pipe is owned by nothing else
+        let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+        thread::spawn(move || {
+            let mut byte = [0];
+            while let Ok(1) = pipe.read(&mut byte) {
+                super::forward(i32::from(byte[0]), grace_period);
+            }
+        });
+        for signal in FORWARDED_SIGNALS {
+            // SAFETY: a zeroed `sigaction` is valid, and the handler only does async-signal-safe work
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = handle_signal as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signal, &action, ptr::null_mut());
+            }
+        }
+    }
+
+    /// Terminate `pn` with `signal` as if it had no handler for it.
+    pub fn die_of(signal: i32) {
+        // SAFETY: restoring the default disposition and raising a signal have no preconditions
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    pub fn kill_group(group: u32, signal: i32) {
+        // SAFETY: `kill` has no preconditions, a negative pid targets a process group
+        unsafe { libc::kill(-(group as libc::pid_t), signal) };
+    }
+
+    /// Whether stdin is a terminal whose foreground process group is the one of `pn`.
+    pub fn owns_terminal() -> bool {
+        // SAFETY: these functions have no preconditions
+        io::stdin().is_terminal()
+            && unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() }
+    }
+
+    /// Make `command` lead a new process group, which takes the terminal if `foreground`.
+    pub fn prepare(command: &mut Command, foreground: bool) {
+        command.process_group(0);
+        if foreground {
+            // SAFETY: the closure only calls async-signal-safe functions
+            unsafe {
+                command.pre_exec(|| {
+                    // take the terminal before `exec`, so that the child never reads from it
+                    // in the background
+                    give_terminal(libc::getpid());
+                    Ok(())
+                })
+            };
+        } else if io::stdin().is_terminal() {
+            command.stdin(Stdio::null());
+        }
+    }
+
+    /// Make `group` the foreground process group of the terminal, even from the background.
+    fn give_terminal(group: libc::pid_t) {
+        // SAFETY: the sets are initialized by `sigemptyset` before use, and the mask is restored
+        unsafe {
+            let mut blocked: libc::sigset_t = mem::zeroed();
+            let mut previous: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut blocked);
+            libc::sigaddset(&mut blocked, libc::SIGTTOU);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &blocked, &mut previous);
+            libc::tcsetpgrp(libc::STDIN_FILENO, group);
+            libc::pthread_sigmask(libc::SIG_SETMASK, &previous, ptr::null_mut());
+        }
+    }
+
+    /// Give the terminal back to `pn` if `group` still has it.
+    fn take_terminal_back(group: libc::pid_t) {
+        // SAFETY: these functions have no preconditions
+        if unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) } == group {
+            give_terminal(unsafe { libc::getpgrp() });
+        }
+    }
+
+    /// Wait for a child that owns the terminal.
+    ///
+    /// When the child is stopped, by `Ctrl-Z` for instance, `pn` stops too so that the shell sees
+    /// the job as stopped, and resumes the child when the job is continued.
+    pub fn wait_foreground(child: &Child) -> io::Result<ExitStatus> {
+        let pid = child.id() as libc::pid_t;
+        loop {
+            let mut status = 0;
+            // SAFETY: `status` outlives the call
+            if unsafe { libc::waitpid(pid, &mut status, libc::WUNTRACED) } == -1 {
+                let error = io::Error::last_os_error();
+                if error.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                take_terminal_back(pid);
+                return Err(error);
+            }
+            take_terminal_back(pid);
+            if !libc::WIFSTOPPED(status) {
+                return Ok(ExitStatus::from_raw(status));
+            }
+            // SAFETY: these functions have no preconditions
+            unsafe { libc::raise(libc::SIGTSTP) };
+            if owns_terminal() {
+                give_terminal(pid);
+            }
+            kill_group(pid as u32, libc::SIGCONT);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::{
+        io,
+        process::{Child, Command, ExitStatus},
+        time::Duration,
+    };
+
+    pub const SIGKILL: i32 = 9;
+    pub const SIGTERM: i32 = 15;
+
+    pub fn forward_signals(_: Duration) {}
+
+    pub fn die_of(_: i32) {}
+
+    pub fn kill_group(_: u32, _: i32) {}
+
+    pub fn owns_terminal() -> bool {
+        false
+    }
+
+    pub fn prepare(_: &mut Command, _: bool) {}
+
+    pub fn wait_foreground(child: &Child) -> io::Result<ExitStatus> {
+        unreachable!("children never own the terminal on this platform: {child:?}")
+    }
+}
@@ -0,0 +1,384 @@
+//! Commands run in the projects of a workspace selected by `--recursive` or `--filter`.
+
+use crate::{
+    cli::{ExecArgs, RunArgs},
+    exec, failure_line, project_display_name, project_label, warn, workspace_tasks, ProjectScripts,
+    ScriptRunner,
+};
+use pipe_trait::Pipe;
+use pn::{
+    config::Config,
+    error::{MainError, PnError},
+    filter::{filter_projects, git_changed_files, PackageSelector},
+    passed_through, process_group,
+    scheduler::{concurrency_limit, run_tasks},
+    script_env::ScriptEnv,
+    script_selector::ScriptSelector,
+    shell_quoted::ShellQuoted,
+    task_graph::{task_config, TaskGraph},
+    utils::{pass_to_pnpm, pass_to_sub, relative_path},
+    workspace::{self, WorkspaceProject},
+    workspace_graph::WorkspaceGraph,
+};
+use std::{path::PathBuf, sync::Mutex};
+
+/// Work done in each selected project, which receives the label of the project when it runs
+/// concurrently with others.
+type ProjectTask<'a> = dyn Fn(&WorkspaceProject, Option<&str>) -> Result<(), MainError> + Sync + 'a;
+
+/// Runs commands in the projects selected by `--recursive` or `--filter`, with the ordering options
+/// of the command line.
+pub struct RecursiveRunner<'a> {
+    pub runner: &'a ScriptRunner<'a>,
+    /// Selectors of `--filter`, every project is selected if empty.
+    pub filter: &'a [String],
+    pub parallel: bool,
+    pub no_sort: bool,
+    pub reverse: bool,
+}
+
+/// Projects of a workspace selected to run a command in.
+struct Selection {
+    workspace_dir: PathBuf,
+    graph: WorkspaceGraph,
+    /// Indices of the selected projects in `graph`.
+    selected: Vec<usize>,
+}
+
+impl RecursiveRunner<'_> {
+    /// Run the script `name` in the selected projects, like `pn --recursive run <name>`.
+    pub fn run(&self, name: &str, args: &RunArgs) -> Result<(), MainError> {
+        if args.watch {
+            return PnError::WatchMultiProject.pipe(MainError::Pn).pipe(Err);
+        }
+        let Some(selection) = self.select_projects()? else {
+            return Ok(());
+        };
+        let selector = ScriptSelector::parse(name)?;
+        self.run_recursive(
+            &selection,
+            &selector,
+            args.args(),
+            args.if_present,
+            !args.no_bail,
+        )
+    }
+
+    /// Run a command in the selected projects, like `pn --recursive exec <command>`.
+    pub fn exec(&self, args: &ExecArgs) -> Result<(), MainError> {
+        let Some(selection) = self.select_projects()? else {
+            return Ok(());
+        };
+        let workspace_dir = &selection.workspace_dir;
+        self.run_in_projects(&selection, true, &|project, label| {
+            let config = Config::load(&project.dir, Some(workspace_dir))?;
+            let env = ScriptEnv::new(
+                &project.manifest,
+                &project.dir,
+                Some(workspace_dir),
+                self.runner.init_cwd,
+            )?
+            .with_exec(&project.manifest.name);
+            let output = self.runner.output(label, &args.command[0]);
+            exec(args, &project.dir, &env, &output, &config)
+        })
+    }
+
+    /// Pass `args` to pnpm for the selected projects if it is a pnpm command, run it as a script
+    /// where there is one, or as a shell command in every selected project otherwise.
+    pub fn run_other(&self, args: &[String]) -> Result<(), MainError> {
+        let init_cwd = self.runner.init_cwd;
+        let Some((name, rest)) = args.split_first() else {
+            return pass_to_pnpm(&["--recursive".to_string()], init_cwd);
+        };
+        if self.filter.is_empty() && passed_through::PASSED_THROUGH_COMMANDS.contains(name) {
+            let args: Vec<_> = ["--recursive".to_string()]
+                .into_iter()
+                .chain(args.iter().cloned())
+                .collect();
+            return pass_to_pnpm(&args, init_cwd);
+        }
+        let Some(selection) = self.select_projects()? else {
+            return Ok(());
+        };
+        let Selection {
+            workspace_dir,
+            graph,
+            selected,
+        } = &selection;
+        if passed_through::PASSED_THROUGH_COMMANDS.contains(name) {
+            let filters = selected.iter().map(|&index| {
+                let project = &graph.projects()[index];
+                if project.manifest.name.is_empty() {
+                    format!(
+                        "--filter={{{}}}",
+                        relative_path(&project.dir, init_cwd).display(),
+                    )
+                } else {
+                    format!("--filter={}", project.manifest.name)
+                }
+            });
+            let args: Vec<_> = filters.chain(args.iter().cloned()).collect();
+            return pass_to_pnpm(&args, init_cwd);
+        }
+        let has_script = selected
+            .iter()
+            .any(|&index| graph.projects()[index].manifest.scripts.contains_key(name));
+        if has_script {
+            let selector = ScriptSelector::Name(name.clone());
+            return self.run_recursive(&selection, &selector, rest, false, true);
+        }
+        self.run_in_projects(&selection, true, &|project, label| {
+            let config = Config::load(&project.dir, Some(workspace_dir))?;
+            let env = ScriptEnv::new(
+                &project.manifest,
+                &project.dir,
+                Some(workspace_dir),
+                init_cwd,
+            )?;
+            let output = self.runner.output(label, name);
+            pass_to_sub(
+                ShellQuoted::from_args(args),
+                &project.dir,
+                &env,
+                &output,
+                &config,
+            )
+        })
+    }
+
+    /// Projects selected by the command line, or `None` after telling so if there are none.
+    fn select_projects(&self) -> Result<Option<Selection>, MainError> {
+        let init_cwd = self.runner.init_cwd;
+        let flag = if self.filter.is_empty() {
+            "--recursive"
+        } else {
+            "--filter"
+        };
+        let workspace_dir =
+            workspace::find_workspace_dir(init_cwd)?.ok_or(PnError::NotInWorkspace { flag })?;
+        let graph = workspace::list_workspace_projects(&workspace_dir)?.pipe(WorkspaceGraph::new);
+        let selected: Vec<_> = if self.filter.is_empty() {
+            let workspace_config = Config::load(&workspace_dir, Some(&workspace_dir))?;
+            graph
+                .projects()
+                .iter()
+                .enumerate()
+                .filter(|(_, project)| {
+                    project.dir != workspace_dir || workspace_config.include_workspace_root
+                })
+                .map(|(index, _)| index)
+                .collect()
+        } else {
+            let selectors = self
+                .filter
+                .iter()
+                .map(|selector| PackageSelector::parse(selector))
+                .collect::<Result<Vec<_>, _>>()?;
+            filter_projects(
+                &graph,
+                &workspace_dir,
+                init_cwd,
+                &selectors,
+                &mut |git_ref| git_changed_files(&workspace_dir, git_ref),
+            )?
+        };
+        if selected.is_empty() {
+            println!("No projects matched the filters");
+            return Ok(None);
+        }
+        Ok(Some(Selection {
+            workspace_dir,
+            graph,
+            selected,
+        }))
+    }
+
+    /// Order in which the `selected` projects of `graph` run.
+    fn sort_projects(&self, graph: &WorkspaceGraph, selected: &[usize]) -> Vec<usize> {
+        let mut order = if self.no_sort || self.parallel {
+            selected.to_vec()
+        } else {
+            let sorted = graph.sort(selected);
+            if !sorted.cycles.is_empty() {
+                let cycles = sorted
+                    .cycles
+                    .iter()
+                    .map(|cycle| {
+                        cycle
+                            .iter()
+                            .map(|&index| project_display_name(&graph.projects()[index]))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    })
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                warn(format_args!(
+                    "There are cyclic workspace dependencies: {cycles}"
+                ));
+            }
+            sorted.order
+        };
+        if self.reverse {
+            order.reverse();
+        }
+        order
+    }
+
+    /// Run `task` in every selected project, stopping at the first failure if `bail`, or reporting
+    /// all of them at the end otherwise.
+    fn run_in_projects(
+        &self,
+        selection: &Selection,
+        bail: bool,
+        task: &ProjectTask,
+    ) -> Result<(), MainError> {
+        let Selection {
+            workspace_dir,
+            graph,
+            selected,
+        } = selection;
+        let order = self.sort_projects(graph, selected);
+        let (waits_for, concurrency) = if self.parallel {
+            (vec![Vec::new(); order.len()], usize::MAX)
+        } else {
+            let waits_for = if self.no_sort {
+                vec![Vec::new(); order.len()]
+            } else {
+                graph.waits_for(&order, self.reverse)
+            };
+            (waits_for, self.concurrency(selection)?)
+        };
+        let is_concurrent = concurrency > 1 && order.len() > 1;
+        let failures = Mutex::new(Vec::new());
+        run_tasks(&waits_for, concurrency, |position| {
+            let project = &graph.projects()[order[position]];
+            let label = is_concurrent.then(|| project_label(workspace_dir, project));
+            match task(project, label.as_deref()) {
+                // an interruption stops the run even with `--no-bail`
+                Err(error) if !bail && process_group::received_signal().is_none() => {
+                    failures.lock().unwrap().push((position, error));
+                    Ok(())
+                }
+                result => result,
+            }
+        })?;
+        let mut failures = failures.into_inner().unwrap();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        failures.sort_by_key(|(position, _)| *position);
+        let summary = failures
+            .iter()
+            .map(|(position, error)| {
+                let project = &graph.projects()[order[*position]];
+                failure_line(&project_display_name(project), error)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        PnError::RecursiveRunFailures {
+            count: failures.len(),
+            summary,
+        }
+        .pipe(MainError::Pn)
+        .pipe(Err)
+    }
+
+    /// Run the scripts matched by `selector` in the selected projects that have them, as a task
+    /// graph if the script is a task, whose order cannot be changed by `--parallel`, `--no-sort` or
+    /// `--reverse`.
+    fn run_recursive(
+        &self,
+        selection: &Selection,
+        selector: &ScriptSelector,
+        args: &[String],
+        if_present: bool,
+        bail: bool,
+    ) -> Result<(), MainError> {
+        let Selection {
+            workspace_dir,
+            graph,
+            selected,
+        } = selection;
+        let has_script = |index: &usize| {
+            !selector
+                .select(&graph.projects()[*index].manifest.scripts)
+                .is_empty()
+        };
+        if !selected.iter().any(has_script) {
+            if if_present {
+                return Ok(());
+            }
+            return PnError::RecursiveRunNoScript {
+                name: selector.as_str().to_string(),
+            }
+            .pipe(MainError::Pn)
+            .pipe(Err);
+        }
+        if let ScriptSelector::Name(script) = selector {
+            let workspace_tasks = workspace_tasks(Some(workspace_dir))?;
+            let roots: Vec<_> = selected.iter().copied().filter(has_script).collect();
+            let is_task = roots.iter().any(|&root| {
+                task_config(&graph.projects()[root].manifest, &workspace_tasks, script).is_some()
+            });
+            if is_task {
+                let option = [
+                    (self.parallel, "--parallel"),
+                    (self.no_sort, "--no-sort"),
+                    (self.reverse, "--reverse"),
+                ]
+                .into_iter()
+                .find_map(|(is_set, option)| is_set.then_some(option));
+                if let Some(option) = option {
+                    return PnError::TaskGraphOption {
+                        option,
+                        script: script.clone(),
+                    }
+                    .pipe(MainError::Pn)
+                    .pipe(Err);
+                }
+                let tasks = TaskGraph::build(graph, &workspace_tasks, &roots, script)?;
+                return self.runner.run_task_graph(
+                    graph,
+                    &tasks,
+                    Some(workspace_dir),
+                    args,
+                    self.concurrency(selection)?,
+                    bail,
+                );
+            }
+        }
+        self.run_in_projects(selection, bail, &|project, label| {
+            let scripts = selector.select(&project.manifest.scripts);
+            if scripts.is_empty() {
+                return Ok(());
+            }
+            let config = Config::load(&project.dir, Some(workspace_dir))?;
+            let project_scripts = ProjectScripts {
+                manifest: &project.manifest,
+                config: &config,
+                dir: &project.dir,
+                workspace_dir: Some(workspace_dir),
+                log_dir: None,
+            };
+            self.runner.run_selected(
+                &project_scripts,
+                &scripts,
+                args,
+                label,
+                &project_label(workspace_dir, project),
+            )
+        })
+    }
+
+    /// Number of projects that run at the same time, from `--workspace-concurrency` or the
+    /// settings of the workspace.
+    fn concurrency(&self, selection: &Selection) -> Result<usize, MainError> {
+        let workspace_dir = &selection.workspace_dir;
+        self.runner
+            .workspace_concurrency
+            .or(Config::load(workspace_dir, Some(workspace_dir))?.workspace_concurrency)
+            .pipe(concurrency_limit)
+            .pipe(Ok)
+    }
+}
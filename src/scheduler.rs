@@ -0,0 +1,172 @@
+//! Run tasks concurrently while respecting the dependencies between them.
+
+use crate::error::MainError;
+use std::{num::NonZeroUsize, sync::mpsc, thread};
+
+/// Number of tasks to run at the same time according to a `workspace-concurrency` setting.
+///
+/// A positive value is used as is, zero or a negative value `-n` means "CPUs minus `n`"
+/// (at least 1), and no value means the number of CPUs.
+pub fn concurrency_limit(setting: Option<isize>) -> usize {
+    let cpus = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    match setting {
+        None => cpus,
+        Some(value) if value > 0 => value.unsigned_abs(),
+        Some(value) => cpus.saturating_sub(value.unsigned_abs()).max(1),
+    }
+}
+
+/// Run `task` for every index of `waits_for`, with at most `concurrency` tasks at a time.
+///
+/// A task only starts after every task listed in its `waits_for` entry finished. Ready tasks start
+/// in index order, so a concurrency of 1 runs the tasks one after another in that order.
+///
+/// After a task fails, no new task is started. The tasks that are already running are waited for,
+/// then the first error is returned.
+pub fn run_tasks<Task>(
+    waits_for: &[Vec<usize>],
+    concurrency: usize,
+    task: Task,
+) -> Result<(), MainError>
+where
+    Task: Fn(usize) -> Result<(), MainError> + Sync,
+{
+    let concurrency = concurrency.max(1);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        let mut pending: Vec<usize> = (0..waits_for.len()).collect();
+        let mut finished = vec![false; waits_for.len()];
+        let mut running = 0;
+        let mut error = None;
+        loop {
+            if error.is_none() {
+                let mut position = 0;
+                while running < concurrency && position < pending.len() {
+                    let index = pending[position];
+                    if !waits_for[index].iter().all(|&other| finished[other]) {
+                        position += 1;
+                        continue;
+                    }
+                    pending.remove(position);
+                    running += 1;
+                    let sender = sender.clone();
+                    let task = &task;
+                    scope.spawn(move || {
+                        let result = task(index);
+                        sender
+                            .send((index, result))
+                            .expect("the scheduler outlives its tasks");
+                    });
+                }
+            }
+            if running == 0 {
+                break;
+            }
+            let (index, result) = receiver.recv().expect("a task is running");
+            running -= 1;
+            finished[index] = true;
+            if let Err(task_error) = result {
+                error.get_or_insert(task_error);
+            }
+        }
+        assert!(
+            pending.is_empty() || error.is_some(),
+            "tasks {pending:?} wait for tasks that never run",
+        );
+        error.map_or(Ok(()), Err)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::PnError;
+    use pretty_assertions::assert_eq;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    #[test]
+    fn test_concurrency_limit() {
+        let cpus = thread::available_parallelism().unwrap().get();
+        assert_eq!(concurrency_limit(None), cpus);
+        assert_eq!(concurrency_limit(Some(3)), 3);
+        assert_eq!(concurrency_limit(Some(0)), cpus);
+        assert_eq!(concurrency_limit(Some(-1)), cpus.saturating_sub(1).max(1));
+        assert_eq!(concurrency_limit(Some(-1000)), 1);
+    }
+
+    #[test]
+    fn test_run_tasks_respects_concurrency() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let waits_for = vec![Vec::new(); 8];
+        run_tasks(&waits_for, 3, |_| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(max_running.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_run_tasks_respects_dependencies() {
+        let log = Mutex::new(Vec::new());
+        // 0 and 1 are independent, 2 waits for both, 3 waits for 2
+        let waits_for = vec![vec![], vec![], vec![0, 1], vec![2]];
+        run_tasks(&waits_for, 4, |index| {
+            log.lock().unwrap().push(format!("start {index}"));
+            thread::sleep(Duration::from_millis(10 * (2 - index.min(2)) as u64));
+            log.lock().unwrap().push(format!("end {index}"));
+            Ok(())
+        })
+        .unwrap();
+        let log = log.into_inner().unwrap();
+        dbg!(&log);
+        let position = |entry: &str| log.iter().position(|x| x == entry).unwrap();
+        assert!(position("start 2") > position("end 0"));
+        assert!(position("start 2") > position("end 1"));
+        assert!(position("start 3") > position("end 2"));
+    }
+
+    #[test]
+    fn test_run_tasks_sequential_order() {
+        let log = Mutex::new(Vec::new());
+        let waits_for = vec![vec![], vec![], vec![0], vec![]];
+        run_tasks(&waits_for, 1, |index| {
+            log.lock().unwrap().push(index);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(log.into_inner().unwrap(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_run_tasks_stops_after_failure() {
+        let started = Mutex::new(Vec::new());
+        let waits_for = vec![vec![], vec![0], vec![]];
+        let error = run_tasks(&waits_for, 1, |index| {
+            started.lock().unwrap().push(index);
+            match index {
+                0 => Err(MainError::Pn(PnError::MissingScript {
+                    name: "build".to_string(),
+                })),
+                _ => Ok(()),
+            }
+        })
+        .unwrap_err();
+        dbg!(&error);
+        assert!(matches!(
+            error,
+            MainError::Pn(PnError::MissingScript { .. })
+        ));
+        assert_eq!(started.into_inner().unwrap(), [0]);
+    }
+}
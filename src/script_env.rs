@@ -0,0 +1,196 @@
+use crate::{
+    error::MainError,
+    utils::{create_path_env, find_executable, pnpm_package_dir},
+    NodeManifest,
+};
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Environment variables that pnpm exposes to the processes it spawns for a package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptEnv {
+    vars: Vec<(&'static str, OsString)>,
+}
+
+impl ScriptEnv {
+    /// Variables describing the package at `package_dir` and the directory `pn` was invoked from,
+    /// and the `PATH` to the executables of the package and of the workspace at `workspace_dir`.
+    pub fn new(
+        manifest: &NodeManifest,
+        package_dir: &Path,
+        workspace_dir: Option<&Path>,
+        init_cwd: &Path,
+    ) -> Result<Self, MainError> {
+        let init_cwd = env::var_os("INIT_CWD").unwrap_or_else(|| init_cwd.into());
+        let package_manager = PackageManager::get();
+        let mut vars = vec![
+            ("npm_package_name", manifest.name.clone().into()),
+            ("npm_package_version", manifest.version.clone().into()),
+            ("npm_package_json", package_dir.join("package.json").into()),
+            (
+                "npm_config_user_agent",
+                package_manager.user_agent.clone().into(),
+            ),
+            ("npm_execpath", package_manager.execpath.clone().into()),
+            ("INIT_CWD", init_cwd),
+            ("PNPM_SCRIPT_SRC_DIR", package_dir.into()),
+            ("PATH", create_path_env(package_dir, workspace_dir)?),
+        ];
+        if let Some(node) = &package_manager.node {
+            vars.push(("npm_node_execpath", node.into()));
+        }
+        Ok(ScriptEnv { vars })
+    }
+
+    /// Add the variables describing the lifecycle script `event` whose command is `script`.
+    pub fn with_lifecycle(mut self, event: &str, script: &str) -> Self {
+        self.vars.push(("npm_command", "run-script".into()));
+        self.vars.push(("npm_lifecycle_event", event.into()));
+        self.vars.push(("npm_lifecycle_script", script.into()));
+        self
+    }
+
+    /// Add the variables that `pnpm exec` sets for the package `package_name`.
+    pub fn with_exec(mut self, package_name: &str) -> Self {
+        self.vars.push(("npm_command", "exec".into()));
+        self.vars.push(("PNPM_PACKAGE_NAME", package_name.into()));
+        self
+    }
+
+    /// Value of the variable `key`, if set.
+    pub fn get(&self, key: &str) -> Option<&OsStr> {
+        self.vars
+            .iter()
+            .rev()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.as_os_str())
+    }
+
+    /// Iterate over the variables as key-value pairs.
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &OsStr)> {
+        self.vars
+            .iter()
+            .map(|(key, value)| (*key, value.as_os_str()))
+    }
+}
+
+/// The `pnpm` and `node` found in the `PATH` of `pn`, which the scripts see as their package manager.
+#[derive(Debug)]
+struct PackageManager {
+    /// Value of `npm_config_user_agent`, in the same shape as the one set by pnpm.
+    user_agent: String,
+    /// Entry point of `pnpm`, or the `pn` executable if `pnpm` is not found.
+    execpath: PathBuf,
+    /// The `node` executable, if found.
+    node: Option<PathBuf>,
+}
+
+impl PackageManager {
+    /// Look up `pnpm` and `node` once, for all the scripts that `pn` runs.
+    fn get() -> &'static Self {
+        static PACKAGE_MANAGER: OnceLock<PackageManager> = OnceLock::new();
+        PACKAGE_MANAGER.get_or_init(|| {
+            let path_env = env::var_os("PATH").unwrap_or_default();
+            let paths: Vec<_> = env::split_paths(&path_env).collect();
+            let pnpm_dir = pnpm_package_dir(&paths);
+            let pnpm_version = pnpm_dir
+                .as_deref()
+                .and_then(|dir| fs::read_to_string(dir.join("package.json")).ok())
+                .and_then(|manifest| serde_json::from_str::<NodeManifest>(&manifest).ok())
+                .map(|manifest| manifest.version)
+                .filter(|version| !version.is_empty());
+            let execpath = pnpm_dir
+                .map(|dir| dir.join("bin").join("pnpm.cjs"))
+                .filter(|execpath| execpath.is_file())
+                .or_else(|| env::current_exe().ok())
+                .unwrap_or_default();
+            let node = env::current_dir()
+                .ok()
+                .and_then(|cwd| find_executable("node", &path_env, &cwd));
+            PackageManager {
+                user_agent: user_agent(pnpm_version.as_deref()),
+                execpath,
+                node,
+            }
+        })
+    }
+}
+
+/// Value of `npm_config_user_agent` in the shape pnpm sets it, e.g. `pnpm/9.1.0 npm/? node/? linux x64`,
+/// with the platform and architecture named the way Node.js names them.
+///
+/// The version of `node` is left out: finding it means spawning `node --version`, which would add
+/// to every `pn` invocation the start of a `node` process (see the README).
+fn user_agent(pnpm_version: Option<&str>) -> String {
+    let platform = match env::consts::OS {
+        "macos" => "darwin",
+        "windows" => "win32",
+        os => os,
+    };
+    let arch = match env::consts::ARCH {
+        "x86_64" => "x64",
+        "x86" => "ia32",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64",
+        arch => arch,
+    };
+    format!(
+        "pnpm/{pnpm} npm/? node/? {platform} {arch}",
+        pnpm = pnpm_version.unwrap_or("?"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_script_env() {
+        let manifest = NodeManifest {
+            name: "foo".to_string(),
+            version: "1.2.3".to_string(),
+            ..Default::default()
+        };
+        let package_dir = Path::new("/workspace/packages/foo");
+        let received: HashMap<_, _> = ScriptEnv::new(&manifest, package_dir, None, Path::new("/"))
+            .unwrap()
+            .with_lifecycle("build", "tsc -p .")
+            .vars()
+            .map(|(key, value)| (key.to_string(), value.to_os_string()))
+            .collect();
+        dbg!(&received);
+        assert_eq!(received["npm_package_name"], "foo");
+        assert_eq!(received["npm_package_version"], "1.2.3");
+        assert_eq!(
+            received["npm_package_json"],
+            package_dir.join("package.json").into_os_string(),
+        );
+        assert_eq!(received["PNPM_SCRIPT_SRC_DIR"], package_dir.as_os_str());
+        assert_eq!(received["npm_lifecycle_event"], "build");
+        assert_eq!(received["npm_lifecycle_script"], "tsc -p .");
+        assert_eq!(received["npm_command"], "run-script");
+        assert!(received["npm_config_user_agent"]
+            .to_string_lossy()
+            .starts_with("pnpm/"));
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let received = user_agent(Some("9.1.0"));
+        dbg!(&received);
+        assert!(received.starts_with("pnpm/9.1.0 npm/? node/? "));
+        assert!(!received.contains("x86_64"));
+        assert!(!received.contains("macos"));
+        assert_eq!(
+            user_agent(None).split(' ').take(3).collect::<Vec<_>>(),
+            ["pnpm/?", "npm/?", "node/?",]
+        );
+    }
+}
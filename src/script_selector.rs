@@ -0,0 +1,209 @@
+use crate::{error::PnError, glob::Glob};
+use indexmap::IndexMap;
+use regex::Regex;
+
+/// Scripts selected by the script name given to `pn run`.
+#[derive(Debug, Clone)]
+pub enum ScriptSelector {
+    /// A plain script name.
+    Name(String),
+    /// `/regex/`, which selects every script whose name matches.
+    Regex { pattern: String, regex: Regex },
+}
+
+impl ScriptSelector {
+    /// Parse a script name, treating names wrapped in slashes as regular expressions like pnpm does.
+    pub fn parse(name: &str) -> Result<Self, PnError> {
+        let Some(source) = name
+            .strip_prefix('/')
+            .and_then(|rest| rest.strip_suffix('/'))
+            .filter(|source| !source.is_empty())
+        else {
+            return Ok(ScriptSelector::Name(name.to_string()));
+        };
+        let regex = Regex::new(source).map_err(|error| PnError::InvalidScriptRegex {
+            pattern: name.to_string(),
+            message: error.to_string(),
+        })?;
+        Ok(ScriptSelector::Regex {
+            pattern: name.to_string(),
+            regex,
+        })
+    }
+
+    /// The script name or the pattern, as written by the user.
+    pub fn as_str(&self) -> &str {
+        match self {
+            ScriptSelector::Name(name) => name,
+            ScriptSelector::Regex { pattern, .. } => pattern,
+        }
+    }
+
+    /// Names and commands of the selected scripts, in the order of `scripts`.
+    pub fn select<'a>(&self, scripts: &'a IndexMap<String, String>) -> Vec<(&'a str, &'a str)> {
+        match self {
+            ScriptSelector::Name(name) => scripts
+                .get_key_value(name)
+                .map(|(name, command)| (name.as_str(), command.as_str()))
+                .into_iter()
+                .collect(),
+            ScriptSelector::Regex { regex, .. } => scripts
+                .iter()
+                .filter(|(name, _)| regex.is_match(name))
+                .map(|(name, command)| (name.as_str(), command.as_str()))
+                .collect(),
+        }
+    }
+
+    /// Error to report when no script is selected.
+    pub fn missing_error(&self) -> PnError {
+        match self {
+            ScriptSelector::Name(name) => PnError::MissingScript { name: name.clone() },
+            ScriptSelector::Regex { pattern, .. } => PnError::NoScriptMatch {
+                pattern: pattern.clone(),
+            },
+        }
+    }
+}
+
+/// Selectors are equal when they were written the same, since the regex follows from its pattern.
+impl PartialEq for ScriptSelector {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ScriptSelector::Name(left), ScriptSelector::Name(right)) => left == right,
+            (
+                ScriptSelector::Regex { pattern: left, .. },
+                ScriptSelector::Regex { pattern: right, .. },
+            ) => left == right,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ScriptSelector {}
+
+/// Names and commands of the scripts selected by the script names or globs given to `pn run-s` and
+/// `pn run-p`, in the order of `patterns` then of `scripts`, each script at most once.
+///
+/// Like `npm-run-all`, globs treat `:` as a separator, so `watch:*` selects `watch:css` but not
+/// `watch:css:min`, which `watch:**` selects.
+pub fn select_globs<'a>(
+    patterns: &[String],
+    scripts: &'a IndexMap<String, String>,
+) -> Result<Vec<(&'a str, &'a str)>, PnError> {
+    let mut selected: Vec<(&str, &str)> = Vec::new();
+    for pattern in patterns {
+        let matches: Vec<_> = if let Some((name, command)) = scripts.get_key_value(pattern) {
+            vec![(name.as_str(), command.as_str())]
+        } else {
+            let glob = Glob::new(&pattern.replace(':', "/"))?;
+            scripts
+                .iter()
+                .filter(|(name, _)| glob.is_match(&name.replace(':', "/")))
+                .map(|(name, command)| (name.as_str(), command.as_str()))
+                .collect()
+        };
+        if matches.is_empty() {
+            return Err(if pattern.contains(['*', '?', '[', '{']) {
+                PnError::NoScriptMatch {
+                    pattern: pattern.clone(),
+                }
+            } else {
+                PnError::MissingScript {
+                    name: pattern.clone(),
+                }
+            });
+        }
+        for script in matches {
+            if !selected.contains(&script) {
+                selected.push(script);
+            }
+        }
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_select() {
+        let scripts: IndexMap<String, String> = [
+            ("build", "tsc"),
+            ("build:css", "sass"),
+            ("build:js", "esbuild"),
+            ("prebuild:js", "rm -rf dist"),
+        ]
+        .into_iter()
+        .map(|(name, command)| (name.to_string(), command.to_string()))
+        .collect();
+
+        let received = ScriptSelector::parse("build").unwrap().select(&scripts);
+        assert_eq!(received, [("build", "tsc")]);
+
+        let received = ScriptSelector::parse("/^build:.*/")
+            .unwrap()
+            .select(&scripts);
+        assert_eq!(received, [("build:css", "sass"), ("build:js", "esbuild")]);
+
+        let received = ScriptSelector::parse("/^test/").unwrap().select(&scripts);
+        assert_eq!(received, []);
+
+        let received = ScriptSelector::parse("/").unwrap();
+        assert_eq!(received, ScriptSelector::Name("/".to_string()));
+
+        let error = ScriptSelector::parse("/build(/").unwrap_err();
+        dbg!(&error);
+        assert!(matches!(error, PnError::InvalidScriptRegex { .. }));
+    }
+
+    #[test]
+    fn test_select_globs() {
+        let scripts: IndexMap<String, String> = [
+            ("lint", "eslint ."),
+            ("watch:css", "sass --watch"),
+            ("watch:js", "esbuild --watch"),
+            ("watch:js:min", "esbuild --watch --minify"),
+            ("test", "vitest"),
+        ]
+        .into_iter()
+        .map(|(name, command)| (name.to_string(), command.to_string()))
+        .collect();
+        let select = |patterns: &[&str]| {
+            let patterns: Vec<_> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+            select_globs(&patterns, &scripts)
+        };
+
+        let received = select(&["test", "lint"]).unwrap();
+        assert_eq!(received, [("test", "vitest"), ("lint", "eslint .")]);
+
+        let received = select(&["watch:*"]).unwrap();
+        assert_eq!(
+            received,
+            [
+                ("watch:css", "sass --watch"),
+                ("watch:js", "esbuild --watch")
+            ],
+        );
+
+        let received = select(&["watch:js", "watch:**"]).unwrap();
+        assert_eq!(
+            received,
+            [
+                ("watch:js", "esbuild --watch"),
+                ("watch:css", "sass --watch"),
+                ("watch:js:min", "esbuild --watch --minify"),
+            ],
+        );
+
+        let error = select(&["lint", "build"]).unwrap_err();
+        dbg!(&error);
+        assert!(matches!(error, PnError::MissingScript { name } if name == "build"));
+
+        let error = select(&["build:*"]).unwrap_err();
+        dbg!(&error);
+        assert!(matches!(error, PnError::NoScriptMatch { pattern } if pattern == "build:*"));
+    }
+}
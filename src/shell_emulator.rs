@@ -0,0 +1,1146 @@
+//! A small POSIX-like shell that runs scripts the same way whether or not a POSIX shell is
+//! installed, used instead of `sh -c` when the `shell-emulator` setting is enabled.
+//!
+//! Supports `&&`, `||`, `;`, pipes, the redirections `<`, `>`, `>>` and `n>&m`, `VAR=value`
+//! assignments and command prefixes, `$VAR`, `${VAR}` and `$?` expansion, `~`, quoting and
+//! pathname globbing, plus the built-ins `cd`, `echo`, `exit`, `export`, `pwd`, `true`, `false`,
+//! `unset` and `:`. Compound commands such as `if` and `for`, `!`, subshells, command substitution
+//! and the special parameters `$@`, `$*` and `$#` are rejected as syntax errors.
+
+use crate::{
+    glob,
+    signal::{signal_exit_code, termination_signal},
+    utils::normalize_path,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Run `script` in the current directory and return its exit status.
+///
+/// Syntax errors are reported on stderr with the status 2, like POSIX shells do.
+pub fn run(script: &str) -> i32 {
+    let list = match Lexer::new(script).tokenize().and_then(parse) {
+        Ok(list) => list,
+        Err(message) => {
+            eprintln!("pn: {message}");
+            return 2;
+        }
+    };
+    Shell::from_env().run_list(&list)
+}
+
+/// A piece of a word, which remembers whether it was quoted because quoted text is neither split
+/// nor globbed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text {
+        text: String,
+        quoted: bool,
+    },
+    Var {
+        name: String,
+        quoted: bool,
+    },
+    /// A leading unquoted `~`.
+    Tilde,
+}
+
+type Word = Vec<Part>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(Word),
+    And,
+    Or,
+    Semi,
+    Pipe,
+    Redirect { fd: u32, kind: RedirectKind },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RedirectKind {
+    /// `<`
+    Input,
+    /// `>`
+    Output,
+    /// `>>`
+    Append,
+    /// `>&` or `<&`
+    Duplicate,
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    index: usize,
+    tokens: Vec<Token>,
+    word: Option<Word>,
+}
+
+impl Lexer {
+    fn new(script: &str) -> Self {
+        Lexer {
+            chars: script.chars().collect(),
+            index: 0,
+            tokens: Vec::new(),
+            word: None,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        let found = self.peek() == Some(expected);
+        if found {
+            self.index += 1;
+        }
+        found
+    }
+
+    fn finish_word(&mut self) {
+        if let Some(word) = self.word.take() {
+            self.tokens.push(Token::Word(word));
+        }
+    }
+
+    fn push_token(&mut self, token: Token) {
+        self.finish_word();
+        self.tokens.push(token);
+    }
+
+    fn push_part(&mut self, part: Part) {
+        let word = self.word.get_or_insert_with(Vec::new);
+        if let (
+            Some(Part::Text { text, quoted }),
+            Part::Text {
+                text: new,
+                quoted: new_quoted,
+            },
+        ) = (word.last_mut(), &part)
+        {
+            if quoted == new_quoted {
+                text.push_str(new);
+                return;
+            }
+        }
+        word.push(part);
+    }
+
+    fn push_char(&mut self, char: char, quoted: bool) {
+        self.push_part(Part::Text {
+            text: char.to_string(),
+            quoted,
+        });
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Token>, String> {
+        while let Some(char) = self.peek() {
+            self.index += 1;
+            match char {
+                ' ' | '\t' => self.finish_word(),
+                '\n' => self.push_token(Token::Semi),
+                '#' if self.word.is_none() => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.index += 1;
+                    }
+                }
+                '\'' => {
+                    self.word.get_or_insert_with(Vec::new);
+                    let length = self.chars[self.index..]
+                        .iter()
+                        .position(|&char| char == '\'')
+                        .ok_or("unterminated single quote")?;
+                    let text = self.chars[self.index..self.index + length].iter().collect();
+                    self.index += length + 1;
+                    self.push_part(Part::Text { text, quoted: true });
+                }
+                '"' => self.double_quoted()?,
+                '\\' => match self.peek() {
+                    Some('\n') => self.index += 1,
+                    Some(char) => {
+                        self.index += 1;
+                        self.push_char(char, true);
+                    }
+                    None => self.push_char('\\', false),
+                },
+                '$' => self.dollar(false)?,
+                '`' => return Err("command substitution is not supported".to_string()),
+                '~' if self.word.is_none()
+                    && matches!(
+                        self.peek(),
+                        None | Some('/' | ' ' | '\t' | '\n' | ';' | '|' | '&' | '<' | '>'),
+                    ) =>
+                {
+                    self.push_part(Part::Tilde);
+                }
+                '&' if self.eat('&') => self.push_token(Token::And),
+                '&' => return Err("background jobs (`&`) are not supported".to_string()),
+                '|' if self.eat('|') => self.push_token(Token::Or),
+                '|' => self.push_token(Token::Pipe),
+                ';' => self.push_token(Token::Semi),
+                '>' | '<' => {
+                    // an unquoted number right before the operator is the file descriptor
+                    let fd = match self.word.as_deref() {
+                        Some(
+                            [Part::Text {
+                                text,
+                                quoted: false,
+                            }],
+                        ) => text.parse().ok(),
+                        _ => None,
+                    };
+                    if fd.is_some() {
+                        self.word = None;
+                    }
+                    let (default_fd, kind) = match char {
+                        '>' if self.eat('>') => (1, RedirectKind::Append),
+                        '>' if self.eat('&') => (1, RedirectKind::Duplicate),
+                        '>' => (1, RedirectKind::Output),
+                        _ if self.eat('&') => (0, RedirectKind::Duplicate),
+                        _ => (0, RedirectKind::Input),
+                    };
+                    self.push_token(Token::Redirect {
+                        fd: fd.unwrap_or(default_fd),
+                        kind,
+                    });
+                }
+                '(' | ')' => return Err("subshells are not supported".to_string()),
+                char => self.push_char(char, false),
+            }
+        }
+        self.finish_word();
+        Ok(self.tokens)
+    }
+
+    fn double_quoted(&mut self) -> Result<(), String> {
+        self.word.get_or_insert_with(Vec::new);
+        loop {
+            let char = self.peek().ok_or("unterminated double quote")?;
+            self.index += 1;
+            match char {
+                '"' => return Ok(()),
+                '\\' => match self.peek() {
+                    Some(char @ ('$' | '`' | '"' | '\\')) => {
+                        self.index += 1;
+                        self.push_char(char, true);
+                    }
+                    Some('\n') => self.index += 1,
+                    _ => self.push_char('\\', true),
+                },
+                '$' => self.dollar(true)?,
+                '`' => return Err("command substitution is not supported".to_string()),
+                char => self.push_char(char, true),
+            }
+        }
+    }
+
+    /// Parse what follows a `$`.
+    fn dollar(&mut self, quoted: bool) -> Result<(), String> {
+        let name = match self.peek() {
+            Some('{') => {
+                let length = self.chars[self.index..]
+                    .iter()
+                    .position(|&char| char == '}')
+                    .ok_or("unterminated ${")?;
+                let name: String = self.chars[self.index + 1..self.index + length]
+                    .iter()
+                    .collect();
+                if !is_name(&name) && name != "?" && !is_number(&name) {
+                    return Err(format!("unsupported parameter expansion: ${{{name}}}"));
+                }
+                self.index += length + 1;
+                name
+            }
+            Some('(') => return Err("command substitution is not supported".to_string()),
+            Some(char @ ('@' | '*' | '#' | '$' | '!' | '-')) => {
+                return Err(format!("the special parameter ${char} is not supported"));
+            }
+            Some(char @ ('?' | '0'..='9')) => {
+                self.index += 1;
+                char.to_string()
+            }
+            Some(char) if char == '_' || char.is_ascii_alphabetic() => {
+                let length = self.chars[self.index..]
+                    .iter()
+                    .position(|&char| char != '_' && !char.is_ascii_alphanumeric())
+                    .unwrap_or(self.chars.len() - self.index);
+                let name = self.chars[self.index..self.index + length].iter().collect();
+                self.index += length;
+                name
+            }
+            _ => {
+                self.push_char('$', quoted);
+                return Ok(());
+            }
+        };
+        self.push_part(Part::Var { name, quoted });
+        Ok(())
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|char| char == '_' || char.is_ascii_alphabetic())
+        && chars.all(|char| char == '_' || char.is_ascii_alphanumeric())
+}
+
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|char| char.is_ascii_digit())
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct SimpleCommand {
+    assignments: Vec<(String, Word)>,
+    words: Vec<Word>,
+    redirects: Vec<Redirect>,
+}
+
+impl SimpleCommand {
+    fn is_empty(&self) -> bool {
+        self.assignments.is_empty() && self.words.is_empty() && self.redirects.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Redirect {
+    fd: u32,
+    kind: RedirectKind,
+    target: Word,
+}
+
+type Pipeline = Vec<SimpleCommand>;
+
+/// How a pipeline is chained to the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Connector {
+    Always,
+    And,
+    Or,
+}
+
+type List = Vec<(Connector, Pipeline)>;
+
+fn parse(tokens: Vec<Token>) -> Result<List, String> {
+    let mut list = List::new();
+    let mut connector = Connector::Always;
+    let mut pipeline = Pipeline::new();
+    let mut command = SimpleCommand::default();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) => {
+                match reserved_word(&word) {
+                    Some("!") if command.is_empty() => {
+                        return Err("negated pipelines (`!`) are not supported".to_string());
+                    }
+                    Some(_) if command.is_empty() => {
+                        return Err("compound commands are not supported".to_string());
+                    }
+                    _ => {}
+                }
+                if command.words.is_empty() {
+                    if let Some(assignment) = split_assignment(&word) {
+                        command.assignments.push(assignment);
+                        continue;
+                    }
+                }
+                command.words.push(word);
+            }
+            Token::Redirect { fd, kind } => {
+                let Some(Token::Word(target)) = tokens.next() else {
+                    return Err("missing file name after redirection".to_string());
+                };
+                command.redirects.push(Redirect { fd, kind, target });
+            }
+            Token::Pipe => {
+                if command.is_empty() {
+                    return Err("syntax error near `|`".to_string());
+                }
+                pipeline.push(std::mem::take(&mut command));
+            }
+            Token::And | Token::Or | Token::Semi => {
+                if command.is_empty() {
+                    // empty lines, and line breaks after `&&` or `||`
+                    if token == Token::Semi && pipeline.is_empty() {
+                        continue;
+                    }
+                    return Err("syntax error near an operator".to_string());
+                }
+                pipeline.push(std::mem::take(&mut command));
+                list.push((connector, std::mem::take(&mut pipeline)));
+                connector = match token {
+                    Token::And => Connector::And,
+                    Token::Or => Connector::Or,
+                    _ => Connector::Always,
+                };
+            }
+        }
+    }
+    if !command.is_empty() {
+        pipeline.push(command);
+        list.push((connector, pipeline));
+    } else if !pipeline.is_empty() || connector != Connector::Always {
+        return Err("unexpected end of script".to_string());
+    }
+    Ok(list)
+}
+
+/// The reserved word that `word` is if it is one unquoted, which starts a compound command such
+/// as `if` or `for`, or negates a pipeline, when it comes first.
+fn reserved_word(word: &Word) -> Option<&str> {
+    match word.as_slice() {
+        [Part::Text {
+            text,
+            quoted: false,
+        }] if RESERVED_WORDS.contains(&text.as_str()) => Some(text),
+        _ => None,
+    }
+}
+
+const RESERVED_WORDS: &[&str] = &[
+    "if", "then", "elif", "else", "fi", "for", "while", "until", "do", "done", "case", "esac", "!",
+];
+
+/// Recognize `NAME=value`.
+fn split_assignment(word: &Word) -> Option<(String, Word)> {
+    let Some(Part::Text {
+        text,
+        quoted: false,
+    }) = word.first()
+    else {
+        return None;
+    };
+    let (name, value) = text.split_once('=')?;
+    if !is_name(name) {
+        return None;
+    }
+    let mut value_word = Vec::new();
+    if !value.is_empty() {
+        value_word.push(Part::Text {
+            text: value.to_string(),
+            quoted: false,
+        });
+    }
+    value_word.extend(word[1..].iter().cloned());
+    Some((name.to_string(), value_word))
+}
+
+/// Where a file descriptor of a command points to.
+#[derive(Debug)]
+enum Target {
+    /// A standard stream of the shell itself.
+    Parent(u32),
+    File(File),
+    /// The pipe from the previous command, or to the next one.
+    Pipe,
+    Null,
+}
+
+impl Target {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Target::Parent(fd) => Target::Parent(*fd),
+            Target::File(file) => Target::File(file.try_clone()?),
+            Target::Pipe => Target::Pipe,
+            Target::Null => Target::Null,
+        })
+    }
+
+    fn into_stdio(self, fd: u32) -> io::Result<Stdio> {
+        Ok(match self {
+            Target::Parent(parent) if parent == fd => Stdio::inherit(),
+            Target::Parent(parent) => parent_stdio(parent)?,
+            Target::File(file) => Stdio::from(file),
+            Target::Pipe => Stdio::piped(),
+            Target::Null => Stdio::null(),
+        })
+    }
+
+    /// Write the output of a built-in, collecting what goes to the next command in `pipe`.
+    fn write(&mut self, data: &[u8], pipe: &mut Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        let result = match self {
+            Target::Parent(2) => io::stderr().write_all(data),
+            Target::Parent(_) => io::stdout().write_all(data),
+            Target::File(file) => file.write_all(data),
+            Target::Pipe => {
+                pipe.extend_from_slice(data);
+                Ok(())
+            }
+            Target::Null => Ok(()),
+        };
+        result.ok();
+    }
+}
+
+/// Duplicate a standard stream of the shell for a child.
+#[cfg(unix)]
+fn parent_stdio(fd: u32) -> io::Result<Stdio> {
+    use std::os::fd::AsFd;
+    let owned = match fd {
+        0 => io::stdin().as_fd().try_clone_to_owned()?,
+        1 => io::stdout().as_fd().try_clone_to_owned()?,
+        _ => io::stderr().as_fd().try_clone_to_owned()?,
+    };
+    Ok(Stdio::from(owned))
+}
+
+/// Duplicate a standard stream of the shell for a child.
+#[cfg(windows)]
+fn parent_stdio(fd: u32) -> io::Result<Stdio> {
+    use std::os::windows::io::AsHandle;
+    let owned = match fd {
+        0 => io::stdin().as_handle().try_clone_to_owned()?,
+        1 => io::stdout().as_handle().try_clone_to_owned()?,
+        _ => io::stderr().as_handle().try_clone_to_owned()?,
+    };
+    Ok(Stdio::from(owned))
+}
+
+/// Output of a command of a pipeline, which becomes the input of the next one.
+enum Input {
+    /// No previous command: the standard input of the shell.
+    Parent,
+    /// Output of a built-in.
+    Bytes(Vec<u8>),
+    /// Output streams of a child.
+    Streams(Vec<Box<dyn Read + Send>>),
+}
+
+/// A command of a pipeline that was started.
+enum Stage {
+    Done(i32),
+    Running(Child),
+}
+
+#[derive(Debug, Clone)]
+struct Shell {
+    vars: HashMap<String, String>,
+    exported: HashSet<String>,
+    cwd: PathBuf,
+    status: i32,
+    /// Status given to the `exit` built-in.
+    exit: Option<i32>,
+}
+
+impl Shell {
+    fn from_env() -> Self {
+        let vars: HashMap<String, String> = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        let exported = vars.keys().cloned().collect();
+        Shell {
+            vars,
+            exported,
+            cwd: env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+            status: 0,
+            exit: None,
+        }
+    }
+
+    fn var(&self, name: &str) -> String {
+        match name {
+            "?" => self.status.to_string(),
+            "0" => "pn".to_string(),
+            _ => self.vars.get(name).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn run_list(&mut self, list: &List) -> i32 {
+        for (connector, pipeline) in list {
+            let should_run = match connector {
+                Connector::Always => true,
+                Connector::And => self.status == 0,
+                Connector::Or => self.status != 0,
+            };
+            if should_run {
+                self.status = self.run_pipeline(pipeline);
+            }
+            if let Some(status) = self.exit {
+                return status;
+            }
+        }
+        self.status
+    }
+
+    fn run_pipeline(&mut self, pipeline: &Pipeline) -> i32 {
+        if let [command] = pipeline.as_slice() {
+            return match self.start(command, Input::Parent, false) {
+                (Stage::Done(status), _) => status,
+                (Stage::Running(mut child), _) => wait(&mut child),
+            };
+        }
+        // like in POSIX shells, every command of a pipeline runs in a copy of the shell
+        let mut input = Input::Parent;
+        let mut stages = Vec::new();
+        for (index, command) in pipeline.iter().enumerate() {
+            let is_last = index + 1 == pipeline.len();
+            let (stage, output) = self.clone().start(command, input, !is_last);
+            input = output;
+            stages.push(stage);
+        }
+        let mut status = 0;
+        for stage in stages {
+            status = match stage {
+                Stage::Done(status) => status,
+                Stage::Running(mut child) => wait(&mut child),
+            };
+        }
+        status
+    }
+
+    /// Start `command` and return it along with its output for the next command of the pipeline.
+    fn start(&mut self, command: &SimpleCommand, input: Input, piped: bool) -> (Stage, Input) {
+        let words: Vec<String> = command
+            .words
+            .iter()
+            .flat_map(|word| self.expand_fields(word))
+            .collect();
+        let assignments: Vec<(String, String)> = command
+            .assignments
+            .iter()
+            .map(|(name, value)| (name.clone(), self.expand_string(value)))
+            .collect();
+        let has_input = !matches!(input, Input::Parent);
+        let mut targets = [
+            if has_input {
+                Target::Pipe
+            } else {
+                Target::Parent(0)
+            },
+            if piped {
+                Target::Pipe
+            } else {
+                Target::Parent(1)
+            },
+            Target::Parent(2),
+        ];
+        if let Err(message) = self.apply_redirects(&command.redirects, &mut targets) {
+            let mut pipe = Vec::new();
+            targets[2].write(format!("pn: {message}\n").as_bytes(), &mut pipe);
+            return (Stage::Done(1), Input::Bytes(pipe));
+        }
+        let Some((program, args)) = words.split_first() else {
+            for (name, value) in assignments {
+                self.vars.insert(name, value);
+            }
+            return (Stage::Done(0), Input::Bytes(Vec::new()));
+        };
+        if let Some(result) = self.run_builtin(program, args) {
+            let (status, stdout, stderr) = result;
+            let [_, mut out, mut err] = targets;
+            let mut pipe = Vec::new();
+            out.write(&stdout, &mut pipe);
+            err.write(&stderr, &mut pipe);
+            return (Stage::Done(status), Input::Bytes(pipe));
+        }
+        self.spawn(program, args, &assignments, input, targets)
+    }
+
+    fn apply_redirects(
+        &self,
+        redirects: &[Redirect],
+        targets: &mut [Target; 3],
+    ) -> Result<(), String> {
+        for redirect in redirects {
+            let fd = redirect.fd as usize;
+            if fd > 2 {
+                return Err(format!("unsupported file descriptor: {fd}"));
+            }
+            let target = self.expand_string(&redirect.target);
+            let path = self.cwd.join(&target);
+            let open_error = |error: io::Error| format!("{target}: {error}");
+            targets[fd] = match redirect.kind {
+                RedirectKind::Input => File::open(&path).map(Target::File).map_err(open_error)?,
+                RedirectKind::Output => {
+                    File::create(&path).map(Target::File).map_err(open_error)?
+                }
+                RedirectKind::Append => OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&path)
+                    .map(Target::File)
+                    .map_err(open_error)?,
+                RedirectKind::Duplicate if target == "-" => Target::Null,
+                RedirectKind::Duplicate => {
+                    let source: usize = target
+                        .parse()
+                        .ok()
+                        .filter(|source| *source <= 2)
+                        .ok_or_else(|| format!("unsupported file descriptor: {target}"))?;
+                    targets[source].try_clone().map_err(open_error)?
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Run `program` if it is a built-in, returning its status, stdout and stderr.
+    fn run_builtin(&mut self, program: &str, args: &[String]) -> Option<(i32, Vec<u8>, Vec<u8>)> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let status = match program {
+            ":" | "true" => 0,
+            "false" => 1,
+            "echo" => {
+                let (newline, args) = match args.split_first() {
+                    Some((flag, rest)) if flag == "-n" => (false, rest),
+                    _ => (true, args),
+                };
+                stdout.extend_from_slice(args.join(" ").as_bytes());
+                if newline {
+                    stdout.push(b'\n');
+                }
+                0
+            }
+            "pwd" => {
+                writeln!(stdout, "{}", self.cwd.display()).ok();
+                0
+            }
+            "cd" => {
+                let dir = match args.first() {
+                    Some(dir) => dir.clone(),
+                    None => self.var("HOME"),
+                };
+                let path = normalize_path(&self.cwd.join(&dir));
+                if path.is_dir() {
+                    self.vars
+                        .insert("PWD".to_string(), path.to_string_lossy().into_owned());
+                    self.cwd = path;
+                    0
+                } else {
+                    writeln!(stderr, "pn: cd: {dir}: No such directory").ok();
+                    1
+                }
+            }
+            "export" => {
+                for arg in args {
+                    let name = match arg.split_once('=') {
+                        Some((name, value)) => {
+                            self.vars.insert(name.to_string(), value.to_string());
+                            name
+                        }
+                        None => arg,
+                    };
+                    self.exported.insert(name.to_string());
+                }
+                0
+            }
+            "unset" => {
+                for name in args {
+                    self.vars.remove(name);
+                    self.exported.remove(name);
+                }
+                0
+            }
+            "exit" => {
+                let status = match args.first() {
+                    Some(status) => status.parse().unwrap_or(2),
+                    None => self.status,
+                };
+                self.exit = Some(status);
+                status
+            }
+            _ => return None,
+        };
+        Some((status, stdout, stderr))
+    }
+
+    fn spawn(
+        &self,
+        program: &str,
+        args: &[String],
+        assignments: &[(String, String)],
+        input: Input,
+        targets: [Target; 3],
+    ) -> (Stage, Input) {
+        let [stdin, stdout, stderr] = targets;
+        let feeds_stdin = matches!(stdin, Target::Pipe);
+        let pipes_stdout = matches!(stdout, Target::Pipe);
+        let pipes_stderr = matches!(stderr, Target::Pipe);
+        let exported = self
+            .exported
+            .iter()
+            .filter_map(|name| Some((name.as_str(), self.vars.get(name)?.as_str())));
+        let assignments = assignments
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+        let mut error_target = stderr.try_clone().unwrap_or(Target::Parent(2));
+        let spawn = || -> io::Result<Child> {
+            Command::new(program)
+                .args(args)
+                .current_dir(&self.cwd)
+                .env_clear()
+                .envs(exported.chain(assignments))
+                .stdin(stdin.into_stdio(0)?)
+                .stdout(stdout.into_stdio(1)?)
+                .stderr(stderr.into_stdio(2)?)
+                .spawn()
+        };
+        let mut child = match spawn() {
+            Ok(child) => child,
+            Err(error) => {
+                let (status, message) = match error.kind() {
+                    ErrorKind::NotFound => (127, format!("pn: {program}: command not found\n")),
+                    _ => (126, format!("pn: {program}: {error}\n")),
+                };
+                let mut pipe = Vec::new();
+                error_target.write(message.as_bytes(), &mut pipe);
+                return (Stage::Done(status), Input::Bytes(pipe));
+            }
+        };
+        if feeds_stdin {
+            if let Some(child_stdin) = child.stdin.take() {
+                feed(input, child_stdin);
+            }
+        }
+        let mut output: Vec<Box<dyn Read + Send>> = Vec::new();
+        if pipes_stdout {
+            output.extend(child.stdout.take().map(|stdout| Box::new(stdout) as _));
+        }
+        if pipes_stderr {
+            output.extend(child.stderr.take().map(|stderr| Box::new(stderr) as _));
+        }
+        (Stage::Running(child), Input::Streams(output))
+    }
+
+    /// Expand a word into fields, splitting unquoted variables and globbing unquoted patterns.
+    fn expand_fields(&self, word: &Word) -> Vec<String> {
+        let mut fields: Vec<Field> = Vec::new();
+        let mut current: Option<Field> = None;
+        for part in word {
+            match part {
+                Part::Text { text, quoted } => {
+                    current
+                        .get_or_insert_with(Field::default)
+                        .push(text, *quoted);
+                }
+                Part::Tilde => {
+                    current
+                        .get_or_insert_with(Field::default)
+                        .push(&self.var("HOME"), true);
+                }
+                Part::Var { name, quoted: true } => {
+                    current
+                        .get_or_insert_with(Field::default)
+                        .push(&self.var(name), true);
+                }
+                Part::Var {
+                    name,
+                    quoted: false,
+                } => {
+                    let value = self.var(name);
+                    let starts_with_space = value.starts_with(char::is_whitespace);
+                    let ends_with_space = value.ends_with(char::is_whitespace);
+                    for (index, piece) in value.split_whitespace().enumerate() {
+                        if index > 0 || starts_with_space {
+                            fields.extend(current.take());
+                        }
+                        current
+                            .get_or_insert_with(Field::default)
+                            .push(piece, false);
+                    }
+                    if ends_with_space {
+                        fields.extend(current.take());
+                    }
+                }
+            }
+        }
+        fields.extend(current);
+        fields
+            .into_iter()
+            .flat_map(|field| match field.has_glob {
+                true => match glob_paths(&self.cwd, &field.pattern) {
+                    paths if paths.is_empty() => vec![field.text],
+                    paths => paths,
+                },
+                false => vec![field.text],
+            })
+            .collect()
+    }
+
+    /// Expand a word into a single string, without splitting nor globbing.
+    fn expand_string(&self, word: &Word) -> String {
+        word.iter()
+            .map(|part| match part {
+                Part::Text { text, .. } => text.clone(),
+                Part::Var { name, .. } => self.var(name),
+                Part::Tilde => self.var("HOME"),
+            })
+            .collect()
+    }
+}
+
+/// A field being expanded, with its text and the glob pattern in which quoted text is escaped.
+#[derive(Debug, Default)]
+struct Field {
+    text: String,
+    pattern: String,
+    has_glob: bool,
+}
+
+impl Field {
+    fn push(&mut self, text: &str, quoted: bool) {
+        self.text.push_str(text);
+        for char in text.chars() {
+            let is_special = matches!(char, '*' | '?' | '[' | ']' | '\\');
+            if quoted && is_special {
+                self.pattern.push('\\');
+            }
+            self.pattern.push(char);
+        }
+        self.has_glob |= !quoted && text.contains(['*', '?', '[']);
+    }
+}
+
+/// Paths matching `pattern`, relative to `cwd` unless the pattern is absolute, sorted.
+fn glob_paths(cwd: &Path, pattern: &str) -> Vec<String> {
+    let is_absolute = pattern.starts_with('/');
+    let mut matches = vec![(
+        if is_absolute {
+            PathBuf::from("/")
+        } else {
+            cwd.to_path_buf()
+        },
+        String::from(if is_absolute { "/" } else { "" }),
+    )];
+    let segments: Vec<&str> = pattern
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    for (index, segment) in segments.iter().enumerate() {
+        let is_last = index + 1 == segments.len();
+        let join = |display: &str, name: &str| match display {
+            "" => name.to_string(),
+            "/" => format!("/{name}"),
+            display => format!("{display}/{name}"),
+        };
+        let mut next = Vec::new();
+        for (path, display) in matches {
+            if !has_wildcard(segment) {
+                let name = unescape(segment);
+                let path = path.join(&name);
+                if is_last || path.is_dir() {
+                    next.push((path, join(&display, &name)));
+                }
+                continue;
+            }
+            let Ok(entries) = fs::read_dir(&path) else {
+                continue;
+            };
+            let mut names: Vec<String> = entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| glob::is_shell_segment_match(segment, name))
+                .collect();
+            names.sort();
+            for name in names {
+                let path = path.join(&name);
+                if is_last || path.is_dir() {
+                    next.push((path, join(&display, &name)));
+                }
+            }
+        }
+        matches = next;
+    }
+    matches
+        .into_iter()
+        .filter(|(path, _)| path.symlink_metadata().is_ok())
+        .map(|(_, display)| display)
+        .collect()
+}
+
+fn has_wildcard(segment: &str) -> bool {
+    let mut chars = segment.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn unescape(segment: &str) -> String {
+    let mut result = String::new();
+    let mut chars = segment.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => result.extend(chars.next()),
+            char => result.push(char),
+        }
+    }
+    result
+}
+
+/// Copy the output of the previous command of a pipeline into `stdin` in the background.
+fn feed(input: Input, stdin: ChildStdin) {
+    let stdin = Arc::new(Mutex::new(stdin));
+    let sources: Vec<Box<dyn Read + Send>> = match input {
+        Input::Parent => Vec::new(),
+        Input::Bytes(bytes) => vec![Box::new(io::Cursor::new(bytes))],
+        Input::Streams(streams) => streams,
+    };
+    for mut source in sources {
+        let stdin = Arc::clone(&stdin);
+        thread::spawn(move || {
+            let mut buffer = [0; 8192];
+            while let Ok(length @ 1..) = source.read(&mut buffer) {
+                if stdin.lock().unwrap().write_all(&buffer[..length]).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+fn wait(child: &mut Child) -> i32 {
+    child.wait().map_or(126, status_code)
+}
+
+/// Exit status of a child as a shell reports it: 128 plus the signal number when killed.
+fn status_code(status: ExitStatus) -> i32 {
+    match termination_signal(status) {
+        Some(signal) => signal_exit_code(signal),
+        None => status.code().unwrap_or(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn text(text: &str, quoted: bool) -> Part {
+        Part::Text {
+            text: text.to_string(),
+            quoted,
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let received = Lexer::new(r#"FOO=1 echo "a $B" 'c'\ d 2>&1 >> out && x || y; z | w"#)
+            .tokenize()
+            .unwrap();
+        dbg!(&received);
+        assert_eq!(
+            received,
+            [
+                Token::Word(vec![text("FOO=1", false)]),
+                Token::Word(vec![text("echo", false)]),
+                Token::Word(vec![
+                    text("a ", true),
+                    Part::Var {
+                        name: "B".to_string(),
+                        quoted: true,
+                    },
+                ]),
+                Token::Word(vec![text("c ", true), text("d", false)]),
+                Token::Redirect {
+                    fd: 2,
+                    kind: RedirectKind::Duplicate,
+                },
+                Token::Word(vec![text("1", false)]),
+                Token::Redirect {
+                    fd: 1,
+                    kind: RedirectKind::Append,
+                },
+                Token::Word(vec![text("out", false)]),
+                Token::And,
+                Token::Word(vec![text("x", false)]),
+                Token::Or,
+                Token::Word(vec![text("y", false)]),
+                Token::Semi,
+                Token::Word(vec![text("z", false)]),
+                Token::Pipe,
+                Token::Word(vec![text("w", false)]),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let tokens = Lexer::new("A=1 B=2 cmd arg > file && next\n\nlast")
+            .tokenize()
+            .unwrap();
+        let received = parse(tokens).unwrap();
+        dbg!(&received);
+        assert_eq!(received.len(), 3);
+        let (connector, pipeline) = &received[0];
+        assert_eq!(*connector, Connector::Always);
+        assert_eq!(pipeline[0].assignments.len(), 2);
+        assert_eq!(pipeline[0].words.len(), 2);
+        assert_eq!(pipeline[0].redirects.len(), 1);
+        assert_eq!(received[1].0, Connector::And);
+        assert_eq!(received[2].0, Connector::Always);
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        for script in [
+            "echo 'a",
+            "echo \"a",
+            "a &&",
+            "| a",
+            "a && || b",
+            "(a)",
+            "a &",
+            "`a`",
+            "$(a)",
+            "a >",
+            "if true; then echo a; fi",
+            "for x in a b; do echo $x; done",
+            "a && while true; do :; done",
+            "! a",
+            "a && ! b",
+            "echo \"$@\"",
+            "echo $*",
+            "echo $#",
+        ] {
+            let result = Lexer::new(script).tokenize().and_then(parse);
+            eprintln!("script={script:?} result={result:?}");
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_expand_fields() {
+        let mut shell = Shell::from_env();
+        shell
+            .vars
+            .insert("SPACED".to_string(), " a  b ".to_string());
+        shell.vars.insert("EMPTY".to_string(), String::new());
+        let expand = |script: &str| -> Vec<String> {
+            let tokens = Lexer::new(script).tokenize().unwrap();
+            tokens
+                .into_iter()
+                .flat_map(|token| match token {
+                    Token::Word(word) => shell.expand_fields(&word),
+                    _ => panic!("unexpected token"),
+                })
+                .collect()
+        };
+        assert_eq!(expand("x$SPACED\"y\""), ["x", "a", "b", "y"]);
+        assert_eq!(expand("\"$SPACED\""), [" a  b "]);
+        assert_eq!(expand("$EMPTY"), Vec::<String>::new());
+        assert_eq!(expand("\"$EMPTY\" ''"), ["", ""]);
+        assert_eq!(expand("${EMPTY}x$?"), ["x0"]);
+        assert_eq!(expand("a$ b"), ["a$", "b"]);
+    }
+}
@@ -2,7 +2,7 @@ use derive_more::{Display, Into};
 use os_display::Quoted;
 use std::ffi::OsStr;
 
-#[derive(Debug, Display, Into)]
+#[derive(Debug, Display, Into, PartialEq, Eq)]
 pub struct ShellQuoted(String);
 
 impl AsRef<OsStr> for ShellQuoted {
@@ -22,7 +22,7 @@ impl ShellQuoted {
         if !self.0.is_empty() {
             self.0.push(' ');
         }
-        let quoted = Quoted::unix(arg.as_ref()); // because pn uses `sh -c` even on Windows
+        let quoted = Quoted::unix(arg.as_ref()); // both `sh -c` and the shell emulator parse POSIX quotes
         write!(self.0, "{quoted}").expect("string write doesn't panic");
     }
 
@@ -47,6 +47,106 @@ impl ShellQuoted {
     }
 }
 
+/// Commands that a shell runs differently from the executable of the same name, if there is one.
+const SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "[", "alias", "bg", "break", "case", "cd", "command", "continue", "do", "done",
+    "echo", "elif", "else", "esac", "eval", "exec", "exit", "export", "false", "fg", "fi", "for",
+    "function", "getopts", "hash", "if", "in", "jobs", "kill", "local", "printf", "pwd", "read",
+    "readonly", "return", "select", "set", "shift", "source", "test", "then", "time", "times",
+    "trap", "true", "type", "ulimit", "umask", "unalias", "unset", "until", "wait", "while",
+];
+
+/// Shell builtins and reserved words whose effect on the commands after them, such as a change of
+/// directory or variables, would be lost if those commands ran in another shell.
+const SHELL_STATE_BUILTINS: &[&str] = &[
+    ".", "alias", "break", "case", "cd", "continue", "do", "done", "elif", "else", "esac", "eval",
+    "exec", "exit", "export", "fi", "for", "function", "getopts", "hash", "if", "in", "local",
+    "read", "readonly", "return", "select", "set", "shift", "source", "then", "trap", "ulimit",
+    "umask", "unalias", "unset", "until", "while",
+];
+
+impl ShellQuoted {
+    /// Split the command into the arguments of a program if it needs no shell: a single command
+    /// made of plain words and quotes, without expansions, redirections, operators, variable
+    /// assignments or shell builtins.
+    ///
+    /// Anything the tokenizer is not sure about gives `None`, so that the command runs in a shell.
+    pub fn split_simple(&self) -> Option<Vec<String>> {
+        match <[_; 1]>::try_from(self.split_and_list()?) {
+            Ok([words]) if !SHELL_BUILTINS.contains(&words[0].as_str()) => Some(words),
+            _ => None,
+        }
+    }
+
+    /// Split the command into the arguments of the commands of an `&&` list, such as
+    /// `tsc && vitest run`, made of plain words like the ones of [`split_simple`](Self::split_simple).
+    ///
+    /// Shell builtins such as `echo` are allowed, since each command can run in a shell of its own,
+    /// except the ones that affect the commands after them, such as `cd`.
+    pub fn split_and_list(&self) -> Option<Vec<Vec<String>>> {
+        let mut commands = Vec::new();
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = self.0.chars().peekable();
+        while let Some(char) = chars.next() {
+            match char {
+                ' ' | '\t' => words.extend(word.take()),
+                '\'' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next()? {
+                            '\'' => break,
+                            char => word.push(char),
+                        }
+                    }
+                }
+                '"' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next()? {
+                            '"' => break,
+                            '$' | '`' => return None,
+                            '\\' => match chars.next()? {
+                                char @ ('"' | '\\') => word.push(char),
+                                '$' | '`' | '\n' => return None,
+                                char => {
+                                    word.push('\\');
+                                    word.push(char);
+                                }
+                            },
+                            char => word.push(char),
+                        }
+                    }
+                }
+                '\\' => match chars.next()? {
+                    '\n' => return None,
+                    char => word.get_or_insert_with(String::new).push(char),
+                },
+                '&' if chars.next_if_eq(&'&').is_some() => {
+                    words.extend(word.take());
+                    commands.push(simple_command(std::mem::take(&mut words))?);
+                }
+                '\n' | '|' | '&' | ';' | '<' | '>' | '(' | ')' | '$' | '`' | '*' | '?' | '['
+                | '{' | '}' | '~' | '#' | '!' => return None,
+                '=' if words.is_empty() => return None,
+                char => word.get_or_insert_with(String::new).push(char),
+            }
+        }
+        words.extend(word);
+        commands.push(simple_command(words)?);
+        Some(commands)
+    }
+}
+
+/// `words` if they make a command that can run in a shell of its own.
+fn simple_command(words: Vec<String>) -> Option<Vec<String>> {
+    let program = words.first()?;
+    if program.is_empty() || SHELL_STATE_BUILTINS.contains(&program.as_str()) {
+        return None;
+    }
+    Some(words)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -63,4 +163,105 @@ mod tests {
             r#"echo hello world 'abc' ';ls /etc' 'ghi jkl' '"' "'""#
         );
     }
+
+    #[test]
+    fn test_split_simple() {
+        let split = |command: &str| ShellQuoted::from_command(command.to_string()).split_simple();
+        let simple = [
+            ("tsc -p .", vec!["tsc", "-p", "."]),
+            (
+                "vitest  run\t--watch=false",
+                vec!["vitest", "run", "--watch=false"],
+            ),
+            (
+                "eslint 'src dir' \"a\\\"b\" c\\ d",
+                vec!["eslint", "src dir", "a\"b", "c d"],
+            ),
+            ("node ''", vec!["node", ""]),
+            ("jest \"\\n\"", vec!["jest", "\\n"]),
+            (
+                "prettier --write 'src/*.ts'",
+                vec!["prettier", "--write", "src/*.ts"],
+            ),
+        ];
+        for (command, expected) in simple {
+            eprintln!("command={command:?}");
+            assert_eq!(
+                split(command),
+                Some(expected.into_iter().map(String::from).collect())
+            );
+        }
+        let needs_shell = [
+            "",
+            "  ",
+            "tsc && vitest",
+            "tsc; vitest",
+            "tsc | tee log",
+            "tsc > log",
+            "echo hello",
+            "cd src",
+            "NODE_ENV=production node build.js",
+            "node $SCRIPT",
+            "node \"$SCRIPT\"",
+            "node `which x`",
+            "rm -rf dist/*",
+            "node ~/script.js",
+            "node script.js # comment",
+            "node 'unterminated",
+            "node \"unterminated",
+            "node trailing\\",
+            "node\nother",
+            "(node)",
+            "'' arg",
+        ];
+        for command in needs_shell {
+            eprintln!("command={command:?}");
+            assert_eq!(split(command), None);
+        }
+    }
+
+    #[test]
+    fn test_split_and_list() {
+        let split = |command: &str| ShellQuoted::from_command(command.to_string()).split_and_list();
+        let lists = [
+            ("tsc", vec![vec!["tsc"]]),
+            (
+                "pnpm run lint&&pnpm run 'test' --watch",
+                vec![
+                    vec!["pnpm", "run", "lint"],
+                    vec!["pnpm", "run", "test", "--watch"],
+                ],
+            ),
+            ("a && b && c", vec![vec!["a"], vec!["b"], vec!["c"]]),
+            ("node '&&' x", vec![vec!["node", "&&", "x"]]),
+            (
+                "pnpm run build && echo done",
+                vec![vec!["pnpm", "run", "build"], vec!["echo", "done"]],
+            ),
+        ];
+        for (command, expected) in lists {
+            eprintln!("command={command:?}");
+            let expected: Vec<Vec<String>> = expected
+                .into_iter()
+                .map(|words| words.into_iter().map(String::from).collect())
+                .collect();
+            assert_eq!(split(command), Some(expected));
+        }
+        let needs_shell = [
+            "a &&",
+            "&& b",
+            "a && && b",
+            "a & b",
+            "a || b",
+            "a && cd src",
+            "a && NODE_ENV=test b",
+            "a && b > log",
+            "a && export FOO=1",
+            "exit 0 && b",
+        ];
+        for command in needs_shell {
+            eprintln!("command={command:?}");
+            assert_eq!(split(command), None);
+        }
+    }
 }
@@ -0,0 +1,77 @@
+//! Signals that terminate the processes spawned by `pn`.
+
+use std::process::ExitStatus;
+
+/// Signal that terminated the process with `status`, if any.
+#[cfg(unix)]
+pub fn termination_signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+/// Signal that terminated the process with `status`, if any.
+#[cfg(not(unix))]
+pub fn termination_signal(_: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Exit code of a shell whose child was terminated by `signal`.
+pub fn signal_exit_code(signal: i32) -> i32 {
+    128 + signal
+}
+
+/// Name of `signal`, such as `SIGKILL`, if it is a well-known one.
+#[cfg(unix)]
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    const NAMES: &[(i32, &str)] = &[
+        (libc::SIGHUP, "SIGHUP"),
+        (libc::SIGINT, "SIGINT"),
+        (libc::SIGQUIT, "SIGQUIT"),
+        (libc::SIGILL, "SIGILL"),
+        (libc::SIGTRAP, "SIGTRAP"),
+        (libc::SIGABRT, "SIGABRT"),
+        (libc::SIGBUS, "SIGBUS"),
+        (libc::SIGFPE, "SIGFPE"),
+        (libc::SIGKILL, "SIGKILL"),
+        (libc::SIGUSR1, "SIGUSR1"),
+        (libc::SIGSEGV, "SIGSEGV"),
+        (libc::SIGUSR2, "SIGUSR2"),
+        (libc::SIGPIPE, "SIGPIPE"),
+        (libc::SIGALRM, "SIGALRM"),
+        (libc::SIGTERM, "SIGTERM"),
+        (libc::SIGXCPU, "SIGXCPU"),
+        (libc::SIGXFSZ, "SIGXFSZ"),
+    ];
+    NAMES
+        .iter()
+        .find(|(number, _)| *number == signal)
+        .map(|(_, name)| *name)
+}
+
+/// Name of `signal`, such as `SIGKILL`, if it is a well-known one.
+#[cfg(not(unix))]
+pub fn signal_name(_: i32) -> Option<&'static str> {
+    None
+}
+
+/// Describe `signal` in messages, e.g. `SIGKILL (9)`.
+pub fn describe_signal(signal: i32) -> String {
+    match signal_name(signal) {
+        Some(name) => format!("{name} ({signal})"),
+        None => format!("signal {signal}"),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_describe_signal() {
+        assert_eq!(describe_signal(libc::SIGKILL), "SIGKILL (9)");
+        assert_eq!(describe_signal(libc::SIGINT), "SIGINT (2)");
+        assert_eq!(describe_signal(1000), "signal 1000");
+        assert_eq!(signal_exit_code(libc::SIGKILL), 137);
+    }
+}
@@ -0,0 +1,488 @@
+//! Local cache of the tasks that declare their inputs, keyed by a hash of these inputs.
+//!
+//! An entry of the cache holds the logs of the task, which are replayed, and the files matched by
+//! its outputs, which are restored into the project.
+
+use crate::{error::PnError, glob::Glob};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+};
+
+/// Directories that are never searched for inputs and outputs.
+const IGNORED_DIRS: &[&str] = &["node_modules", ".git"];
+
+/// Directory of the outputs in a cache entry.
+const OUTPUTS_DIR: &str = "outputs";
+
+/// Name of the log in a cache entry, see [`TaskLog`].
+const LOG: &str = "output.log";
+
+/// Hash of everything that determines what a task does: the `fingerprint` of its scripts, the
+/// hashes of the tasks it depends on, and the paths and contents of the files of `project_dir`
+/// matched by `inputs`.
+pub fn task_hash(
+    project_dir: &Path,
+    inputs: &[String],
+    fingerprint: &[&str],
+    dependency_hashes: &[String],
+) -> Result<String, PnError> {
+    let mut hasher = Sha256::new();
+    let mut write = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+    for part in fingerprint {
+        write(part.as_bytes());
+    }
+    for hash in dependency_hashes {
+        write(hash.as_bytes());
+    }
+    for path in matching_files(project_dir, inputs)? {
+        let full_path = project_dir.join(&path);
+        let content = fs::read(&full_path).map_err(|error| PnError::FsError {
+            path: full_path,
+            error,
+        })?;
+        write(path.as_bytes());
+        write(&content);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Files of `dir` matched by `globs`, as sorted `/`-separated relative paths, where `!` negates a
+/// glob.
+pub fn matching_files(dir: &Path, globs: &[String]) -> Result<Vec<String>, PnError> {
+    let (excludes, includes): (Vec<_>, Vec<_>) = globs
+        .iter()
+        .map(String::as_str)
+        .partition(|glob| glob.starts_with('!'));
+    let includes = includes
+        .into_iter()
+        .map(Glob::new)
+        .collect::<Result<Vec<_>, _>>()?;
+    let excludes = excludes
+        .into_iter()
+        .map(|glob| Glob::new(&glob[1..]))
+        .collect::<Result<Vec<_>, _>>()?;
+    let dir = dunce::canonicalize(dir).map_err(|error| PnError::FsError {
+        path: dir.to_path_buf(),
+        error,
+    })?;
+    let mut files = Vec::new();
+    let mut visiting = vec![dir.clone()];
+    collect_files(&dir, "", &includes, &excludes, &mut files, &mut visiting)?;
+    files.sort();
+    Ok(files)
+}
+
+/// Add the files of `dir` matched by the globs to `files`, following symbolic links.
+///
+/// `visiting` holds the canonical paths of `dir` and of the directories it is in, so that a link to
+/// one of them is not followed forever.
+fn collect_files(
+    dir: &Path,
+    relative_dir: &str,
+    includes: &[Glob],
+    excludes: &[Glob],
+    files: &mut Vec<String>,
+    visiting: &mut Vec<PathBuf>,
+) -> Result<(), PnError> {
+    let fs_error = |error| PnError::FsError {
+        path: dir.to_path_buf(),
+        error,
+    };
+    for entry in fs::read_dir(dir).map_err(fs_error)? {
+        let entry = entry.map_err(fs_error)?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        let relative_path = if relative_dir.is_empty() {
+            file_name.to_string()
+        } else {
+            format!("{relative_dir}/{file_name}")
+        };
+        let mut path = entry.path();
+        let mut file_type = entry.file_type().map_err(fs_error)?;
+        if file_type.is_symlink() {
+            let Ok(target) = dunce::canonicalize(&path) else {
+                continue; // a broken link
+            };
+            file_type = fs::metadata(&target).map_err(fs_error)?.file_type();
+            path = target;
+        }
+        if file_type.is_dir() {
+            let may_match = includes
+                .iter()
+                .any(|glob| glob.may_match_descendant(&relative_path));
+            if may_match && !IGNORED_DIRS.contains(&file_name) && !visiting.contains(&path) {
+                visiting.push(path.clone());
+                collect_files(&path, &relative_path, includes, excludes, files, visiting)?;
+                visiting.pop();
+            }
+        } else if includes.iter().any(|glob| glob.is_match(&relative_path))
+            && !excludes.iter().any(|glob| glob.is_match(&relative_path))
+        {
+            files.push(relative_path);
+        }
+    }
+    Ok(())
+}
+
+/// The cache in `node_modules/.cache/pn` of a directory, usually the workspace root.
+#[derive(Debug, Clone)]
+pub struct TaskCache {
+    dir: PathBuf,
+}
+
+impl TaskCache {
+    pub fn new(root_dir: &Path) -> Self {
+        TaskCache {
+            dir: root_dir.join("node_modules/.cache/pn"),
+        }
+    }
+
+    /// The entry of `hash`, if the task was cached.
+    pub fn get(&self, hash: &str) -> Option<CacheEntry> {
+        let dir = self.dir.join(hash);
+        dir.is_dir().then_some(CacheEntry { dir })
+    }
+
+    /// Start the entry of `hash`, where the task writes its logs while it runs.
+    pub fn start(&self, hash: &str) -> Result<PendingEntry, PnError> {
+        let temp_dir = self.dir.join(format!("{hash}.{}.tmp", process::id()));
+        if temp_dir.exists() {
+            fs::remove_dir_all(&temp_dir).map_err(cache_error(&temp_dir))?;
+        }
+        fs::create_dir_all(&temp_dir).map_err(cache_error(&temp_dir))?;
+        Ok(PendingEntry {
+            temp_dir,
+            dir: self.dir.join(hash),
+        })
+    }
+}
+
+/// Remove the log that a failed attempt of a task wrote in `log_dir`, before it runs again.
+pub fn clear_logs(log_dir: &Path) -> Result<(), PnError> {
+    let path = log_dir.join(LOG);
+    match fs::remove_file(&path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => {
+            Err(PnError::TaskCacheError { path, error })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Stream of the output of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+impl LogStream {
+    /// Byte in front of the lines of this stream in a [`TaskLog`].
+    fn tag(self) -> u8 {
+        match self {
+            LogStream::Stdout => b'1',
+            LogStream::Stderr => b'2',
+        }
+    }
+}
+
+/// The log of a running task in the directory of its cache entry, which records the lines of both
+/// its stdout and stderr in the order they were written, each after the tag of its stream.
+#[derive(Debug)]
+pub struct TaskLog {
+    file: Mutex<File>,
+}
+
+impl TaskLog {
+    /// Open the log in `log_dir` to append lines to it.
+    pub fn open(log_dir: &Path) -> Result<Self, PnError> {
+        let path = log_dir.join(LOG);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(cache_error(&path))?;
+        Ok(TaskLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append `line`, which ends with a line break, written to `stream`.
+    ///
+    /// Write errors are ignored, like the ones of the terminal.
+    pub fn write_line(&self, stream: LogStream, line: &[u8]) {
+        let record = [&[stream.tag()], line].concat();
+        self.file.lock().unwrap().write_all(&record).ok();
+    }
+}
+
+fn cache_error(path: &Path) -> impl FnOnce(io::Error) -> PnError + '_ {
+    |error| PnError::TaskCacheError {
+        path: path.to_path_buf(),
+        error,
+    }
+}
+
+/// A finished task in the cache.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    dir: PathBuf,
+}
+
+impl CacheEntry {
+    /// Replace the files of `project_dir` matched by `outputs` with the cached ones, so that no
+    /// file left by another run survives.
+    pub fn restore_outputs(&self, project_dir: &Path, outputs: &[String]) -> Result<(), PnError> {
+        for path in matching_files(project_dir, outputs)? {
+            let target = project_dir.join(&path);
+            fs::remove_file(&target).map_err(cache_error(&target))?;
+        }
+        let outputs_dir = self.dir.join(OUTPUTS_DIR);
+        if !outputs_dir.is_dir() {
+            return Ok(()); // the task has no outputs
+        }
+        for path in matching_files(&outputs_dir, &["**".to_string()])? {
+            let target = project_dir.join(&path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(cache_error(parent))?;
+            }
+            fs::copy(outputs_dir.join(&path), &target).map_err(cache_error(&target))?;
+        }
+        Ok(())
+    }
+
+    /// Write the cached log to stdout and stderr in the order the task wrote it, each line starting
+    /// with `prefix`.
+    pub fn replay_logs(&self, prefix: &str) {
+        self.read_log(|stream, line| {
+            let line = [prefix.as_bytes(), line].concat();
+            match stream {
+                LogStream::Stdout => io::stdout().write_all(&line).ok(),
+                LogStream::Stderr => io::stderr().write_all(&line).ok(),
+            };
+        });
+        io::stdout().flush().ok();
+    }
+
+    /// Pass the lines of the cached log to `handle_line`, in order.
+    fn read_log(&self, mut handle_line: impl FnMut(LogStream, &[u8])) {
+        let Ok(log) = File::open(self.dir.join(LOG)) else {
+            return;
+        };
+        let mut log = BufReader::new(log);
+        let mut record = Vec::new();
+        while let Ok(1..) = log.read_until(b'\n', &mut record) {
+            if let Some((&tag, line)) = record.split_first() {
+                let stream = if tag == LogStream::Stderr.tag() {
+                    LogStream::Stderr
+                } else {
+                    LogStream::Stdout
+                };
+                handle_line(stream, line);
+            }
+            record.clear();
+        }
+    }
+}
+
+/// An entry of the cache whose task is running, which is discarded unless it is committed.
+#[derive(Debug)]
+pub struct PendingEntry {
+    temp_dir: PathBuf,
+    dir: PathBuf,
+}
+
+impl PendingEntry {
+    /// Directory of the logs of the task.
+    pub fn log_dir(&self) -> &Path {
+        &self.temp_dir
+    }
+
+    /// Save the files of `project_dir` matched by `outputs` and make the entry available.
+    pub fn commit(self, project_dir: &Path, outputs: &[String]) -> Result<(), PnError> {
+        let outputs_dir = self.temp_dir.join(OUTPUTS_DIR);
+        for path in matching_files(project_dir, outputs)? {
+            let target = outputs_dir.join(&path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(cache_error(parent))?;
+            }
+            fs::copy(project_dir.join(&path), &target).map_err(cache_error(&target))?;
+        }
+        if self.dir.exists() {
+            // another run cached the same task meanwhile
+            return Ok(());
+        }
+        fs::rename(&self.temp_dir, &self.dir).map_err(cache_error(&self.dir))
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.temp_dir).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build_fs_tree::{dir, file, Build, MergeableFileSystemTree};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn test_matching_files() {
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            "package.json" => file!("{}"),
+            "src" => dir! {
+                "index.ts" => file!(""),
+                "index.test.ts" => file!(""),
+                "lib" => dir! {
+                    "util.ts" => file!(""),
+                },
+            },
+            "node_modules" => dir! {
+                "dep" => dir! {
+                    "index.ts" => file!(""),
+                },
+            },
+        });
+        tree.build(&temp_dir).unwrap();
+
+        let received = matching_files(
+            temp_dir.path(),
+            &strings(&["src/**/*.ts", "package.json", "!**/*.test.ts"]),
+        )
+        .unwrap();
+        assert_eq!(
+            received,
+            ["package.json", "src/index.ts", "src/lib/util.ts"]
+        );
+
+        let received = matching_files(temp_dir.path(), &strings(&["**/index.ts"])).unwrap();
+        assert_eq!(received, ["src/index.ts"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_matching_files_symlinks() {
+        use std::os::unix::fs::symlink;
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            "shared" => dir! {
+                "util.ts" => file!(""),
+            },
+            "project" => dir! {
+                "src" => dir! {
+                    "index.ts" => file!(""),
+                },
+            },
+        });
+        tree.build(&temp_dir).unwrap();
+        let project_dir = temp_dir.path().join("project");
+        symlink(
+            temp_dir.path().join("shared"),
+            project_dir.join("src/shared"),
+        )
+        .unwrap();
+        symlink(&project_dir, project_dir.join("src/loop")).unwrap();
+        symlink("missing", project_dir.join("src/broken.ts")).unwrap();
+
+        let received = matching_files(&project_dir, &strings(&["src/**"])).unwrap();
+        assert_eq!(received, ["src/index.ts", "src/shared/util.ts"]);
+    }
+
+    #[test]
+    fn test_task_hash() {
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            "src" => dir! {
+                "index.ts" => file!("one"),
+            },
+            "README.md" => file!("docs"),
+        });
+        tree.build(&temp_dir).unwrap();
+        let inputs = strings(&["src/**"]);
+        let hash = |fingerprint: &[&str], dependency_hashes: &[&str]| {
+            task_hash(
+                temp_dir.path(),
+                &inputs,
+                fingerprint,
+                &strings(dependency_hashes),
+            )
+            .unwrap()
+        };
+
+        let first = hash(&["build", "tsc"], &[]);
+        assert_eq!(first.len(), 64);
+        assert_eq!(hash(&["build", "tsc"], &[]), first);
+        assert_ne!(hash(&["build", "tsc -p ."], &[]), first);
+        assert_ne!(hash(&["build", "tsc"], &["abc"]), first);
+
+        fs::write(temp_dir.path().join("README.md"), "changed").unwrap();
+        assert_eq!(hash(&["build", "tsc"], &[]), first);
+
+        fs::write(temp_dir.path().join("src/index.ts"), "two").unwrap();
+        assert_ne!(hash(&["build", "tsc"], &[]), first);
+    }
+
+    #[test]
+    fn test_cache_entry() {
+        let temp_dir = tempdir().unwrap();
+        let project_dir = temp_dir.path().join("project");
+        fs::create_dir_all(project_dir.join("dist")).unwrap();
+        fs::write(project_dir.join("dist/index.js"), "built").unwrap();
+        let cache = TaskCache::new(temp_dir.path());
+        assert!(cache.get("abc").is_none());
+
+        let pending = cache.start("abc").unwrap();
+        let log = TaskLog::open(pending.log_dir()).unwrap();
+        log.write_line(LogStream::Stdout, b"out\n");
+        log.write_line(LogStream::Stderr, b"err\n");
+        log.write_line(LogStream::Stdout, b"\n");
+        drop(log);
+        pending
+            .commit(&project_dir, &strings(&["dist/**"]))
+            .unwrap();
+
+        fs::remove_dir_all(project_dir.join("dist")).unwrap();
+        fs::create_dir_all(project_dir.join("dist")).unwrap();
+        fs::write(project_dir.join("dist/stale.js"), "stale").unwrap();
+        let entry = cache.get("abc").expect("the entry is committed");
+        let mut lines = Vec::new();
+        entry.read_log(|stream, line| {
+            lines.push((stream, String::from_utf8_lossy(line).into_owned()))
+        });
+        let expected = [
+            (LogStream::Stdout, "out\n".to_string()),
+            (LogStream::Stderr, "err\n".to_string()),
+            (LogStream::Stdout, "\n".to_string()),
+        ];
+        assert_eq!(lines, expected);
+        entry
+            .restore_outputs(&project_dir, &strings(&["dist/**"]))
+            .unwrap();
+        let restored = fs::read_to_string(project_dir.join("dist/index.js")).unwrap();
+        assert_eq!(restored, "built");
+        assert!(!project_dir.join("dist/stale.js").exists());
+
+        let pending = cache.start("def").unwrap();
+        let log_dir = pending.log_dir().to_path_buf();
+        drop(pending);
+        assert!(!log_dir.exists());
+        assert!(cache.get("def").is_none());
+    }
+}
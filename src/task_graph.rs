@@ -0,0 +1,284 @@
+//! Scripts declared as tasks in the `pn` section of `package.json` or `pnpm-workspace.yaml`, which
+//! run after the scripts they depend on, in the same project or in the workspace dependencies of the
+//! project.
+
+use crate::{error::PnError, workspace_graph::WorkspaceGraph, NodeManifest};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The `pn` section of `package.json` and `pnpm-workspace.yaml`.
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PnSection {
+    /// Settings of the scripts that run as tasks, by script name.
+    #[serde(default)]
+    pub tasks: IndexMap<String, TaskConfig>,
+}
+
+/// Settings of a script that runs as a task.
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskConfig {
+    /// Scripts to run first: `name` in the same project, `^name` in the workspace dependencies of the
+    /// project.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+
+    /// Globs of the files that the script reads, relative to the project, `!` negates a glob.
+    ///
+    /// Only scripts with inputs are cached.
+    #[serde(default)]
+    pub inputs: Vec<String>,
+
+    /// Globs of the files that the script writes, which are restored from the cache.
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+/// Settings of the task `script` of `manifest`, which take precedence over the ones of the
+/// workspace.
+pub fn task_config<'a>(
+    manifest: &'a NodeManifest,
+    workspace_tasks: &'a IndexMap<String, TaskConfig>,
+    script: &str,
+) -> Option<&'a TaskConfig> {
+    manifest
+        .pn
+        .as_ref()
+        .and_then(|section| section.tasks.get(script))
+        .or_else(|| workspace_tasks.get(script))
+}
+
+/// A script of a workspace project.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    /// Index of the project in the [`WorkspaceGraph`].
+    pub project: usize,
+    pub script: String,
+    /// Settings of the task, the default ones if it has none.
+    pub config: TaskConfig,
+    /// Whether the task was asked for rather than depended on, so that it receives the arguments.
+    pub is_root: bool,
+}
+
+/// Tasks to run, each one after the tasks it depends on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskGraph {
+    /// Every task comes after the tasks it depends on.
+    pub tasks: Vec<Task>,
+    /// Indices of the tasks that each task depends on.
+    pub waits_for: Vec<Vec<usize>>,
+}
+
+impl TaskGraph {
+    /// Gather the tasks needed to run `script` in the `roots` projects of `graph`.
+    ///
+    /// Dependencies on scripts that a project does not have are ignored.
+    pub fn build(
+        graph: &WorkspaceGraph,
+        workspace_tasks: &IndexMap<String, TaskConfig>,
+        roots: &[usize],
+        script: &str,
+    ) -> Result<Self, PnError> {
+        let mut builder = Builder {
+            graph,
+            workspace_tasks,
+            indices: HashMap::new(),
+            stack: Vec::new(),
+            tasks: TaskGraph {
+                tasks: Vec::new(),
+                waits_for: Vec::new(),
+            },
+        };
+        for &root in roots {
+            if graph.projects()[root].manifest.scripts.contains_key(script) {
+                let index = builder.visit(root, script)?;
+                builder.tasks.tasks[index].is_root = true;
+            }
+        }
+        Ok(builder.tasks)
+    }
+}
+
+struct Builder<'a> {
+    graph: &'a WorkspaceGraph,
+    workspace_tasks: &'a IndexMap<String, TaskConfig>,
+    indices: HashMap<(usize, String), usize>,
+    /// Tasks being visited, to detect cycles.
+    stack: Vec<(usize, String)>,
+    tasks: TaskGraph,
+}
+
+impl Builder<'_> {
+    /// Add the task `script` of `project` after the tasks it depends on, and return its index.
+    fn visit(&mut self, project: usize, script: &str) -> Result<usize, PnError> {
+        let key = (project, script.to_string());
+        if let Some(&index) = self.indices.get(&key) {
+            return Ok(index);
+        }
+        if let Some(position) = self.stack.iter().position(|visiting| *visiting == key) {
+            let cycle = self.stack[position..]
+                .iter()
+                .chain([&key])
+                .map(|(project, script)| self.describe(*project, script))
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(PnError::TaskCycle { cycle });
+        }
+        let manifest = &self.graph.projects()[project].manifest;
+        let config = task_config(manifest, self.workspace_tasks, script)
+            .cloned()
+            .unwrap_or_default();
+        self.stack.push(key.clone());
+        let mut waits_for = Vec::new();
+        for dependency in &config.depends_on {
+            if let Some(dependency) = dependency.strip_prefix('^') {
+                for &other in self.graph.dependencies_of(project) {
+                    if self.graph.projects()[other]
+                        .manifest
+                        .scripts
+                        .contains_key(dependency)
+                    {
+                        waits_for.push(self.visit(other, dependency)?);
+                    }
+                }
+            } else if manifest.scripts.contains_key(dependency) {
+                waits_for.push(self.visit(project, dependency)?);
+            }
+        }
+        self.stack.pop();
+        waits_for.sort_unstable();
+        waits_for.dedup();
+        let index = self.tasks.tasks.len();
+        self.tasks.tasks.push(Task {
+            project,
+            script: script.to_string(),
+            config,
+            is_root: false,
+        });
+        self.tasks.waits_for.push(waits_for);
+        self.indices.insert(key, index);
+        Ok(index)
+    }
+
+    /// Describe a task in messages, e.g. `app#build`.
+    fn describe(&self, project: usize, script: &str) -> String {
+        let project = &self.graph.projects()[project];
+        if project.manifest.name.is_empty() {
+            format!("{}#{script}", project.dir.display())
+        } else {
+            format!("{}#{script}", project.manifest.name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::WorkspaceProject;
+    use pipe_trait::Pipe;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::path::Path;
+
+    /// `app` -> `lib` -> `utils`, where `utils` has no `codegen` script.
+    fn create_graph(app_tasks: serde_json::Value) -> WorkspaceGraph {
+        let project = |name: &str, dependencies: &[&str], pn: serde_json::Value| {
+            let dependencies: serde_json::Map<_, _> = dependencies
+                .iter()
+                .map(|name| (name.to_string(), json!("workspace:*")))
+                .collect();
+            let scripts = if name == "utils" {
+                json!({"build": "tsc"})
+            } else {
+                json!({"build": "tsc", "codegen": "gen"})
+            };
+            WorkspaceProject {
+                dir: Path::new("/repo/packages").join(name),
+                manifest: json!({
+                    "name": name,
+                    "dependencies": dependencies,
+                    "scripts": scripts,
+                    "pn": pn,
+                })
+                .pipe(serde_json::from_value)
+                .unwrap(),
+            }
+        };
+        WorkspaceGraph::new(vec![
+            project("app", &["lib"], app_tasks),
+            project("lib", &["utils"], json!({})),
+            project("utils", &[], json!({})),
+        ])
+    }
+
+    fn workspace_tasks() -> IndexMap<String, TaskConfig> {
+        json!({
+            "build": {"dependsOn": ["^build", "codegen"], "inputs": ["src/**"]},
+        })
+        .pipe(serde_json::from_value)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_build() {
+        let graph = create_graph(json!({}));
+        let received = TaskGraph::build(&graph, &workspace_tasks(), &[0], "build").unwrap();
+        let received: Vec<_> = received
+            .tasks
+            .iter()
+            .zip(&received.waits_for)
+            .map(|(task, waits_for)| {
+                let name = &graph.projects()[task.project].manifest.name;
+                (
+                    format!("{name}#{}", task.script),
+                    waits_for.clone(),
+                    task.is_root,
+                )
+            })
+            .collect();
+        let expected = [
+            ("utils#build", vec![], false),
+            ("lib#codegen", vec![], false),
+            ("lib#build", vec![0, 1], false),
+            ("app#codegen", vec![], false),
+            ("app#build", vec![2, 3], true),
+        ]
+        .map(|(name, waits_for, is_root)| (name.to_string(), waits_for, is_root));
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_project_overrides_workspace() {
+        let graph = create_graph(json!({"tasks": {"build": {"outputs": ["dist/**"]}}}));
+        let received = TaskGraph::build(&graph, &workspace_tasks(), &[0, 1], "build").unwrap();
+        let received: Vec<_> = received
+            .tasks
+            .iter()
+            .map(|task| (task.project, task.script.as_str(), task.is_root))
+            .collect();
+        // `app#build` depends on nothing, `lib#build` still uses the settings of the workspace
+        assert_eq!(
+            received,
+            [
+                (0, "build", true),
+                (2, "build", false),
+                (1, "codegen", false),
+                (1, "build", true),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_cycle() {
+        let graph = create_graph(json!({"tasks": {
+            "build": {"dependsOn": ["codegen"]},
+            "codegen": {"dependsOn": ["build"]},
+        }}));
+        let error = TaskGraph::build(&graph, &IndexMap::new(), &[0], "build").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Tasks depend on each other in a cycle: app#build -> app#codegen -> app#build",
+        );
+    }
+}
@@ -1,90 +1,290 @@
 use crate::{
+    config::Config,
     error::{MainError, PnError},
+    output::ScriptOutput,
+    process_group,
+    script_env::ScriptEnv,
     shell_quoted::ShellQuoted,
+    signal::termination_signal,
     NodeManifest,
 };
 use pipe_trait::Pipe;
 use std::{
     env,
-    ffi::OsString,
-    fs::File,
+    ffi::{OsStr, OsString},
+    fs::{self, File},
     io::ErrorKind,
     num::NonZeroI32,
-    path::Path,
-    process::{Command, Stdio},
+    path::{Component, Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    time::Duration,
 };
 
-pub fn run_script(name: &str, command: ShellQuoted, cwd: &Path) -> Result<(), MainError> {
-    let path_env = create_path_env()?;
-    let status = Command::new("sh")
+pub fn run_script(
+    name: &str,
+    command: ShellQuoted,
+    cwd: &Path,
+    env: ScriptEnv,
+    output: &ScriptOutput,
+    header: &str,
+    config: &Config,
+) -> Result<(), MainError> {
+    let env = env.with_lifecycle(name, &command.to_string());
+    let status = script_command(&command, cwd, &env, config)?
         .current_dir(cwd)
-        .env("PATH", path_env)
-        .arg("-c")
-        .arg(&command)
+        .envs(env.vars())
         .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .map_err(PnError::SpawnProcessError)?
-        .wait()
-        .map_err(PnError::WaitProcessError)?
-        .code()
-        .map(NonZeroI32::new);
-    match status {
-        Some(None) => return Ok(()),
-        Some(Some(status)) => PnError::ScriptError {
+        .pipe(|command| output.run(command, header))?;
+    if status.success() {
+        return Ok(());
+    }
+    if let Some(status) = status.code().and_then(NonZeroI32::new) {
+        PnError::ScriptError {
             name: name.to_string(),
             status,
-        },
-        None => PnError::UnexpectedTermination { command },
+        }
+    } else if let Some(signal) = termination_signal(status) {
+        PnError::TerminatedBySignal {
+            name: name.to_string(),
+            signal,
+        }
+    } else {
+        PnError::UnexpectedTermination { command }
     }
     .pipe(MainError::Pn)
     .pipe(Err)
 }
 
-pub fn pass_to_pnpm(args: &[String]) -> Result<(), MainError> {
+pub fn pass_to_pnpm(args: &[String], cwd: &Path) -> Result<(), MainError> {
     let status = Command::new("pnpm")
         .args(args)
+        .current_dir(cwd)
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
-        .spawn()
-        .map_err(PnError::SpawnProcessError)?
-        .wait()
-        .map_err(PnError::WaitProcessError)?
-        .code()
-        .map(NonZeroI32::new);
-    Err(match status {
-        Some(None) => return Ok(()),
-        Some(Some(status)) => MainError::Sub(status),
-        None => MainError::Pn(PnError::UnexpectedTermination {
-            command: ShellQuoted::from_command_and_args("pnpm".into(), args),
-        }),
-    })
+        .pipe(|command| process_group::spawn(command, true))?
+        .wait()?;
+    let command = || ShellQuoted::from_command_and_args("pnpm".into(), args);
+    sub_status_result(status, command)
 }
 
-pub fn pass_to_sub(command: ShellQuoted) -> Result<(), MainError> {
-    let path_env = create_path_env()?;
-    let status = Command::new("sh")
-        .env("PATH", path_env)
-        .arg("-c")
-        .arg(&command)
+pub fn pass_to_sub(
+    command: ShellQuoted,
+    cwd: &Path,
+    env: &ScriptEnv,
+    output: &ScriptOutput,
+    config: &Config,
+) -> Result<(), MainError> {
+    let status = script_command(&command, cwd, env, config)?
+        .current_dir(cwd)
+        .envs(env.vars())
         .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .map_err(PnError::SpawnProcessError)?
-        .wait()
-        .map_err(PnError::WaitProcessError)?
-        .code()
-        .map(NonZeroI32::new);
-    Err(match status {
-        Some(None) => return Ok(()),
-        Some(Some(status)) => MainError::Sub(status),
-        None => MainError::Pn(PnError::UnexpectedTermination { command }),
+        .pipe(|command| output.run(command, ""))?;
+    sub_status_result(status, || command)
+}
+
+/// Hand the status of a subprocess that takes control over to the caller: its exit code as is, or
+/// an error naming the signal that terminated it.
+fn sub_status_result(
+    status: ExitStatus,
+    command: impl FnOnce() -> ShellQuoted,
+) -> Result<(), MainError> {
+    if status.success() {
+        return Ok(());
+    }
+    if let Some(status) = status.code().and_then(NonZeroI32::new) {
+        return Err(MainError::Sub(status));
+    }
+    let command = command();
+    match termination_signal(status) {
+        Some(signal) => PnError::TerminatedBySignal {
+            name: command.to_string(),
+            signal,
+        },
+        None => PnError::UnexpectedTermination { command },
+    }
+    .pipe(MainError::Pn)
+    .pipe(Err)
+}
+
+/// Run the executable `args[0]`, found in the `PATH` of `env`, with the arguments `args[1..]`, without
+/// a shell.
+pub fn exec_command(
+    args: &[String],
+    cwd: &Path,
+    env: &ScriptEnv,
+    output: &ScriptOutput,
+    config: &Config,
+) -> Result<(), MainError> {
+    let (name, args) = args.split_first().expect("clap requires a command");
+    let path_env = env.get("PATH").unwrap_or_default();
+    let program =
+        find_executable(name, path_env, cwd).ok_or_else(|| PnError::ExecutableNotFound {
+            name: name.to_string(),
+        })?;
+    let status = direct_command(program, args, config)
+        .current_dir(cwd)
+        .envs(env.vars())
+        .stdin(Stdio::inherit())
+        .pipe(|command| output.run(command, ""))?;
+    sub_status_result(status, || {
+        ShellQuoted::from_command_and_args(name.into(), args)
     })
 }
 
+/// Find the executable `name` in the directories of `path_env`, or relative to `cwd` when `name` is
+/// a path.
+pub fn find_executable(name: &str, path_env: &OsStr, cwd: &Path) -> Option<PathBuf> {
+    if Path::new(name).components().count() > 1 {
+        return executable_candidates(&cwd.join(name)).find(|path| is_executable(path));
+    }
+    env::split_paths(path_env)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .flat_map(|dir| executable_candidates(&cwd.join(dir).join(name)).collect::<Vec<_>>())
+        .find(|path| is_executable(path))
+}
+
+/// Paths that the executable `path` may have.
+#[cfg(unix)]
+fn executable_candidates(path: &Path) -> impl Iterator<Item = PathBuf> {
+    std::iter::once(path.to_path_buf())
+}
+
+/// Paths that the executable `path` may have: the path itself and the path with every extension of
+/// `PATHEXT`.
+#[cfg(not(unix))]
+fn executable_candidates(path: &Path) -> impl Iterator<Item = PathBuf> {
+    let extensions = env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+    let with_extensions: Vec<_> = extensions
+        .split(';')
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| {
+            let mut path = path.as_os_str().to_owned();
+            path.push(extension);
+            PathBuf::from(path)
+        })
+        .collect();
+    std::iter::once(path.to_path_buf()).chain(with_extensions)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Whether the kernel can run the file at `path` itself, because it starts with a shebang or is an
+/// ELF or Mach-O binary. Spawning any other file fails with `ENOEXEC`.
+#[cfg(unix)]
+fn has_executable_format(path: &Path) -> bool {
+    use std::io::Read;
+    const MAGIC_NUMBERS: &[&[u8]] = &[
+        b"#!",
+        b"\x7fELF",
+        &[0xfe, 0xed, 0xfa, 0xce],
+        &[0xfe, 0xed, 0xfa, 0xcf],
+        &[0xce, 0xfa, 0xed, 0xfe],
+        &[0xcf, 0xfa, 0xed, 0xfe],
+        &[0xca, 0xfe, 0xba, 0xbe],
+    ];
+    let mut header = [0; 4];
+    let Ok(length) = File::open(path).and_then(|mut file| file.read(&mut header)) else {
+        return false;
+    };
+    MAGIC_NUMBERS
+        .iter()
+        .any(|magic| header[..length].starts_with(magic))
+}
+
+#[cfg(not(unix))]
+fn has_executable_format(_: &Path) -> bool {
+    true
+}
+
+/// Command that runs the shell script `command` in `cwd` with `env`.
+///
+/// A script that needs no shell, such as `tsc -p .`, spawns its executable directly, which saves
+/// starting a shell, unless `config` asks for a specific `script-shell` or the executable is a shell
+/// script without a shebang, which only a shell can run.
+fn script_command(
+    command: &ShellQuoted,
+    cwd: &Path,
+    env: &ScriptEnv,
+    config: &Config,
+) -> Result<Command, MainError> {
+    if config.script_shell.is_some() {
+        return shell_command(command, config);
+    }
+    let Some(args) = command.split_simple() else {
+        return shell_command(command, config);
+    };
+    let path_env = env.get("PATH").unwrap_or_default();
+    let Some(program) = find_executable(&args[0], path_env, cwd) else {
+        // the shell reports the missing command the usual way
+        return shell_command(command, config);
+    };
+    if !has_executable_format(&program) {
+        return shell_command(command, config);
+    }
+    let mut direct = direct_command(program, &args[1..], config);
+    direct.env("PWD", cwd); // like the shell would
+    Ok(direct)
+}
+
+/// Command that runs `program` with `args` without a shell, with the `node-options` of `config`.
+fn direct_command(program: PathBuf, args: &[String], config: &Config) -> Command {
+    let mut command = Command::new(program);
+    command.args(args);
+    if let Some(node_options) = &config.node_options {
+        command.env("NODE_OPTIONS", node_options);
+    }
+    command
+}
+
+/// Command that runs the shell script `command` with the shell chosen by `config`: the shell
+/// emulator built into `pn` when `shell-emulator` is set, `script-shell` if any, `sh` otherwise.
+///
+/// The command also carries the `node-options` of `config` as `NODE_OPTIONS`.
+pub fn shell_command(command: &ShellQuoted, config: &Config) -> Result<Command, MainError> {
+    let mut shell = if config.shell_emulator {
+        let mut shell = env::current_exe()
+            .map_err(PnError::SpawnProcessError)?
+            .pipe(Command::new);
+        shell.arg("__shell-emulator").arg("--");
+        shell
+    } else if let Some(script_shell) = &config.script_shell {
+        let mut shell = Command::new(script_shell);
+        if is_cmd_exe(script_shell) {
+            shell.args(["/d", "/s", "/c"]);
+        } else {
+            shell.arg("-c");
+        }
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    if let Some(node_options) = &config.node_options {
+        shell.env("NODE_OPTIONS", node_options);
+    }
+    Ok(shell)
+}
+
+/// Whether `shell` is `cmd.exe`, which takes `/c` instead of `-c`.
+fn is_cmd_exe(shell: &Path) -> bool {
+    shell
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("cmd"))
+}
+
 pub fn read_package_manifest(manifest_path: &Path) -> Result<NodeManifest, MainError> {
     manifest_path
         .pipe(File::open)
@@ -106,14 +306,130 @@ pub fn read_package_manifest(manifest_path: &Path) -> Result<NodeManifest, MainE
         })
 }
 
-pub fn create_path_env() -> Result<OsString, MainError> {
-    let existing_paths = env::var_os("PATH");
-    let existing_paths = existing_paths.iter().flat_map(env::split_paths);
-    Path::new("node_modules")
-        .join(".bin")
-        .pipe(std::iter::once)
+/// Value of `PATH` for the scripts of the package at `package_dir`: the `node-gyp-bin` directory of
+/// pnpm, the absolute `node_modules/.bin` of the package and of each of its ancestors up to the
+/// workspace root (or up to the filesystem root outside of a workspace), then the existing `PATH`.
+pub fn create_path_env(
+    package_dir: &Path,
+    workspace_dir: Option<&Path>,
+) -> Result<OsString, MainError> {
+    let existing_paths: Vec<_> = env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .collect();
+    pnpm_node_gyp_bin(&existing_paths)
+        .into_iter()
+        .chain(bin_dirs(package_dir, workspace_dir))
         .chain(existing_paths)
         .pipe(env::join_paths)
         .map_err(PnError::NodeBinPathError)
         .map_err(MainError::from)
 }
+
+/// The `node_modules/.bin` directories of `package_dir` and its ancestors, nearest first.
+fn bin_dirs(package_dir: &Path, workspace_dir: Option<&Path>) -> Vec<PathBuf> {
+    let absolute = |path: &Path| {
+        std::path::absolute(path)
+            .unwrap_or_else(|_| path.to_path_buf())
+            .pipe_ref(|path| normalize_path(path))
+    };
+    let package_dir = absolute(package_dir);
+    let workspace_dir = workspace_dir
+        .map(absolute)
+        .filter(|workspace_dir| package_dir.starts_with(workspace_dir));
+    package_dir
+        .ancestors()
+        .take_while(|dir| match &workspace_dir {
+            Some(workspace_dir) => dir.starts_with(workspace_dir),
+            None => true,
+        })
+        .map(|dir| dir.join("node_modules").join(".bin"))
+        .collect()
+}
+
+/// The `node-gyp-bin` directory shipped with the `pnpm` found in `paths`, if any.
+fn pnpm_node_gyp_bin(paths: &[PathBuf]) -> Option<PathBuf> {
+    let node_gyp_bin = pnpm_package_dir(paths)?.join("dist").join("node-gyp-bin");
+    node_gyp_bin.is_dir().then_some(node_gyp_bin)
+}
+
+/// The directory of the package of the `pnpm` found in `paths`, if any.
+pub fn pnpm_package_dir(paths: &[PathBuf]) -> Option<PathBuf> {
+    let pnpm = paths
+        .iter()
+        .map(|dir| dir.join("pnpm"))
+        .find(|pnpm| pnpm.is_file())?;
+    // the `pnpm` executable links to `<package>/bin/pnpm.cjs`
+    dunce::canonicalize(pnpm)
+        .ok()?
+        .parent()?
+        .parent()?
+        .to_path_buf()
+        .pipe(Some)
+}
+
+/// Parse a duration such as `500ms`, `10s`, `1.5m` or `2h`, in seconds when there is no unit.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text
+        .find(|char: char| !char.is_ascii_digit() && char != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {text:?}"))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        unit => return Err(format!("unknown unit {unit:?} in duration {text:?}")),
+    };
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|error| format!("invalid duration {text:?}: {error}"))
+}
+
+/// Format a duration the way [`parse_duration`] reads it, e.g. `500ms`, `10s` or `1.5s`.
+pub fn format_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 && !duration.is_zero() {
+        format!("{}s", duration.as_secs())
+    } else if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{}s", duration.as_secs_f64())
+    }
+}
+
+/// Resolve `.` and `..` components without touching the filesystem.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            component => result.push(component),
+        }
+    }
+    result
+}
+
+/// Express `path` relative to `base`, assuming both are absolute and normalized.
+pub fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut result: PathBuf = base
+        .components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .collect();
+    result.extend(path.components().skip(common));
+    if result.as_os_str().is_empty() {
+        result.push(Component::CurDir);
+    }
+    result
+}
@@ -0,0 +1,410 @@
+//! Watch the files of a project for `pn run --watch`, with the file events of the operating system.
+//!
+//! `node_modules`, `.git` and the paths ignored by `.gitignore` files are never watched. Every other
+//! directory is watched on its own, so that large ignored directories cost no watches.
+
+use crate::{error::PnError, glob::Glob, process_group};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    DirEntry, Match, WalkBuilder,
+};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::{
+    collections::BTreeSet,
+    path::{Component, Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+/// Directories that are never watched.
+const IGNORED_DIRS: &[&str] = &["node_modules", ".git"];
+
+const GITIGNORE_FILENAME: &str = ".gitignore";
+
+/// How often a signal received by `pn` is checked for while no file changes.
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the files must stay unchanged before a burst of changes is reported.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(200);
+
+/// Watcher of the files of a directory.
+#[derive(Debug)]
+pub struct Watcher {
+    dir: PathBuf,
+    /// Globs of the watched paths, every path is watched if empty.
+    includes: Vec<Glob>,
+    /// Globs of the paths that are not watched.
+    excludes: Vec<Glob>,
+    /// Rules of the `.gitignore` files that apply to `dir`, outer files first.
+    gitignores: Vec<Gitignore>,
+    events: Receiver<notify::Result<Event>>,
+    /// Stops the events when dropped.
+    watcher: RecommendedWatcher,
+    /// Directories registered with `watcher`.
+    watched_dirs: BTreeSet<PathBuf>,
+}
+
+impl Watcher {
+    /// Start watching `dir`, or the paths of `dir` matched by `watch_paths` if any, except the ones
+    /// matched by `watch_ignores`.
+    ///
+    /// A glob that matches a directory matches everything inside it.
+    pub fn new(
+        dir: &Path,
+        watch_paths: &[String],
+        watch_ignores: &[String],
+    ) -> Result<Self, PnError> {
+        // the events report canonical paths
+        let dir = dunce::canonicalize(dir).map_err(|error| PnError::FsError {
+            path: dir.to_path_buf(),
+            error,
+        })?;
+        let (sender, events) = mpsc::channel();
+        let watcher = notify::recommended_watcher(sender).map_err(|error| PnError::WatchError {
+            path: dir.clone(),
+            message: error.to_string(),
+        })?;
+        let mut watcher = Watcher {
+            includes: watch_paths
+                .iter()
+                .map(|glob| Glob::new(glob))
+                .collect::<Result<_, _>>()?,
+            excludes: watch_ignores
+                .iter()
+                .map(|glob| Glob::new(glob))
+                .collect::<Result<_, _>>()?,
+            gitignores: load_gitignores(&dir),
+            dir: dir.clone(),
+            events,
+            watcher,
+            watched_dirs: BTreeSet::new(),
+        };
+        watcher.watch_tree(&dir)?;
+        Ok(watcher)
+    }
+
+    /// Watch `dir` and the directories inside it that are not ignored, and return the files found
+    /// in them.
+    fn watch_tree(&mut self, dir: &Path) -> Result<Vec<PathBuf>, PnError> {
+        let mut files = Vec::new();
+        let entries = WalkBuilder::new(dir)
+            .standard_filters(false)
+            .git_ignore(true)
+            .parents(true)
+            .require_git(false)
+            .filter_entry(|entry| !is_ignored_dir(entry))
+            .build()
+            .flatten();
+        for entry in entries {
+            let path = entry.into_path();
+            if !path.is_dir() {
+                files.push(path);
+                continue;
+            }
+            if self.watched_dirs.contains(&path) {
+                continue;
+            }
+            match self.watcher.watch(&path, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    self.watched_dirs.insert(path);
+                }
+                Err(_) if !path.exists() => {} // removed while it was walked
+                Err(error) => {
+                    return Err(PnError::WatchError {
+                        path,
+                        message: error.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Wait until some of the watched files are created, modified or removed, and return their
+    /// sorted relative paths once they stop changing.
+    ///
+    /// Fails when `pn` is interrupted by a signal meanwhile.
+    pub fn wait_for_changes(&mut self) -> Result<Vec<String>, PnError> {
+        let mut changes = BTreeSet::new();
+        loop {
+            if let Some(signal) = process_group::received_signal() {
+                return Err(PnError::Interrupted { signal });
+            }
+            let timeout = if changes.is_empty() {
+                SIGNAL_INTERVAL
+            } else {
+                DEBOUNCE_DELAY
+            };
+            let event = match self.events.recv_timeout(timeout) {
+                Ok(Ok(event)) => event,
+                Ok(Err(error)) => {
+                    return Err(PnError::WatchError {
+                        path: self.dir.clone(),
+                        message: error.to_string(),
+                    })
+                }
+                Err(RecvTimeoutError::Timeout) if changes.is_empty() => continue,
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+                    return Ok(changes.into_iter().collect());
+                }
+            };
+            if event.kind.is_access() {
+                continue; // reading a file changes nothing
+            }
+            for path in &event.paths {
+                if path
+                    .file_name()
+                    .is_some_and(|name| name == GITIGNORE_FILENAME)
+                {
+                    self.gitignores = load_gitignores(&self.dir);
+                    // directories that were ignored may not be anymore
+                    let dir = self.dir.clone();
+                    self.watch_tree(&dir)?;
+                }
+                if !path.is_dir() {
+                    // a directory that is gone is no longer watched, even if it comes back
+                    self.watched_dirs.remove(path);
+                } else if !self.watched_dirs.contains(path) && self.is_watched_dir(path) {
+                    // files may have been created in the new directory before it was watched
+                    for file in self.watch_tree(path)? {
+                        changes.extend(self.watched_path(&file));
+                    }
+                }
+                if let Some(relative_path) = self.watched_path(path) {
+                    changes.insert(relative_path);
+                }
+            }
+        }
+    }
+
+    /// The `/`-separated path of `path` relative to the watched directory, if it is a watched file.
+    fn watched_path(&self, path: &Path) -> Option<String> {
+        if path.is_dir() {
+            return None;
+        }
+        let relative_path = path.strip_prefix(&self.dir).ok()?;
+        let mut names = Vec::new();
+        for component in relative_path.components() {
+            let Component::Normal(name) = component else {
+                return None;
+            };
+            names.push(name.to_str()?);
+        }
+        if names[..names.len().saturating_sub(1)]
+            .iter()
+            .any(|name| IGNORED_DIRS.contains(name))
+            || self.is_ignored(path, false)
+        {
+            return None;
+        }
+        let relative_path = names.join("/");
+        // the path, or one of its parent directories, must match
+        let ancestors: Vec<_> = (1..=names.len())
+            .map(|length| names[..length].join("/"))
+            .collect();
+        let matches = |globs: &[Glob]| {
+            ancestors
+                .iter()
+                .any(|path| globs.iter().any(|glob| glob.is_match(path)))
+        };
+        let included = self.includes.is_empty() || matches(&self.includes);
+        (included && !matches(&self.excludes)).then_some(relative_path)
+    }
+
+    /// Whether `dir` is inside the watched directory, and neither it nor one of its parents is
+    /// ignored.
+    fn is_watched_dir(&self, dir: &Path) -> bool {
+        let Ok(relative_dir) = dir.strip_prefix(&self.dir) else {
+            return false;
+        };
+        let in_ignored_dir = relative_dir
+            .components()
+            .filter_map(|component| component.as_os_str().to_str())
+            .any(|name| IGNORED_DIRS.contains(&name));
+        !in_ignored_dir && !self.is_ignored(dir, true)
+    }
+
+    /// Whether the file or directory at `path` is ignored by a `.gitignore` file, where the rules of
+    /// inner files take precedence.
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gitignore in self.gitignores.iter().rev() {
+            if !path.starts_with(gitignore.path()) {
+                continue;
+            }
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+/// Load the `.gitignore` files of the ancestors of `dir` up to the root of the git repository, then
+/// the ones of `dir` and of its subdirectories that are not ignored, outer files first.
+fn load_gitignores(dir: &Path) -> Vec<Gitignore> {
+    let mut ancestors: Vec<_> = dir.ancestors().skip(1).collect();
+    if let Some(root) = dir.ancestors().position(|dir| dir.join(".git").exists()) {
+        ancestors.truncate(root);
+    }
+    let mut gitignores: Vec<_> = ancestors
+        .into_iter()
+        .rev()
+        .filter_map(|dir| load_gitignore(&dir.join(GITIGNORE_FILENAME)))
+        .collect();
+    let files = WalkBuilder::new(dir)
+        .hidden(false)
+        .parents(true)
+        .require_git(false)
+        .filter_entry(|entry| !is_ignored_dir(entry))
+        .build()
+        .flatten()
+        .filter(|entry| entry.file_name() == GITIGNORE_FILENAME)
+        .map(|entry| entry.into_path());
+    gitignores.extend(files.filter_map(|path| load_gitignore(&path)));
+    gitignores.sort_by_key(|gitignore| gitignore.path().components().count());
+    gitignores
+}
+
+/// Whether `entry` of a walk is `node_modules`, `.git` or another of [`IGNORED_DIRS`].
+fn is_ignored_dir(entry: &DirEntry) -> bool {
+    entry
+        .file_type()
+        .is_some_and(|file_type| file_type.is_dir())
+        && entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| IGNORED_DIRS.contains(&name))
+}
+
+/// Parse a `.gitignore` file, whose invalid lines are skipped.
+fn load_gitignore(path: &Path) -> Option<Gitignore> {
+    if !path.is_file() {
+        return None;
+    }
+    let mut builder = GitignoreBuilder::new(path.parent()?);
+    builder.add(path);
+    builder.build().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build_fs_tree::{dir, file, Build, MergeableFileSystemTree};
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn create_project() -> tempfile::TempDir {
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            ".git" => dir! {},
+            ".gitignore" => file!("*.log\n"),
+            "package.json" => file!("{}"),
+            "src" => dir! {
+                ".gitignore" => file!("generated.ts\n!keep.log\n"),
+                "index.ts" => file!(""),
+                "index.test.ts" => file!(""),
+            },
+            "packages" => dir! {
+                "app" => dir! {
+                    ".gitignore" => file!("dist\n"),
+                    "package.json" => file!("{}"),
+                    "src" => dir! {
+                        "index.ts" => file!(""),
+                    },
+                    "dist" => dir! {
+                        "index.js" => file!(""),
+                    },
+                    "node_modules" => dir! {
+                        "dep" => dir! {
+                            "index.js" => file!(""),
+                        },
+                    },
+                },
+            },
+        });
+        tree.build(&temp_dir).unwrap();
+        temp_dir
+    }
+
+    fn watched_paths(watcher: &Watcher, paths: &[&str]) -> Vec<String> {
+        paths
+            .iter()
+            .filter_map(|path| watcher.watched_path(&watcher.dir.join(path)))
+            .collect()
+    }
+
+    #[test]
+    fn test_watched_path() {
+        let temp_dir = create_project();
+        let app_dir = temp_dir.path().join("packages/app");
+        let paths = [
+            ".gitignore",
+            "package.json",
+            "src",
+            "src/index.ts",
+            "dist/index.js",
+            "node_modules/dep/index.js",
+            "debug.log",
+        ];
+        let watcher = Watcher::new(&app_dir, &[], &[]).unwrap();
+        assert_eq!(
+            watched_paths(&watcher, &paths),
+            [".gitignore", "package.json", "src/index.ts"],
+        );
+
+        let watcher = Watcher::new(&app_dir, &["src".to_string()], &[]).unwrap();
+        assert_eq!(watched_paths(&watcher, &paths), ["src/index.ts"]);
+
+        let paths = ["src/index.ts", "src/index.test.ts", "src/generated.ts"];
+        let watcher = Watcher::new(temp_dir.path(), &[], &["**/*.test.ts".to_string()]).unwrap();
+        assert_eq!(watched_paths(&watcher, &paths), ["src/index.ts"]);
+        assert_eq!(
+            watched_paths(&watcher, &["src/debug.log", "src/keep.log"]),
+            ["src/keep.log"],
+        );
+    }
+
+    #[test]
+    fn test_watched_dirs() {
+        let temp_dir = create_project();
+        let watcher = Watcher::new(temp_dir.path(), &[], &[]).unwrap();
+        let received: Vec<_> = watcher
+            .watched_dirs
+            .iter()
+            .map(|dir| dir.strip_prefix(&watcher.dir).unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            received,
+            ["", "packages", "packages/app", "packages/app/src", "src"]
+        );
+    }
+
+    #[test]
+    fn test_wait_for_changes() {
+        let temp_dir = create_project();
+        let app_dir = temp_dir.path().join("packages/app");
+        let mut watcher = Watcher::new(&app_dir, &[], &[]).unwrap();
+        fs::write(app_dir.join("dist/index.js"), "ignored").unwrap();
+        fs::write(app_dir.join("src/index.ts"), "changed").unwrap();
+        fs::write(app_dir.join("src/new.ts"), "created").unwrap();
+        fs::remove_file(app_dir.join("package.json")).unwrap();
+        let received = watcher.wait_for_changes().unwrap();
+        assert_eq!(received, ["package.json", "src/index.ts", "src/new.ts"]);
+
+        fs::create_dir_all(app_dir.join("src/lib/deep")).unwrap();
+        fs::write(app_dir.join("src/lib/deep/util.ts"), "created").unwrap();
+        fs::create_dir_all(app_dir.join("dist/chunks")).unwrap();
+        let received = watcher.wait_for_changes().unwrap();
+        assert_eq!(received, ["src/lib/deep/util.ts"]);
+        assert!(!watcher
+            .watched_dirs
+            .contains(&watcher.dir.join("dist/chunks")));
+
+        fs::write(app_dir.join("src/lib/deep/util.ts"), "changed").unwrap();
+        let received = watcher.wait_for_changes().unwrap();
+        assert_eq!(received, ["src/lib/deep/util.ts"]);
+    }
+}
@@ -1,20 +1,412 @@
 use super::error::{MainError, PnError};
-use lets_find_up::*;
-use std::path::{Path, PathBuf};
+use crate::{glob::Glob, task_graph::PnSection, utils::read_package_manifest, NodeManifest};
+use pipe_trait::Pipe;
+use serde::Deserialize;
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
 
-const WORKSPACE_MANIFEST_FILENAME: &str = "pnpm-workspace.yaml";
+pub const WORKSPACE_MANIFEST_FILENAME: &str = "pnpm-workspace.yaml";
 
-pub fn find_workspace_root(cwd: &Path) -> Result<PathBuf, MainError> {
-    let options = FindUpOptions {
-        kind: FindUpKind::File,
-        cwd,
+/// Directories that are never searched for workspace projects.
+const IGNORED_DIRS: &[&str] = &["node_modules", "bower_components", ".git"];
+
+/// Structure of `pnpm-workspace.yaml`.
+#[derive(Debug, Default, Clone, Deserialize, PartialEq)]
+pub struct WorkspaceManifest {
+    /// Globs of the project directories, `!` negates a glob.
+    #[serde(default)]
+    pub packages: Option<Vec<String>>,
+
+    /// Settings of `pn` itself.
+    #[serde(default)]
+    pub pn: Option<PnSection>,
+}
+
+/// A project of a workspace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkspaceProject {
+    pub dir: PathBuf,
+    pub manifest: NodeManifest,
+}
+
+/// Find the closest file named `file_name` in `start_dir` or one of its ancestors.
+pub fn find_up(start_dir: &Path, file_name: &'static str) -> Result<Option<PathBuf>, MainError> {
+    find_up_until(start_dir, file_name, None)
+}
+
+/// Find the closest file named `file_name` in `start_dir` or one of its ancestors, without looking
+/// above `stop_dir` when `start_dir` is inside it.
+pub fn find_up_until(
+    start_dir: &Path,
+    file_name: &'static str,
+    stop_dir: Option<&Path>,
+) -> Result<Option<PathBuf>, MainError> {
+    let stop_dir = stop_dir.filter(|stop_dir| start_dir.starts_with(stop_dir));
+    for dir in start_dir.ancestors() {
+        if stop_dir.is_some_and(|stop_dir| !dir.starts_with(stop_dir)) {
+            break;
+        }
+        let path = dir.join(file_name);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => return Ok(Some(path)),
+            Ok(_) => continue,
+            Err(error) if error.kind() == ErrorKind::NotFound => continue,
+            Err(error) => {
+                return Err(MainError::Pn(PnError::FindUpError {
+                    start_dir: start_dir.to_path_buf(),
+                    file_name,
+                    error,
+                }))
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Find the directory of the closest `package.json`, without leaving the workspace at
+/// `workspace_dir`.
+pub fn find_project_dir(
+    cwd: &Path,
+    workspace_dir: Option<&Path>,
+) -> Result<Option<PathBuf>, MainError> {
+    let dir = find_up_until(cwd, "package.json", workspace_dir)?
+        .and_then(|x| x.parent().map(Path::to_path_buf));
+    Ok(dir)
+}
+
+/// Find the directory of the closest `pnpm-workspace.yaml`, if any.
+pub fn find_workspace_dir(cwd: &Path) -> Result<Option<PathBuf>, MainError> {
+    let dir =
+        find_up(cwd, WORKSPACE_MANIFEST_FILENAME)?.and_then(|x| x.parent().map(Path::to_path_buf));
+    Ok(dir)
+}
+
+pub fn read_workspace_manifest(workspace_dir: &Path) -> Result<WorkspaceManifest, MainError> {
+    let manifest_path = workspace_dir.join(WORKSPACE_MANIFEST_FILENAME);
+    let content = fs::read_to_string(&manifest_path).map_err(|error| PnError::FsError {
+        path: manifest_path.clone(),
+        error,
+    })?;
+    if content.trim().is_empty() {
+        return Ok(WorkspaceManifest::default());
+    }
+    serde_yaml::from_str(&content)
+        .map_err(|error| PnError::ParseYamlError {
+            file: manifest_path,
+            message: error.to_string(),
+        })
+        .map_err(MainError::from)
+}
+
+/// List the root project and every project matched by the `packages` globs of the workspace.
+///
+/// The root project comes first, the others are sorted by directory.
+pub fn list_workspace_projects(workspace_dir: &Path) -> Result<Vec<WorkspaceProject>, MainError> {
+    let patterns = read_workspace_manifest(workspace_dir)?
+        .packages
+        .unwrap_or_else(|| vec!["**".to_string()]);
+    let (excludes, includes): (Vec<_>, Vec<_>) = patterns
+        .iter()
+        .map(String::as_str)
+        .partition(|pattern| pattern.starts_with('!'));
+    let includes = includes
+        .into_iter()
+        .map(Glob::new)
+        .collect::<Result<Vec<_>, _>>()?;
+    let excludes = excludes
+        .into_iter()
+        .map(|pattern| Glob::new(&pattern[1..]))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut dirs = Vec::new();
+    collect_project_dirs(workspace_dir, "", &includes, &excludes, &mut dirs)?;
+    dirs.sort();
+
+    let root = workspace_dir.to_path_buf();
+    let mut projects = Vec::with_capacity(dirs.len() + 1);
+    if root.join("package.json").is_file() {
+        projects.push(load_project(root)?);
+    }
+    for dir in dirs {
+        projects.push(load_project(dir)?);
+    }
+    Ok(projects)
+}
+
+fn load_project(dir: PathBuf) -> Result<WorkspaceProject, MainError> {
+    let manifest = dir
+        .join("package.json")
+        .pipe_as_ref(read_package_manifest)?;
+    Ok(WorkspaceProject { dir, manifest })
+}
+
+/// Recursively collect the subdirectories of `dir` that contain a `package.json` and match the globs.
+fn collect_project_dirs(
+    dir: &Path,
+    relative_dir: &str,
+    includes: &[Glob],
+    excludes: &[Glob],
+    dirs: &mut Vec<PathBuf>,
+) -> Result<(), MainError> {
+    let fs_error = |error| PnError::FsError {
+        path: dir.to_path_buf(),
+        error,
     };
-    find_up_with(WORKSPACE_MANIFEST_FILENAME, options)
-        .map_err(|error| PnError::FindUpError {
-            start_dir: cwd.to_path_buf(),
-            file_name: WORKSPACE_MANIFEST_FILENAME,
+    for entry in fs::read_dir(dir).map_err(fs_error)? {
+        let entry = entry.map_err(fs_error)?;
+        if !entry.file_type().map_err(fs_error)?.is_dir() {
+            continue;
+        }
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        if IGNORED_DIRS.contains(&file_name) {
+            continue;
+        }
+        let relative_path = if relative_dir.is_empty() {
+            file_name.to_string()
+        } else {
+            format!("{relative_dir}/{file_name}")
+        };
+        if !includes
+            .iter()
+            .any(|glob| glob.may_match_descendant(&relative_path))
+        {
+            continue;
+        }
+        let path = entry.path();
+        let is_included = includes.iter().any(|glob| glob.is_match(&relative_path))
+            && !excludes.iter().any(|glob| glob.is_match(&relative_path));
+        if is_included && path.join("package.json").is_file() {
+            dirs.push(path.clone());
+        }
+        collect_project_dirs(&path, &relative_path, includes, excludes, dirs)?;
+    }
+    Ok(())
+}
+
+/// Find the project of `projects` named `name`, or whose unscoped name is `name` when no project
+/// has that exact name, e.g. `web` for `@acme/web`.
+pub fn find_project_by_name<'a>(
+    projects: &'a [WorkspaceProject],
+    name: &str,
+) -> Result<&'a WorkspaceProject, PnError> {
+    let unscoped = |project: &&WorkspaceProject| {
+        project
+            .manifest
+            .name
+            .strip_prefix('@')
+            .and_then(|name| name.split_once('/'))
+            .is_some_and(|(_, unscoped)| unscoped == name)
+    };
+    let mut candidates: Vec<_> = projects
+        .iter()
+        .filter(|project| project.manifest.name == name)
+        .collect();
+    if candidates.is_empty() {
+        candidates = projects.iter().filter(unscoped).collect();
+    }
+    match candidates.as_slice() {
+        [project] => Ok(project),
+        [] => {
+            let mut close_matches: Vec<_> = projects
+                .iter()
+                .map(|project| project.manifest.name.as_str())
+                .filter(|candidate| !candidate.is_empty())
+                .map(|candidate| (strsim::jaro_winkler(name, candidate), candidate))
+                .filter(|(similarity, _)| *similarity >= 0.8)
+                .collect();
+            close_matches.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+            Err(PnError::UnknownPackage {
+                name: name.to_string(),
+                close_matches: close_matches
+                    .into_iter()
+                    .map(|(_, candidate)| candidate.to_string())
+                    .collect(),
+            })
+        }
+        candidates => Err(PnError::AmbiguousPackage {
+            name: name.to_string(),
+            candidates: candidates
+                .iter()
+                .map(|project| format!("{} ({})", project.manifest.name, project.dir.display()))
+                .collect(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use build_fs_tree::{dir, file, Build, MergeableFileSystemTree};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_list_workspace_projects() {
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            "package.json" => file!(r#"{"name": "root"}"#),
+            "pnpm-workspace.yaml" => file!("packages:\n  - 'packages/*'\n  - 'apps/**'\n  - '!apps/legacy'\n"),
+            "packages" => dir! {
+                "foo" => dir! {
+                    "package.json" => file!(r#"{"name": "foo"}"#),
+                    "node_modules" => dir! {
+                        "dep" => dir! {
+                            "package.json" => file!(r#"{"name": "dep"}"#),
+                        },
+                    },
+                },
+                "bar" => dir! {
+                    "package.json" => file!(r#"{"name": "bar"}"#),
+                    "nested" => dir! {
+                        "package.json" => file!(r#"{"name": "nested"}"#),
+                    },
+                },
+                "no-manifest" => dir! {},
+            },
+            "apps" => dir! {
+                "web" => dir! {
+                    "package.json" => file!(r#"{"name": "web"}"#),
+                },
+                "legacy" => dir! {
+                    "package.json" => file!(r#"{"name": "legacy"}"#),
+                },
+                "group" => dir! {
+                    "admin" => dir! {
+                        "package.json" => file!(r#"{"name": "admin"}"#),
+                    },
+                },
+            },
+            "other" => dir! {
+                "package.json" => file!(r#"{"name": "other"}"#),
+            },
+        });
+        tree.build(&temp_dir).unwrap();
+
+        let received: Vec<_> = list_workspace_projects(temp_dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|project| project.manifest.name)
+            .collect();
+        dbg!(&received);
+        assert_eq!(received, ["root", "admin", "web", "bar", "foo"]);
+    }
+
+    #[test]
+    fn test_list_workspace_projects_without_packages_field() {
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            "pnpm-workspace.yaml" => file!(""),
+            "a" => dir! {
+                "package.json" => file!(r#"{"name": "a"}"#),
+                "b" => dir! {
+                    "package.json" => file!(r#"{"name": "b"}"#),
+                },
+            },
+        });
+        tree.build(&temp_dir).unwrap();
+
+        let received: Vec<_> = list_workspace_projects(temp_dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|project| project.manifest.name)
+            .collect();
+        dbg!(&received);
+        assert_eq!(received, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_find_project_dir() {
+        let temp_dir = tempdir().unwrap();
+        let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+            "outer" => dir! {
+                "package.json" => file!(r#"{"name": "outer"}"#),
+                "workspace" => dir! {
+                    "pnpm-workspace.yaml" => file!("packages: ['packages/*']\n"),
+                    "packages" => dir! {
+                        "foo" => dir! {
+                            "package.json" => file!(r#"{"name": "foo"}"#),
+                            "src" => dir! {
+                                "components" => dir! {},
+                            },
+                        },
+                    },
+                },
+            },
+        });
+        tree.build(&temp_dir).unwrap();
+        let outer = temp_dir.path().join("outer");
+        let workspace_dir = outer.join("workspace");
+        let foo = workspace_dir.join("packages/foo");
+
+        let received = find_project_dir(&foo.join("src/components"), Some(&workspace_dir)).unwrap();
+        assert_eq!(received, Some(foo.clone()));
+
+        let received = find_project_dir(&foo, Some(&workspace_dir)).unwrap();
+        assert_eq!(received, Some(foo));
+
+        let received =
+            find_project_dir(&workspace_dir.join("packages"), Some(&workspace_dir)).unwrap();
+        assert_eq!(received, None);
+
+        let received = find_project_dir(&workspace_dir.join("packages"), None).unwrap();
+        assert_eq!(received, Some(outer));
+    }
+
+    #[test]
+    fn test_find_project_by_name() {
+        let projects: Vec<_> = [
+            ("packages/web", "@acme/web"),
+            ("packages/api", "@acme/api"),
+            ("apps/api", "@other/api"),
+            ("apps/docs", "docs"),
+        ]
+        .into_iter()
+        .map(|(dir, name)| WorkspaceProject {
+            dir: PathBuf::from(dir),
+            manifest: NodeManifest {
+                name: name.to_string(),
+                ..NodeManifest::default()
+            },
+        })
+        .collect();
+
+        let received = find_project_by_name(&projects, "@acme/web").unwrap();
+        assert_eq!(received.dir, Path::new("packages/web"));
+
+        let received = find_project_by_name(&projects, "web").unwrap();
+        assert_eq!(received.dir, Path::new("packages/web"));
+
+        let received = find_project_by_name(&projects, "@other/api").unwrap();
+        assert_eq!(received.dir, Path::new("apps/api"));
+
+        let error = find_project_by_name(&projects, "api").unwrap_err();
+        dbg!(&error);
+        assert!(matches!(
             error,
-        })?
-        .and_then(|x| x.parent().map(Path::to_path_buf))
-        .ok_or(MainError::Pn(PnError::NotInWorkspace))
+            PnError::AmbiguousPackage { candidates, .. } if candidates.len() == 2,
+        ));
+
+        let error = find_project_by_name(&projects, "doc").unwrap_err();
+        dbg!(&error);
+        assert!(matches!(
+            &error,
+            PnError::UnknownPackage { close_matches, .. } if close_matches == &["docs"],
+        ));
+        assert_eq!(
+            error.to_string(),
+            r#"No package named "doc" in the workspace, did you mean docs?"#,
+        );
+
+        let error = find_project_by_name(&projects, "zzz").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            r#"No package named "zzz" in the workspace"#
+        );
+    }
 }
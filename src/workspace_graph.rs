@@ -0,0 +1,439 @@
+use crate::{utils::normalize_path, workspace::WorkspaceProject};
+use semver::{Version, VersionReq};
+use std::{collections::HashMap, mem, path::Path};
+
+/// Dependency graph of the projects of a workspace.
+///
+/// Projects are referred to by their index in [`WorkspaceGraph::projects`].
+#[derive(Debug, Clone)]
+pub struct WorkspaceGraph {
+    projects: Vec<WorkspaceProject>,
+    dependencies: Vec<Vec<usize>>,
+}
+
+/// Result of [`WorkspaceGraph::sort`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortedProjects {
+    /// Indices of projects, every project comes after its dependencies unless they form a cycle.
+    pub order: Vec<usize>,
+    /// Groups of projects that depend on each other.
+    pub cycles: Vec<Vec<usize>>,
+}
+
+impl WorkspaceGraph {
+    /// Link every project to the other projects it depends on.
+    pub fn new(projects: Vec<WorkspaceProject>) -> Self {
+        let mut by_name = HashMap::<&str, usize>::new();
+        for (index, project) in projects.iter().enumerate() {
+            by_name
+                .entry(project.manifest.name.as_str())
+                .or_insert(index);
+        }
+        let dependencies = projects
+            .iter()
+            .enumerate()
+            .map(|(index, project)| {
+                let mut dependencies: Vec<_> = project
+                    .manifest
+                    .all_dependencies()
+                    .filter_map(|(name, spec)| {
+                        resolve_dependency(&projects, &by_name, &project.dir, name, spec)
+                    })
+                    .filter(|dependency| *dependency != index)
+                    .collect();
+                dependencies.sort_unstable();
+                dependencies.dedup();
+                dependencies
+            })
+            .collect();
+        WorkspaceGraph {
+            projects,
+            dependencies,
+        }
+    }
+
+    pub fn projects(&self) -> &[WorkspaceProject] {
+        &self.projects
+    }
+
+    /// Indices of the workspace projects that the project at `index` depends on.
+    pub fn dependencies_of(&self, index: usize) -> &[usize] {
+        &self.dependencies[index]
+    }
+
+    /// Indices of the workspace projects that depend on the project at `index`.
+    pub fn dependents_of(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        self.dependencies
+            .iter()
+            .enumerate()
+            .filter(move |(_, dependencies)| dependencies.contains(&index))
+            .map(|(dependent, _)| dependent)
+    }
+
+    /// For each project of `order`, list the positions in `order` of the projects it must wait for.
+    ///
+    /// These are its dependencies, or its dependents when `reverse` is set, that come earlier in
+    /// `order`. Later ones, which only exist in cycles, are ignored so that cycles cannot deadlock.
+    pub fn waits_for(&self, order: &[usize], reverse: bool) -> Vec<Vec<usize>> {
+        let position = |node: usize| order.iter().position(|&x| x == node);
+        order
+            .iter()
+            .enumerate()
+            .map(|(current, &node)| {
+                let neighbors: Vec<usize> = if reverse {
+                    self.dependents_of(node).collect()
+                } else {
+                    self.dependencies_of(node).to_vec()
+                };
+                neighbors
+                    .into_iter()
+                    .filter_map(position)
+                    .filter(|&other| other < current)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Sort the `selected` projects so that dependencies come first.
+    ///
+    /// Only the dependencies between selected projects are considered. Projects that are not
+    /// ordered by a dependency keep their relative order in `selected`.
+    pub fn sort(&self, selected: &[usize]) -> SortedProjects {
+        let mut tarjan = Tarjan {
+            graph: self,
+            selected,
+            state: HashMap::new(),
+            stack: Vec::new(),
+            next_index: 0,
+            components: Vec::new(),
+        };
+        for &node in selected {
+            if !tarjan.state.contains_key(&node) {
+                tarjan.visit(node);
+            }
+        }
+        let position = |node: &usize| selected.iter().position(|x| x == node);
+        let mut components = tarjan.components;
+        for component in &mut components {
+            component.sort_by_key(position);
+        }
+        let cycles = components
+            .iter()
+            .filter(|component| component.len() > 1)
+            .cloned()
+            .collect();
+
+        // Emit the earliest component whose dependencies were all emitted.
+        // The graph of components is acyclic, so there is always one.
+        let mut order = Vec::with_capacity(selected.len());
+        while !components.is_empty() {
+            let is_ready = |component: &Vec<usize>| {
+                component.iter().all(|&node| {
+                    self.dependencies_of(node).iter().all(|dependency| {
+                        !selected.contains(dependency)
+                            || component.contains(dependency)
+                            || order.contains(dependency)
+                    })
+                })
+            };
+            let (ready, _) = components
+                .iter()
+                .enumerate()
+                .filter(|(_, component)| is_ready(component))
+                .min_by_key(|(_, component)| position(&component[0]))
+                .expect("the dependencies of the first component were emitted");
+            order.extend(components.remove(ready));
+        }
+        SortedProjects { order, cycles }
+    }
+}
+
+/// Tarjan's strongly connected components algorithm, which groups the projects of a cycle together.
+struct Tarjan<'a> {
+    graph: &'a WorkspaceGraph,
+    selected: &'a [usize],
+    /// Visit index and low link of each visited node, and whether it is still on the stack.
+    state: HashMap<usize, (usize, usize, bool)>,
+    stack: Vec<usize>,
+    next_index: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, node: usize) {
+        let index = self.next_index;
+        self.next_index += 1;
+        self.state.insert(node, (index, index, true));
+        self.stack.push(node);
+        for &dependency in self.graph.dependencies_of(node) {
+            if !self.selected.contains(&dependency) {
+                continue;
+            }
+            match self.state.get(&dependency).copied() {
+                None => {
+                    self.visit(dependency);
+                    let dependency_low = self.state[&dependency].1;
+                    let (_, low, _) = self.state.get_mut(&node).unwrap();
+                    *low = (*low).min(dependency_low);
+                }
+                Some((dependency_index, _, true)) => {
+                    let (_, low, _) = self.state.get_mut(&node).unwrap();
+                    *low = (*low).min(dependency_index);
+                }
+                Some((_, _, false)) => {}
+            }
+        }
+        let (index, low, _) = self.state[&node];
+        if index != low {
+            return;
+        }
+        let mut component = Vec::new();
+        while let Some(member) = self.stack.pop() {
+            self.state.get_mut(&member).unwrap().2 = false;
+            component.push(member);
+            if member == node {
+                break;
+            }
+        }
+        self.components.push(component);
+    }
+}
+
+/// Find the workspace project that satisfies the dependency `name` with version spec `spec`.
+fn resolve_dependency(
+    projects: &[WorkspaceProject],
+    by_name: &HashMap<&str, usize>,
+    dependent_dir: &Path,
+    name: &str,
+    spec: &str,
+) -> Option<usize> {
+    let find_by_dir = |relative: &str| {
+        let dir = normalize_path(&dependent_dir.join(relative));
+        projects
+            .iter()
+            .position(|project| normalize_path(&project.dir) == dir)
+    };
+    if let Some(spec) = spec.strip_prefix("workspace:") {
+        if spec.starts_with('.') || spec.starts_with('/') {
+            return find_by_dir(spec);
+        }
+        let name = split_alias(spec).map_or(name, |(alias, _)| alias);
+        return by_name.get(name).copied();
+    }
+    if let Some(path) = spec.strip_prefix("link:") {
+        return find_by_dir(path);
+    }
+    let index = *by_name.get(name)?;
+    satisfies(&projects[index].manifest.version, spec).then_some(index)
+}
+
+/// Whether `version` satisfies the npm range `range`, such as `^1.2.0 || >=2.1.0 <3`.
+fn satisfies(version: &str, range: &str) -> bool {
+    let Ok(version) = Version::parse(version.trim_start_matches(['v', '='])) else {
+        return false;
+    };
+    range
+        .split("||")
+        .any(|set| parse_comparator_set(set).is_some_and(|req| req.matches(&version)))
+}
+
+/// Convert a whitespace-separated npm comparator set, or a hyphen range, into a [`VersionReq`].
+///
+/// Unlike Cargo, npm treats a bare version as an exact one.
+fn parse_comparator_set(set: &str) -> Option<VersionReq> {
+    let tokens: Vec<_> = set.split_whitespace().collect();
+    let comparators = match tokens[..] {
+        [] => vec!["*".to_string()],
+        [low, "-", high] => vec![
+            format!(">={}", low.trim_start_matches('v')),
+            format!("<={}", high.trim_start_matches('v')),
+        ],
+        _ => {
+            let mut comparators = Vec::with_capacity(tokens.len());
+            let mut operator = "";
+            for token in tokens {
+                if token.chars().all(|char| "<>=~^".contains(char)) {
+                    operator = token; // separated from its version, as in `>= 1.2.3`
+                    continue;
+                }
+                let token = token.trim_start_matches('v');
+                let operator = match mem::take(&mut operator) {
+                    "" if token.starts_with(|char: char| char.is_ascii_digit()) => "=",
+                    operator => operator,
+                };
+                comparators.push(format!("{operator}{token}"));
+            }
+            comparators
+        }
+    };
+    VersionReq::parse(&comparators.join(", ")).ok()
+}
+
+/// Split an aliased spec such as `@scope/foo@^1.0.0` into name and range.
+fn split_alias(spec: &str) -> Option<(&str, &str)> {
+    let at = spec.get(1..)?.find('@')? + 1;
+    Some((&spec[..at], &spec[at + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NodeManifest;
+    use pretty_assertions::assert_eq;
+
+    fn project(name: &str, version: &str, dependencies: &[(&str, &str)]) -> WorkspaceProject {
+        WorkspaceProject {
+            dir: Path::new("/workspace/packages").join(name),
+            manifest: NodeManifest {
+                name: name.to_string(),
+                version: version.to_string(),
+                dependencies: dependencies
+                    .iter()
+                    .map(|(name, spec)| (name.to_string(), spec.to_string()))
+                    .collect(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_satisfies() {
+        let cases = [
+            ("1.2.3", "1.2.3", true),
+            ("1.2.3", "=1.2.3", true),
+            ("1.2.3", "v1.2.3", true),
+            ("1.2.4", "1.2.3", false),
+            ("1.2.3", "*", true),
+            ("1.2.3", "", true),
+            ("1.2.3", "1.x", true),
+            ("2.0.0", "1.x", false),
+            ("1.2.3", "1.2", true),
+            ("1.3.0", "1.2", false),
+            ("1.9.9", "^1.2.3", true),
+            ("2.0.0", "^1.2.3", false),
+            ("0.3.0", "^0.2.3", false),
+            ("0.0.4", "^0.0.3", false),
+            ("1.2.9", "~1.2.3", true),
+            ("1.3.0", "~1.2.3", false),
+            ("1.5.0", ">=1.2.3 <2.0.0", true),
+            ("2.0.0", ">=1.2.3 <2.0.0", false),
+            ("1.5.0", ">= 1.2.3", true),
+            ("3.0.0", "^1.0.0 || ^3.0.0", true),
+            ("2.0.0", "^1.0.0 || ^3.0.0", false),
+            ("1.5.0", "1.2.3 - 1.6", true),
+            ("1.7.0", "1.2.3 - 1.6", false),
+            ("1.9.0", ">1", false),
+            ("1.0.0-beta.2", "1.0.0-beta.10", false),
+            ("1.0.0-beta.2", "<1.0.0", false),
+            ("not-a-version", "*", false),
+            ("1.0.0", "not a range", false),
+        ];
+        for (version, range, expected) in cases {
+            eprintln!("version={version:?} range={range:?}");
+            assert_eq!(satisfies(version, range), expected);
+        }
+    }
+
+    #[test]
+    fn test_resolve_dependencies() {
+        let mut app = project("app", "1.0.0", &[]);
+        app.manifest.dependencies = [
+            ("lib", "workspace:*"),
+            ("utils-alias", "workspace:utils@^2.0.0"),
+            ("external", "^1.0.0"),
+        ]
+        .iter()
+        .map(|(name, spec)| (name.to_string(), spec.to_string()))
+        .collect();
+        app.manifest.dev_dependencies = [("config", "workspace:../config")]
+            .iter()
+            .map(|(name, spec)| (name.to_string(), spec.to_string()))
+            .collect();
+        app.manifest.peer_dependencies = [("core", "^3.0.0"), ("old", "^1.0.0")]
+            .iter()
+            .map(|(name, spec)| (name.to_string(), spec.to_string()))
+            .collect();
+        let graph = WorkspaceGraph::new(vec![
+            app,
+            project("lib", "1.0.0", &[]),
+            project("utils", "2.1.0", &[]),
+            project("config", "0.0.0", &[]),
+            project("core", "3.4.5", &[]),
+            project("old", "2.0.0", &[]),
+        ]);
+        let received = graph.dependencies_of(0);
+        dbg!(received);
+        assert_eq!(received, [1, 2, 3, 4]);
+        assert_eq!(graph.dependents_of(1).collect::<Vec<_>>(), [0]);
+    }
+
+    #[test]
+    fn test_sort() {
+        let graph = WorkspaceGraph::new(vec![
+            project(
+                "app",
+                "1.0.0",
+                &[("lib", "workspace:*"), ("ui", "workspace:*")],
+            ),
+            project("lib", "1.0.0", &[("utils", "workspace:*")]),
+            project("standalone", "1.0.0", &[]),
+            project("ui", "1.0.0", &[("utils", "workspace:*")]),
+            project("utils", "1.0.0", &[]),
+        ]);
+        let received = graph.sort(&[0, 1, 2, 3, 4]);
+        dbg!(&received);
+        assert_eq!(
+            received,
+            SortedProjects {
+                order: vec![2, 4, 1, 3, 0],
+                cycles: Vec::new(),
+            },
+        );
+
+        let received = graph.sort(&[0, 2, 4]);
+        dbg!(&received);
+        assert_eq!(received.order, [0, 2, 4]);
+    }
+
+    #[test]
+    fn test_waits_for() {
+        let graph = WorkspaceGraph::new(vec![
+            project(
+                "app",
+                "1.0.0",
+                &[("lib", "workspace:*"), ("ui", "workspace:*")],
+            ),
+            project("lib", "1.0.0", &[("ui", "workspace:*")]),
+            project("ui", "1.0.0", &[("lib", "workspace:*")]),
+            project("docs", "1.0.0", &[]),
+        ]);
+        let order = [3, 1, 2, 0];
+        let received = graph.waits_for(&order, false);
+        dbg!(&received);
+        assert_eq!(received, [vec![], vec![], vec![1], vec![1, 2]]);
+
+        let order = [0, 2, 1, 3];
+        let received = graph.waits_for(&order, true);
+        dbg!(&received);
+        assert_eq!(received, [vec![], vec![0], vec![0, 1], vec![]]);
+    }
+
+    #[test]
+    fn test_sort_cycle() {
+        let graph = WorkspaceGraph::new(vec![
+            project("a", "1.0.0", &[("b", "workspace:*")]),
+            project("b", "1.0.0", &[("c", "workspace:*")]),
+            project("c", "1.0.0", &[("a", "workspace:*"), ("d", "workspace:*")]),
+            project("d", "1.0.0", &[]),
+        ]);
+        let received = graph.sort(&[0, 1, 2, 3]);
+        dbg!(&received);
+        assert_eq!(
+            received,
+            SortedProjects {
+                order: vec![3, 0, 1, 2],
+                cycles: vec![vec![0, 1, 2]],
+            },
+        );
+    }
+}
@@ -0,0 +1,13 @@
+#!/bin/sh
+set -o errexit -o nounset
+echo "npm_package_name=$npm_package_name"
+echo "npm_package_version=$npm_package_version"
+echo "npm_package_json=$npm_package_json"
+echo "npm_lifecycle_event=$npm_lifecycle_event"
+echo "npm_lifecycle_script=$npm_lifecycle_script"
+echo "npm_config_user_agent=$npm_config_user_agent"
+echo "INIT_CWD=$INIT_CWD"
+echo "PNPM_SCRIPT_SRC_DIR=$PNPM_SCRIPT_SRC_DIR"
+echo "npm_command=$npm_command"
+echo "npm_execpath=$npm_execpath"
+echo "npm_node_execpath=$npm_node_execpath"
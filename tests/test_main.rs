@@ -198,3 +198,2011 @@ fn list_no_script_names() {
         .success()
         .stdout("There are no scripts in package.json\n");
 }
+
+#[test]
+fn run_pre_post_scripts() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(include_str!("fixtures/pre-post-scripts/package.json")),
+        ".npmrc" => file!("enable-pre-post-scripts=true\n"),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "build", "prod"])
+        .assert()
+        .success()
+        .stdout("prebuild\nbuild prod\npostbuild\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .arg("build")
+        .assert()
+        .success()
+        .stdout("prebuild\nbuild\npostbuild\n");
+}
+
+#[test]
+fn pre_post_scripts_disabled_by_default() {
+    let temp_dir = tempdir().unwrap();
+    fs::write(
+        temp_dir.path().join("package.json"),
+        include_str!("fixtures/pre-post-scripts/package.json"),
+    )
+    .unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "build"])
+        .assert()
+        .success()
+        .stdout("build\n");
+}
+
+#[test]
+fn pre_post_scripts_enabled_by_workspace_manifest() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(include_str!("fixtures/pre-post-scripts/package.json")),
+        "pnpm-workspace.yaml" => file!("enablePrePostScripts: true\n"),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "build"])
+        .assert()
+        .success()
+        .stdout("prebuild\nbuild\npostbuild\n");
+}
+
+#[test]
+fn failing_pre_script_stops_chain() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"pretest": "exit 3", "test": "echo test", "posttest": "echo posttest"}}"#),
+        ".npmrc" => file!("enable-pre-post-scripts=true\n"),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "test"])
+        .assert()
+        .failure()
+        .stdout("");
+    let output = assertion.get_output();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains(r#"Command "pretest" failed with exit code 3"#));
+}
+
+#[cfg(unix)]
+#[test]
+fn script_env() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(include_str!("fixtures/script-env/package.json")),
+        "print-env.sh" => file!(include_str!("fixtures/script-env/print-env.sh")),
+    });
+    tree.build(&temp_dir).unwrap();
+    // a `pnpm` package and a `node`, found first in `PATH`
+    let tools_dir = tempdir().unwrap();
+    let tools = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "pnpm" => dir! {
+            "package.json" => file!(r#"{"name": "pnpm", "version": "9.1.0"}"#),
+            "bin" => dir! {
+                "pnpm.cjs" => file!(""),
+            },
+        },
+        "bin" => dir! {
+            "node" => file!("#!/bin/sh\n"),
+        },
+    });
+    tools.build(&tools_dir).unwrap();
+    let tools_dir = dunce::canonicalize(tools_dir.path()).unwrap();
+    let bin_dir = tools_dir.join("bin");
+    fs::set_permissions(bin_dir.join("node"), fs::Permissions::from_mode(0o755)).unwrap();
+    std::os::unix::fs::symlink("../pnpm/bin/pnpm.cjs", bin_dir.join("pnpm")).unwrap();
+    let path_env = std::env::var_os("PATH").unwrap();
+    let path_env = std::env::join_paths(
+        [bin_dir.clone()]
+            .into_iter()
+            .chain(std::env::split_paths(&path_env)),
+    )
+    .unwrap();
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .env_remove("INIT_CWD")
+        .env("PATH", path_env)
+        .args(["run", "print-env", "foo"])
+        .assert()
+        .success();
+    let output = assertion.get_output();
+    let received = String::from_utf8_lossy(&output.stdout);
+    eprintln!("STDOUT:\n{received}\n");
+    let dir = temp_dir.path().display();
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "x64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    let platform = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    let user_agent = format!("pnpm/9.1.0 npm/? node/? {platform} {arch}");
+    let expected = [
+        "npm_package_name=script-env".to_string(),
+        "npm_package_version=1.2.3".to_string(),
+        format!("npm_package_json={dir}/package.json"),
+        "npm_lifecycle_event=print-env".to_string(),
+        "npm_lifecycle_script=sh print-env.sh 'foo'".to_string(),
+        format!("npm_config_user_agent={user_agent}"),
+        format!("INIT_CWD={dir}"),
+        format!("PNPM_SCRIPT_SRC_DIR={dir}"),
+        "npm_command=run-script".to_string(),
+        format!(
+            "npm_execpath={}",
+            tools_dir.join("pnpm/bin/pnpm.cjs").display()
+        ),
+        format!("npm_node_execpath={}", bin_dir.join("node").display()),
+    ]
+    .join("\n");
+    assert_eq!(received.trim(), expected);
+}
+
+#[test]
+fn run_recursive() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "root", "scripts": {"test": "echo test root"}}"#),
+        "pnpm-workspace.yaml" => file!("packages:\n  - 'packages/*'\n  - '!packages/ignored'\nworkspaceConcurrency: 1\n"),
+        "packages" => dir! {
+            "foo" => dir! {
+                "package.json" => file!(r#"{"name": "foo", "scripts": {"test": "echo test foo"}}"#),
+            },
+            "bar" => dir! {
+                "package.json" => file!(r#"{"name": "bar", "scripts": {"test": "echo test bar"}}"#),
+            },
+            "baz" => dir! {
+                "package.json" => file!(r#"{"name": "baz", "scripts": {"build": "echo build baz"}}"#),
+            },
+            "ignored" => dir! {
+                "package.json" => file!(r#"{"name": "ignored", "scripts": {"test": "echo test ignored"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(temp_dir.path().join("packages/foo"))
+        .args(["-r", "run", "test", "arg"])
+        .assert()
+        .success()
+        .stdout("test bar arg\ntest foo arg\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "--recursive", "test"])
+        .assert()
+        .success()
+        .stdout("test bar\ntest foo\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--recursive", "build"])
+        .assert()
+        .success()
+        .stdout("build baz\n");
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "lint"])
+        .assert()
+        .failure();
+    let output = assertion.get_output();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains(r#"None of the selected packages has a "lint" script"#));
+}
+
+#[test]
+fn run_recursive_include_workspace_root() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "root", "scripts": {"test": "echo test root"}}"#),
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\nincludeWorkspaceRoot: true\nworkspaceConcurrency: 1\n"),
+        "packages" => dir! {
+            "foo" => dir! {
+                "package.json" => file!(r#"{"name": "foo", "scripts": {"test": "echo test foo"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "test"])
+        .assert()
+        .success()
+        .stdout("test root\ntest foo\n");
+}
+
+#[test]
+fn run_recursive_outside_workspace_error() {
+    let temp_dir = tempdir().unwrap();
+    fs::write(
+        temp_dir.path().join("package.json"),
+        r#"{"scripts": {"test": "echo hello world"}}"#,
+    )
+    .unwrap();
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "test"])
+        .assert()
+        .failure();
+    let output = assertion.get_output();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains("--recursive may only be used in a workspace"));
+}
+
+#[test]
+fn run_recursive_topological_order() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\nworkspaceConcurrency: 1\n"),
+        "packages" => dir! {
+            "app" => dir! {
+                "package.json" => file!(r#"{"name": "app", "version": "1.0.0", "dependencies": {"lib": "workspace:*"}, "scripts": {"build": "echo build app"}}"#),
+            },
+            "lib" => dir! {
+                "package.json" => file!(r#"{"name": "lib", "version": "1.0.0", "devDependencies": {"utils": "^2.0.0"}, "scripts": {"build": "echo build lib"}}"#),
+            },
+            "utils" => dir! {
+                "package.json" => file!(r#"{"name": "utils", "version": "2.1.0", "scripts": {"build": "echo build utils"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "build"])
+        .assert()
+        .success()
+        .stdout("build utils\nbuild lib\nbuild app\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "--reverse", "run", "build"])
+        .assert()
+        .success()
+        .stdout("build app\nbuild lib\nbuild utils\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "--no-sort", "run", "build"])
+        .assert()
+        .success()
+        .stdout("build app\nbuild lib\nbuild utils\n");
+}
+
+#[test]
+fn run_recursive_cyclic_dependencies() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\nworkspaceConcurrency: 1\n"),
+        "packages" => dir! {
+            "a" => dir! {
+                "package.json" => file!(r#"{"name": "a", "dependencies": {"b": "workspace:*"}, "scripts": {"build": "echo build a"}}"#),
+            },
+            "b" => dir! {
+                "package.json" => file!(r#"{"name": "b", "dependencies": {"a": "workspace:*"}, "scripts": {"build": "echo build b"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "build"])
+        .assert()
+        .success()
+        .stdout("build a\nbuild b\n");
+    let output = assertion.get_output();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains("There are cyclic workspace dependencies: a, b"));
+}
+
+#[test]
+fn run_recursive_concurrency() {
+    // each script only succeeds if the other one runs at the same time
+    let wait_for = |own: &str, other: &str| {
+        format!(
+            "touch ../{own}.ready; i=0; while [ ! -f ../{other}.ready ] && [ $i -lt 30 ]; do sleep 0.1; i=$((i+1)); done; test -f ../{other}.ready",
+        )
+    };
+    let temp_dir = tempdir().unwrap();
+    fs::write(
+        temp_dir.path().join("pnpm-workspace.yaml"),
+        "packages: ['packages/*']\nworkspaceConcurrency: 1\n",
+    )
+    .unwrap();
+    for (own, other) in [("a", "b"), ("b", "a")] {
+        let dir = temp_dir.path().join("packages").join(own);
+        fs::create_dir_all(&dir).unwrap();
+        let manifest = json!({
+            "name": own,
+            "scripts": { "wait": wait_for(own, other) },
+        });
+        fs::write(dir.join("package.json"), manifest.to_string()).unwrap();
+    }
+    let clean = || {
+        for name in ["a", "b"] {
+            let flag = temp_dir
+                .path()
+                .join("packages")
+                .join(format!("{name}.ready"));
+            if flag.exists() {
+                fs::remove_file(flag).unwrap();
+            }
+        }
+    };
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "wait"])
+        .assert()
+        .failure();
+    let b_started = temp_dir.path().join("packages/b.ready").exists();
+    dbg!(b_started);
+    assert!(!b_started);
+    clean();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "--workspace-concurrency=2", "run", "wait"])
+        .assert()
+        .success();
+    clean();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "--parallel", "run", "wait"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn run_recursive_prefixed_output() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\nworkspaceConcurrency: 2\n"),
+        "packages" => dir! {
+            "api" => dir! {
+                "package.json" => file!(r#"{"name": "api", "scripts": {"build": "echo api out; echo api err >&2; printf partial"}}"#),
+            },
+            "web" => dir! {
+                "package.json" => file!(r#"{"name": "web", "scripts": {"build": "echo web out"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "build"])
+        .assert()
+        .success();
+    let output = assertion.get_output();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDOUT:\n{stdout}\nSTDERR:\n{stderr}\n");
+    let has_line = |output: &str, prefix: &str, text: &str| {
+        output
+            .lines()
+            .any(|line| line.contains(&format!("{prefix} build$")) && line.ends_with(text))
+    };
+    assert_eq!(stdout.lines().count(), 3);
+    assert!(has_line(&stdout, "packages/api", "api out"));
+    assert!(has_line(&stdout, "packages/api", "partial"));
+    assert!(has_line(&stdout, "packages/web", "web out"));
+    assert!(has_line(&stderr, "packages/api", "api err"));
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "--reporter-hide-prefix", "run", "build"])
+        .assert()
+        .success();
+    let output = assertion.get_output();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    eprintln!("STDOUT:\n{stdout}\n");
+    let mut stdout_lines: Vec<_> = stdout.lines().collect();
+    stdout_lines.sort();
+    assert_eq!(stdout_lines, ["api out", "partial", "web out"]);
+}
+
+#[test]
+fn run_recursive_aggregate_output() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\nworkspaceConcurrency: 2\n"),
+        "packages" => dir! {
+            "a" => dir! {
+                "package.json" => file!(r#"{"name": "a", "scripts": {"build": "echo a1; sleep 0.5; echo a2", "fail": "echo a failed; sleep 0.5; exit 3"}}"#),
+            },
+            "b" => dir! {
+                "package.json" => file!(r#"{"name": "b", "scripts": {"build": "sleep 0.1; echo b1", "fail": "echo b done"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "--reporter-hide-prefix", "run", "build"])
+        .assert()
+        .success()
+        .stdout("a1\nb1\na2\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args([
+            "-r",
+            "--aggregate-output",
+            "--reporter-hide-prefix",
+            "run",
+            "build",
+        ])
+        .assert()
+        .success()
+        .stdout("b1\na1\na2\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args([
+            "-r",
+            "--aggregate-output",
+            "--reporter-hide-prefix",
+            "run",
+            "fail",
+        ])
+        .assert()
+        .failure()
+        .stdout("b done\na failed\n");
+}
+
+#[test]
+fn run_script_regex() {
+    let temp_dir = tempdir().unwrap();
+    fs::write(
+        temp_dir.path().join("package.json"),
+        r#"{"name": "app", "scripts": {"build:a": "echo build a", "build:b": "echo build b", "lint": "echo lint"}}"#,
+    )
+    .unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--workspace-concurrency=1", "run", "/^build:/"])
+        .assert()
+        .success()
+        .stdout("build a\nbuild b\n");
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--workspace-concurrency=2", "run", "/^build:/"])
+        .assert()
+        .success();
+    let output = assertion.get_output();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    eprintln!("STDOUT:\n{stdout}\n");
+    for name in ["a", "b"] {
+        assert!(stdout
+            .lines()
+            .any(|line| line.contains(&format!("app build:{name}$"))
+                && line.ends_with(&format!("build {name}"))));
+    }
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "/int$/"])
+        .assert()
+        .success()
+        .stdout("lint\n");
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "/^test/"])
+        .assert()
+        .failure();
+    let output = assertion.get_output();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains("No script matches /^test/"));
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "/build(/"])
+        .assert()
+        .failure();
+    let output = assertion.get_output();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains("Invalid regular expression /build(/"));
+}
+
+#[test]
+fn run_script_options_after_name() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"test": "echo test"}}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    // the options of `pn run` go before the name of the script, the ones after it are the script's
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args([
+            "run",
+            "test",
+            "--watch",
+            "--if-present",
+            "--retry",
+            "2",
+            "-x",
+        ])
+        .assert()
+        .success()
+        .stdout("test --watch --if-present --retry 2 -x\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "--if-present", "missing", "--watch"])
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn run_if_present() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "root", "scripts": {}}"#),
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\n"),
+        "packages" => dir! {
+            "foo" => dir! {
+                "package.json" => file!(r#"{"name": "foo", "scripts": {"test": "echo test foo"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "--if-present", "missing"])
+        .assert()
+        .success()
+        .stdout("");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "--if-present", "/^missing/"])
+        .assert()
+        .success()
+        .stdout("");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "--if-present", "missing"])
+        .assert()
+        .success()
+        .stdout("");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "--if-present", "test"])
+        .assert()
+        .success()
+        .stdout("test foo\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "missing"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn run_recursive_no_bail() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\nworkspaceConcurrency: 1\n"),
+        "packages" => dir! {
+            "a" => dir! {
+                "package.json" => file!(r#"{"name": "a", "scripts": {"build": "echo build a; exit 3"}}"#),
+            },
+            "b" => dir! {
+                "package.json" => file!(r#"{"name": "b", "scripts": {"build": "echo build b"}}"#),
+            },
+            "c" => dir! {
+                "package.json" => file!(r#"{"name": "c", "scripts": {"build": "echo build c; exit 4"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "build"])
+        .assert()
+        .failure()
+        .stdout("build a\n");
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "run", "--no-bail", "build"])
+        .assert()
+        .failure()
+        .stdout("build a\nbuild b\nbuild c\n");
+    let output = assertion.get_output();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains("The script failed in 2 of the selected packages"));
+    assert!(stderr.contains(r#"a: Command "build" failed with exit code 3"#));
+    assert!(stderr.contains(r#"c: Command "build" failed with exit code 4"#));
+}
+
+#[cfg(unix)]
+#[test]
+fn shell_emulator_conformance() {
+    let scripts = [
+        "echo hello world",
+        r#"echo "a  b" 'c  d' e\ f"#,
+        "true && echo yes || echo no",
+        "false && echo yes || echo no",
+        "false; echo $?",
+        r#"FOO=bar; echo $FOO ${FOO}baz "$FOO""#,
+        "FOO=1 sh -c 'echo $FOO'",
+        "FOO=1; sh -c 'echo ${FOO:-unset}'",
+        "export FOO=2; sh -c 'echo $FOO'",
+        "unset HOME; sh -c 'echo ${HOME:-unset}'",
+        "echo one two | tr a-z A-Z",
+        r"printf 'b\na\n' | sort | head -n 1",
+        "echo out > out.txt; cat out.txt",
+        "echo a >> append.txt; echo b >> append.txt; cat append.txt",
+        "cat < input.txt",
+        "sh -c 'echo err >&2' 2>&1 | tr a-z A-Z",
+        "sh -c 'echo err >&2' 2>/dev/null; echo done",
+        "echo *.js",
+        r#"echo "*.js" '*'.js"#,
+        "echo nomatch*",
+        "echo src/*",
+        "cd src && pwd | sed 's|.*/||'",
+        r#"X="a   b"; printf '%s\n' $X "$X""#,
+        "exit 3",
+        "false",
+        "missing-command-xyz 2>/dev/null; echo $?",
+        "echo a; echo b # comment",
+        "echo -n foo; echo bar",
+        "sh -c 'exit 7' || echo failed $?",
+        "echo first\necho second",
+        "true &&\n  echo continued",
+        r#"test ~ = "$HOME" && echo tilde"#,
+        "echo $",
+        "echo '' | wc -l",
+        "echo if then fi",
+        r#""if" 2>/dev/null; echo $?"#,
+        "echo ! '!' a!",
+    ];
+    // syntax that the emulator rejects with the status 2 instead of running it differently
+    let unsupported = [
+        "echo $(pwd)",
+        "if true; then echo yes; fi",
+        "true && if true; then echo yes; else echo no; fi",
+        "for x in a b; do echo $x; done",
+        "while false; do echo loop; done",
+        "case a in a) echo a;; esac",
+        "! false && echo negated",
+        r#"echo "$@""#,
+        "echo $* $#",
+    ];
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "a.js" => file!(""),
+        "b.js" => file!(""),
+        ".hidden.js" => file!(""),
+        "input.txt" => file!("from input\n"),
+        "src" => dir! {
+            "x.txt" => file!(""),
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    for script in scripts {
+        eprintln!("SCRIPT: {script:?}");
+        for file in ["out.txt", "append.txt"] {
+            fs::remove_file(temp_dir.path().join(file)).ok();
+        }
+        let expected = Command::new("/bin/sh")
+            .current_dir(&temp_dir)
+            .arg("-c")
+            .arg(script)
+            .output()
+            .unwrap();
+        for file in ["out.txt", "append.txt"] {
+            fs::remove_file(temp_dir.path().join(file)).ok();
+        }
+        let received = Command::cargo_bin("pn")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .args(["__shell-emulator", "--", script])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&received.stdout),
+            String::from_utf8_lossy(&expected.stdout),
+        );
+        assert_eq!(received.status.code(), expected.status.code());
+    }
+
+    for script in unsupported {
+        eprintln!("UNSUPPORTED: {script:?}");
+        let received = Command::cargo_bin("pn")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .args(["__shell-emulator", "--", script])
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&received.stderr);
+        eprintln!("STDERR:\n{stderr}");
+        assert_eq!(String::from_utf8_lossy(&received.stdout), "");
+        assert_eq!(received.status.code(), Some(2));
+        assert!(stderr.contains("not supported"));
+    }
+}
+
+#[test]
+fn shell_emulator_setting() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        ".npmrc" => file!("shell-emulator=true\n"),
+        "package.json" => file!(r#"{"scripts": {"greet": "GREETING=hello; echo $GREETING && exit 4"}}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "greet", "world"])
+        .assert()
+        .failure()
+        .stdout("hello\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["echo", "a b", "c"])
+        .assert()
+        .success()
+        .stdout("a b c\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn script_shell_setting() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"greet": "echo hello"}}"#),
+        "shell" => file!("#!/bin/sh\necho \"custom shell: $1 $2\"\n"),
+    });
+    tree.build(&temp_dir).unwrap();
+    let shell = temp_dir.path().join("shell");
+    fs::set_permissions(&shell, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(
+        temp_dir.path().join(".npmrc"),
+        format!("script-shell={}\n", shell.display()),
+    )
+    .unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "greet"])
+        .assert()
+        .success()
+        .stdout("custom shell: -c echo hello\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .env("npm_config_script_shell", "sh")
+        .args(["run", "greet"])
+        .assert()
+        .success()
+        .stdout("hello\n");
+}
+
+#[test]
+fn node_options_setting() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        ".npmrc" => file!("node-options=--max-old-space-size=${HEAP_SIZE}\n"),
+        "package.json" => file!(r#"{"scripts": {"options": "echo $NODE_OPTIONS"}}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .env("HEAP_SIZE", "4096")
+        .args(["run", "options"])
+        .assert()
+        .success()
+        .stdout("--max-old-space-size=4096\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .env("NPM_CONFIG_NODE_OPTIONS", "--inspect")
+        .args(["run", "options"])
+        .assert()
+        .success()
+        .stdout("--inspect\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_env_var() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"options": "echo $NODE_OPTIONS"}}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .env("NON_UTF8", OsStr::from_bytes(b"\xff\xfe"))
+        .env("npm_config_node_options", "--inspect")
+        .args(["run", "options"])
+        .assert()
+        .success()
+        .stdout("--inspect\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn hoisted_bins() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "root"}"#),
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\n"),
+        "node_modules" => dir! {
+            ".bin" => dir! {
+                "hoisted" => file!("#!/bin/sh\necho \"hoisted $*\"\n"),
+            },
+        },
+        "packages" => dir! {
+            "foo" => dir! {
+                "package.json" => file!(r#"{"name": "foo", "scripts": {"greet": "hoisted from foo && own"}}"#),
+                "node_modules" => dir! {
+                    ".bin" => dir! {
+                        "own" => file!("#!/bin/sh\necho own\n"),
+                    },
+                },
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    for bin in [
+        "node_modules/.bin/hoisted",
+        "packages/foo/node_modules/.bin/own",
+    ] {
+        fs::set_permissions(temp_dir.path().join(bin), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(temp_dir.path().join("packages/foo"))
+        .args(["run", "greet"])
+        .assert()
+        .success()
+        .stdout("hoisted from foo\nown\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(temp_dir.path().join("packages/foo"))
+        .args(["hoisted", "directly"])
+        .assert()
+        .success()
+        .stdout("hoisted directly\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--recursive", "run", "greet"])
+        .assert()
+        .success()
+        .stdout("hoisted from foo\nown\n");
+}
+
+#[test]
+fn run_from_subdirectory() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\n"),
+        "packages" => dir! {
+            "foo" => dir! {
+                "package.json" => file!(r#"{"name": "foo", "scripts": {"where": "pwd && echo $INIT_CWD"}}"#),
+                "src" => dir! {
+                    "components" => dir! {},
+                },
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let package_dir = temp_dir
+        .path()
+        .join("packages/foo")
+        .pipe(fs::canonicalize)
+        .unwrap();
+    let init_cwd = package_dir.join("src/components");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&init_cwd)
+        .args(["run", "where"])
+        .assert()
+        .success()
+        .stdout(format!(
+            "{}\n{}\n",
+            package_dir.display(),
+            init_cwd.display()
+        ));
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(temp_dir.path().join("packages"))
+        .args(["run", "where"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn dir_option() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "pnpm-workspace.yaml" => file!("packages: ['packages/*']\n"),
+        "package.json" => file!(r#"{"name": "root", "scripts": {"where": "echo root"}}"#),
+        "packages" => dir! {
+            "web" => dir! {
+                "package.json" => file!(r#"{"name": "web", "scripts": {"where": "echo web"}}"#),
+                "src" => dir! {},
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-C", "packages/web", "where"])
+        .assert()
+        .success()
+        .stdout("web\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(temp_dir.path().join("packages/web/src"))
+        .args(["run", "--dir=../../..", "where"])
+        .assert()
+        .success()
+        .stdout("root\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--dir", "packages/web/src", "ls"])
+        .assert()
+        .success()
+        .stdout("package.json\nsrc\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-C", "packages/web", "--workspace-root", "where"])
+        .assert()
+        .success()
+        .stdout("root\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-C", "missing", "where"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn exit_code() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"fail": "exit 3"}}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "fail"])
+        .assert()
+        .code(3);
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["sh", "-c", "exit 5"])
+        .assert()
+        .code(5);
+}
+
+#[cfg(unix)]
+#[test]
+fn exit_code_of_signal() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"killed": "kill -9 $$"}}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    let output = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "killed"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}");
+    assert_eq!(output.status.code(), Some(137));
+    assert!(stderr.contains("SIGKILL"));
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["sh", "-c", "kill -TERM $$"])
+        .assert()
+        .code(143);
+}
+
+/// Wait until the file at `path` is written, then read it.
+#[cfg(unix)]
+fn wait_for_file(path: &std::path::Path) -> String {
+    for _ in 0..500 {
+        match fs::read_to_string(path) {
+            Ok(content) if content.ends_with('\n') => return content,
+            _ => std::thread::sleep(std::time::Duration::from_millis(10)),
+        }
+    }
+    panic!("{path:?} was never written");
+}
+
+/// Whether the process `pid` is still running.
+#[cfg(unix)]
+fn is_running(pid: i32) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+#[cfg(unix)]
+#[test]
+fn forward_signal_to_process_group() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"serve": "sleep 30 & echo $! > sleep.pid; wait"}}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    let mut pn = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "serve"])
+        .spawn()
+        .unwrap();
+    let sleep_pid: i32 = wait_for_file(&temp_dir.path().join("sleep.pid"))
+        .trim()
+        .parse()
+        .unwrap();
+    assert!(is_running(sleep_pid));
+
+    unsafe { libc::kill(pn.id() as i32, libc::SIGTERM) };
+    let status = pn.wait().unwrap();
+    dbg!(status);
+    assert_eq!(status.code(), Some(143));
+    let started = std::time::Instant::now();
+    while is_running(sleep_pid) {
+        assert!(started.elapsed().as_secs() < 5, "the grandchild survived");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn kill_after_grace_period() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"stubborn": "trap '' TERM; echo ready > ready; sleep 30"}}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    let mut pn = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--kill-grace-period=200ms", "run", "stubborn"])
+        .spawn()
+        .unwrap();
+    wait_for_file(&temp_dir.path().join("ready"));
+
+    let started = std::time::Instant::now();
+    unsafe { libc::kill(pn.id() as i32, libc::SIGTERM) };
+    let status = pn.wait().unwrap();
+    dbg!(status, started.elapsed());
+    assert_eq!(status.code(), Some(137));
+    assert!(started.elapsed().as_secs() < 10);
+}
+
+#[cfg(unix)]
+#[test]
+fn exec() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "foo"}"#),
+        "node_modules" => dir! {
+            ".bin" => dir! {
+                "print-args" => file!("#!/bin/sh\nprintf '[%s]\\n' \"$@\"\n"),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let bin = temp_dir.path().join("node_modules/.bin/print-args");
+    fs::set_permissions(bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["exec", "print-args", "a b", "$HOME", "--flag"])
+        .assert()
+        .success()
+        .stdout("[a b]\n[$HOME]\n[--flag]\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["exec", "sh", "-c", "echo $PNPM_PACKAGE_NAME"])
+        .assert()
+        .success()
+        .stdout("foo\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["exec", "--shell-mode", "echo a && print-args", "b c"])
+        .assert()
+        .success()
+        .stdout("a\n[b c]\n");
+
+    let output = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["exec", "no-such-command"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}");
+    assert!(!output.status.success());
+    assert!(stderr.contains("Command not found: no-such-command"));
+}
+
+#[test]
+fn exec_recursive() {
+    let temp_dir = build_filter_workspace();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--recursive", "exec", "sh", "-c", "echo $PNPM_PACKAGE_NAME"])
+        .assert()
+        .success()
+        .stdout("utils\n@scope/lib\napp\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--filter=app", "exec", "-c", "basename $PWD"])
+        .assert()
+        .success()
+        .stdout("app\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn run_simple_script_without_shell() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {
+            "simple": "parent 'a b'",
+            "compound": "parent 'a b' && true"
+        }}"#),
+        "node_modules" => dir! {
+            ".bin" => dir! {
+                "parent" => file!("#!/bin/sh\necho \"$(ps -o comm= -p $PPID) $1 $2\"\n"),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let bin = temp_dir.path().join("node_modules/.bin/parent");
+    fs::set_permissions(bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "simple", "c"])
+        .assert()
+        .success()
+        .stdout("pn a b c\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "compound"])
+        .assert()
+        .success()
+        .stdout("sh a b \n");
+}
+
+#[cfg(unix)]
+#[test]
+fn run_script_without_shebang() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {"tool": "tool 'a b'"}}"#),
+        "node_modules" => dir! {
+            ".bin" => dir! {
+                "tool" => file!("echo from-shim \"$@\"\n"),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let bin = temp_dir.path().join("node_modules/.bin/tool");
+    fs::set_permissions(bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "tool", "c"])
+        .assert()
+        .success()
+        .stdout("from-shim a b c\n");
+}
+
+#[test]
+fn nested_run_in_process() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {
+            "ci": "pnpm run lint && npm run test -- --watch && pn greet",
+            "lint": "echo lint $npm_lifecycle_event",
+            "pretest": "echo pretest",
+            "test": "echo test",
+            "greet": "echo greet $npm_lifecycle_event",
+            "fail": "pn run bad && pn greet",
+            "bad": "exit 3",
+            "missing": "pnpm run unknown",
+            "builtin": "pnpm run lint && echo after && pn greet"
+        }}"#),
+        "node_modules" => dir! {
+            ".bin" => dir! {
+                "pnpm" => file!("#!/bin/sh\necho spawned pnpm\nexit 99\n"),
+                "npm" => file!("#!/bin/sh\necho spawned npm\nexit 99\n"),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    for bin in ["pnpm", "npm"] {
+        let bin = temp_dir.path().join("node_modules/.bin").join(bin);
+        fs::set_permissions(bin, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "ci"])
+        .assert()
+        .success()
+        .stdout("lint lint\npretest\ntest --watch\ngreet greet\n");
+
+    // shell builtins between the nested runs run in a shell of their own
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "builtin"])
+        .assert()
+        .success()
+        .stdout("lint lint\nafter\ngreet greet\n");
+
+    let output = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "fail"])
+        .output()
+        .unwrap();
+    dbg!(&output);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains(r#"Command "fail" failed with exit code 3"#));
+
+    // scripts missing from the manifest are left to the package manager
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "missing"])
+        .assert()
+        .code(99)
+        .stdout("spawned pnpm\n");
+}
+
+#[test]
+fn run_s() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"scripts": {
+            "a": "echo a",
+            "b": "echo b",
+            "build:x": "echo x",
+            "build:y": "echo y",
+            "fail": "exit 2"
+        }}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run-s", "b", "build:*", "a"])
+        .assert()
+        .success()
+        .stdout("b\nx\ny\na\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run-s", "fail", "a"])
+        .assert()
+        .code(2)
+        .stdout("");
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run-s", "--continue-on-error", "fail", "a"])
+        .assert()
+        .code(1)
+        .stdout("a\n");
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr
+        .contains("1 of the scripts failed:\n  fail: Command \"fail\" failed with exit code 2"));
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run-s", "a", "test:*"])
+        .assert()
+        .failure()
+        .stdout("");
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains("No script matches test:*"));
+}
+
+#[test]
+fn run_p() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "app", "scripts": {
+            "a": "echo a",
+            "b": "echo b",
+            "slow": "sleep 5 && echo slow",
+            "fail": "exit 2"
+        }}"#),
+    });
+    tree.build(&temp_dir).unwrap();
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run-p", "a", "b"])
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assertion.get_output().stdout);
+    eprintln!("STDOUT:\n{stdout}\n");
+    let has_line = |script: &str| {
+        stdout
+            .lines()
+            .any(|line| line.contains(&format!("app {script}$")) && line.ends_with(script))
+    };
+    assert_eq!(stdout.lines().count(), 2);
+    assert!(has_line("a"));
+    assert!(has_line("b"));
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run-p", "--max-parallel=1", "b", "a"])
+        .assert()
+        .success()
+        .stdout("b\na\n");
+
+    let start = std::time::Instant::now();
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--reporter-hide-prefix", "run-p", "--race", "slow", "a"])
+        .assert()
+        .success()
+        .stdout("a\n");
+    dbg!(assertion.get_output(), start.elapsed());
+    assert!(start.elapsed() < std::time::Duration::from_secs(4));
+
+    let start = std::time::Instant::now();
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run-p", "slow", "fail"])
+        .assert()
+        .code(2)
+        .stdout("");
+    dbg!(assertion.get_output(), start.elapsed());
+    assert!(start.elapsed() < std::time::Duration::from_secs(4));
+}
+
+#[test]
+fn run_cached_tasks() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "root"}"#),
+        "pnpm-workspace.yaml" => file!(concat!(
+            "packages: ['packages/*']\n",
+            "workspaceConcurrency: 1\n",
+            "pn:\n",
+            "  tasks:\n",
+            "    build:\n",
+            "      dependsOn: ['^build']\n",
+            "      inputs: ['src/**']\n",
+            "      outputs: ['dist/**']\n",
+        )),
+        "packages" => dir! {
+            "lib" => dir! {
+                "package.json" => file!(r#"{"name": "lib", "scripts": {
+                    "build": "mkdir -p dist && cp src/index.txt dist/out.txt && echo built lib && echo lib >> ../../runs.log"
+                }}"#),
+                "src" => dir! {
+                    "index.txt" => file!("lib v1"),
+                },
+            },
+            "app" => dir! {
+                "package.json" => file!(r#"{"name": "app", "dependencies": {"lib": "workspace:*"}, "scripts": {
+                    "build": "echo built app && echo app >> ../../runs.log"
+                }}"#),
+                "src" => dir! {
+                    "index.txt" => file!("app"),
+                },
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let app_dir = temp_dir.path().join("packages/app");
+    let lib_dir = temp_dir.path().join("packages/lib");
+    let runs = || fs::read_to_string(temp_dir.path().join("runs.log")).unwrap();
+    let build = |args: &[&str]| {
+        let assertion = Command::cargo_bin("pn")
+            .unwrap()
+            .current_dir(&app_dir)
+            .args(args)
+            .assert()
+            .success()
+            .stdout("built lib\nbuilt app\n");
+        String::from_utf8_lossy(&assertion.get_output().stderr).into_owned()
+    };
+
+    let stderr = build(&["run", "build"]);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(!stderr.contains("cache hit"));
+    assert_eq!(runs(), "lib\napp\n");
+
+    let stderr = build(&["run", "build"]);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert_eq!(stderr.matches("cache hit, replaying logs").count(), 2);
+    assert_eq!(runs(), "lib\napp\n");
+
+    fs::remove_dir_all(lib_dir.join("dist")).unwrap();
+    build(&["-r", "run", "build"]);
+    assert_eq!(runs(), "lib\napp\n");
+    let restored = fs::read_to_string(lib_dir.join("dist/out.txt")).unwrap();
+    assert_eq!(restored, "lib v1");
+
+    // a change in `lib` invalidates `app` too
+    fs::write(lib_dir.join("src/index.txt"), "lib v2").unwrap();
+    build(&["run", "build"]);
+    assert_eq!(runs(), "lib\napp\nlib\napp\n");
+    let rebuilt = fs::read_to_string(lib_dir.join("dist/out.txt")).unwrap();
+    assert_eq!(rebuilt, "lib v2");
+}
+
+#[test]
+fn run_cached_task_retry() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "app", "scripts": {
+            "build": "test -f attempted || { echo first attempt; touch attempted; exit 3; }; echo passed"
+        }}"#),
+        "pnpm-workspace.yaml" => file!("pn:\n  tasks:\n    build:\n      inputs: ['src/**']\n"),
+        "src" => dir! {
+            "index.txt" => file!("app"),
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let build = || {
+        Command::cargo_bin("pn")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .args(["run", "--retry", "1", "build"])
+            .assert()
+            .success()
+    };
+
+    build().stdout("first attempt\npassed\n");
+    // only the logs of the successful attempt are cached
+    build().stdout("passed\n");
+}
+
+#[test]
+fn run_task_graph_options() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "root"}"#),
+        "pnpm-workspace.yaml" => file!(concat!(
+            "packages: ['packages/*']\n",
+            "workspaceConcurrency: 1\n",
+            "pn:\n",
+            "  tasks:\n",
+            "    build:\n",
+            "      dependsOn: ['^build']\n",
+            "    lint: {}\n",
+        )),
+        "packages" => dir! {
+            "lib" => dir! {
+                "package.json" => file!(r#"{"name": "lib", "scripts": {"build": "echo build lib && exit 3"}}"#),
+            },
+            "app" => dir! {
+                "package.json" => file!(r#"{"name": "app", "dependencies": {"lib": "workspace:*"}, "scripts": {"build": "echo build app"}}"#),
+            },
+            "other" => dir! {
+                "package.json" => file!(r#"{"name": "other", "scripts": {"build": "echo build other"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let pn = |args: &[&str]| {
+        Command::cargo_bin("pn")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .args(args)
+            .assert()
+    };
+
+    let assertion = pn(&["-r", "run", "build"]).code(3);
+    let stdout = String::from_utf8_lossy(&assertion.get_output().stdout);
+    assert!(stdout.contains("build lib\n"));
+    assert!(!stdout.contains("build app"));
+
+    // the other tasks keep running, except the ones that depend on the failed one
+    let assertion = pn(&["-r", "run", "--no-bail", "build"]).code(1);
+    let output = assertion.get_output();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDOUT:\n{stdout}\n\nSTDERR:\n{stderr}\n");
+    assert!(stdout.contains("build lib\n"));
+    assert!(stdout.contains("build other\n"));
+    assert!(!stdout.contains("build app"));
+    assert!(stderr.contains("1 of the scripts failed:\n  lib#build: "));
+
+    let assertion = pn(&["-r", "run", "lint"]).failure();
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    assert!(stderr.contains(r#"None of the selected packages has a "lint" script"#));
+    pn(&["-r", "run", "--if-present", "lint"])
+        .success()
+        .stdout("");
+    pn(&["--filter=app", "run", "--if-present", "lint"])
+        .success()
+        .stdout("");
+
+    for option in ["--parallel", "--no-sort", "--reverse"] {
+        let assertion = pn(&["-r", option, "run", "build"]).failure().stdout("");
+        let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+        let expected = format!(r#"{option} cannot be used with "build", which runs as a task"#);
+        assert!(stderr.contains(&expected));
+    }
+}
+
+/// Build a workspace where `app` depends on `lib` and `lib` depends on `utils`.
+fn build_filter_workspace() -> tempfile::TempDir {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "root", "scripts": {"build": "echo build root"}}"#),
+        "pnpm-workspace.yaml" => file!("packages: ['apps/*', 'packages/*']\nworkspaceConcurrency: 1\n"),
+        "apps" => dir! {
+            "app" => dir! {
+                "package.json" => file!(r#"{"name": "app", "dependencies": {"@scope/lib": "workspace:*"}, "scripts": {"build": "echo build app"}}"#),
+            },
+        },
+        "packages" => dir! {
+            "lib" => dir! {
+                "package.json" => file!(r#"{"name": "@scope/lib", "dependencies": {"utils": "workspace:*"}, "scripts": {"build": "echo build lib"}}"#),
+            },
+            "utils" => dir! {
+                "package.json" => file!(r#"{"name": "utils", "scripts": {"build": "echo build utils"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    temp_dir
+}
+
+#[test]
+fn run_package_script() {
+    let temp_dir = build_filter_workspace();
+    let app_dir = temp_dir.path().join("apps/app");
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&app_dir)
+        .args(["run", "utils#build"])
+        .assert()
+        .success()
+        .stdout("build utils\n");
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    let utils_dir = dunce::canonicalize(temp_dir.path().join("packages/utils")).unwrap();
+    assert!(stderr.contains(&format!("> utils@ {}", utils_dir.display())));
+
+    for name in ["@scope/lib#build", "lib#build"] {
+        Command::cargo_bin("pn")
+            .unwrap()
+            .current_dir(&app_dir)
+            .args(["run", name])
+            .assert()
+            .success()
+            .stdout("build lib\n");
+    }
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&app_dir)
+        .args(["run", "util#build"])
+        .assert()
+        .failure()
+        .stdout("");
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains(r#"No package named "util" in the workspace, did you mean utils?"#));
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&app_dir)
+        .args(["run", "utils#test"])
+        .assert()
+        .failure()
+        .stdout("");
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains("Missing script: test"));
+}
+
+#[test]
+fn run_filter() {
+    let temp_dir = build_filter_workspace();
+    let cases: &[(&[&str], &str)] = &[
+        (&["--filter", "app", "run", "build"], "build app\n"),
+        (&["-F", "lib", "build"], "build lib\n"),
+        (
+            &["--filter", "app...", "run", "build"],
+            "build utils\nbuild lib\nbuild app\n",
+        ),
+        (
+            &["--filter", "app^...", "run", "build"],
+            "build utils\nbuild lib\n",
+        ),
+        (
+            &["--filter", "...^utils", "run", "build"],
+            "build lib\nbuild app\n",
+        ),
+        (
+            &[
+                "--filter",
+                "./packages",
+                "--filter",
+                "!utils",
+                "run",
+                "build",
+            ],
+            "build lib\n",
+        ),
+        (&["--filter", "{apps/*}", "run", "build"], "build app\n"),
+        (&["--filter", "root", "run", "build"], "build root\n"),
+    ];
+    for (args, expected) in cases {
+        eprintln!("ARGS: {args:?}");
+        Command::cargo_bin("pn")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .args(*args)
+            .assert()
+            .success()
+            .stdout(*expected);
+    }
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(temp_dir.path().join("packages"))
+        .args(["--filter", ".", "run", "build"])
+        .assert()
+        .success()
+        .stdout("build utils\nbuild lib\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--filter", "missing", "run", "build"])
+        .assert()
+        .success()
+        .stdout("No projects matched the filters\n");
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--filter", "app{", "run", "build"])
+        .assert()
+        .failure();
+    let output = assertion.get_output();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprintln!("STDERR:\n{stderr}\n");
+    assert!(stderr.contains(r#"Invalid filter: "app{""#));
+}
+
+#[test]
+fn filter_shell_command() {
+    let temp_dir = build_filter_workspace();
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--filter", "@scope/lib...", "pwd"])
+        .assert()
+        .success();
+    let output = assertion.get_output();
+    let received = String::from_utf8_lossy(&output.stdout);
+    eprintln!("STDOUT:\n{received}\n");
+    let dir = temp_dir.path().pipe(dunce::canonicalize).unwrap();
+    let expected = format!(
+        "{}\n{}\n",
+        dir.join("packages/utils").display(),
+        dir.join("packages/lib").display(),
+    );
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn filter_changed_since_git_ref() {
+    let temp_dir = build_filter_workspace();
+    let git = |args: &[&str]| {
+        let status = Command::new("git")
+            .current_dir(&temp_dir)
+            .args(["-c", "user.name=pn", "-c", "user.email=pn@example.com"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?} failed");
+    };
+    git(&["init", "--quiet"]);
+    git(&["add", "."]);
+    git(&["commit", "--quiet", "-m", "initial"]);
+    fs::write(temp_dir.path().join("packages/utils/index.js"), "").unwrap();
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--filter", "[HEAD]", "run", "build"])
+        .assert()
+        .success()
+        .stdout("build utils\n");
+
+    Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--filter", "...[HEAD]", "run", "build"])
+        .assert()
+        .success()
+        .stdout("build utils\nbuild lib\nbuild app\n");
+}
+
+#[test]
+fn run_watch() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "app", "scripts": {"build": "echo run >> runs.log"}}"#),
+        ".gitignore" => file!("runs.log\n"),
+        "src" => dir! {
+            "index.js" => file!(""),
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let runs_log = temp_dir.path().join("runs.log");
+    let wait_for_runs = |count: usize| {
+        for _ in 0..100 {
+            let runs = fs::read_to_string(&runs_log).unwrap_or_default();
+            if runs.lines().count() >= count {
+                return runs;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        panic!("the script did not run {count} times");
+    };
+
+    let mut child = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["run", "--watch", "build"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_for_runs(1);
+    fs::write(temp_dir.path().join("src/index.js"), "changed").unwrap();
+    wait_for_runs(2);
+    // the log written by the script is ignored, so it does not restart the script again
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    let runs = fs::read_to_string(&runs_log).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(runs, "run\nrun\n");
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--recursive", "run", "--watch", "build"])
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}");
+    assert!(stderr.contains("--watch cannot be used with --recursive or --filter"));
+}
+
+#[test]
+fn run_timeout_retry() {
+    let temp_dir = tempdir().unwrap();
+    let tree = MergeableFileSystemTree::<&str, &str>::from(dir! {
+        "package.json" => file!(r#"{"name": "root", "scripts": {}}"#),
+        "pnpm-workspace.yaml" => file!("packages:\n  - 'packages/*'\nworkspaceConcurrency: 1\n"),
+        "packages" => dir! {
+            "app" => dir! {
+                "package.json" => file!(r#"{"name": "app", "version": "1.0.0", "scripts": {
+                    "hang": "echo start && sleep 5",
+                    "flaky": "test -f attempted || { touch attempted; exit 3; }"
+                }}"#),
+            },
+            "lib" => dir! {
+                "package.json" => file!(r#"{"name": "lib", "scripts": {"hang": "echo lib"}}"#),
+            },
+        },
+    });
+    tree.build(&temp_dir).unwrap();
+    let app_dir = temp_dir.path().join("packages/app");
+
+    let start = std::time::Instant::now();
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&app_dir)
+        .args(["run", "--timeout", "500ms", "hang"])
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}");
+    assert!(start.elapsed() < std::time::Duration::from_secs(4));
+    assert!(stderr.contains("> app@1.0.0 "));
+    assert!(stderr.contains(" (timeout 500ms)\n"));
+    assert!(stderr.contains(r#"Script "hang" timed out after 500ms"#));
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(&app_dir)
+        .args(["run", "--retry", "2", "--retry-backoff", "100ms", "flaky"])
+        .assert()
+        .success();
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}");
+    assert_eq!(stderr.matches(" (retry 2, backoff 100ms)\n").count(), 2);
+    assert!(stderr.contains(
+        r#"flaky: Command "flaky" failed with exit code 3, retrying in 100ms (attempt 2 of 3)"#
+    ));
+
+    let assertion = Command::cargo_bin("pn")
+        .unwrap()
+        .current_dir(temp_dir.path())
+        .args(["-r", "run", "--timeout", "500ms", "--retry", "1", "hang"])
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assertion.get_output().stderr);
+    eprintln!("STDERR:\n{stderr}");
+    assert_eq!(stderr.matches("(attempt 2 of 2)").count(), 1);
+    assert!(stderr.contains(r#"Script "hang" timed out after 500ms"#));
+}